@@ -0,0 +1,66 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use actix_web::dev::Payload;
+use actix_web::error::InternalError;
+use actix_web::{web, FromRequest, HttpRequest};
+use crate::auth::{Role, User};
+use crate::session::{SessionStore, SessionToken};
+use crate::store::UserStore;
+use crate::utils::{self, ApiResponse};
+
+/// The signed in caller. As a handler argument it rejects requests without a
+/// valid session with 401. The user is read from the user store on every
+/// request, so role changes apply immediately.
+pub struct Caller {
+    pub session: SessionToken,
+    pub user: User,
+}
+
+/// A caller with the admin role. Everyone else gets 403.
+pub struct Admin(pub Caller);
+
+impl Caller {
+    pub fn has_any_role(&self, roles: &[Role]) -> bool {
+        roles.iter().any(|role| self.user.has_role(*role))
+    }
+
+    async fn extract(req: HttpRequest) -> Result<Self, ApiResponse> {
+        let sessions = req.app_data::<web::Data<Arc<SessionStore>>>().ok_or(ApiResponse::InternalServerError)?;
+        let users = req.app_data::<web::Data<dyn UserStore>>().ok_or(ApiResponse::InternalServerError)?;
+        let session = utils::verfiy_session_token(&req, sessions).await?;
+        let user = users.get_user(&session.user_id).await.map_err(|_| ApiResponse::Unauthorized)?;
+        Ok(Caller { session, user })
+    }
+}
+
+impl FromRequest for Caller {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move { Caller::extract(req).await.map_err(reject) })
+    }
+}
+
+impl FromRequest for Admin {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            let caller = Caller::extract(req).await.map_err(reject)?;
+            if !caller.has_any_role(&[Role::Admin]) {
+                println!("admin: 403 ({} is not an admin)", caller.user.email);
+                return Err(reject(ApiResponse::Forbidden));
+            }
+            Ok(Admin(caller))
+        })
+    }
+}
+
+fn reject(response: ApiResponse) -> actix_web::Error {
+    InternalError::from_response("request rejected", response.to_response()).into()
+}
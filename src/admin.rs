@@ -0,0 +1,238 @@
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use crate::access::Admin;
+use crate::api_keys::ApiKeyStore;
+use crate::audit::{AuditAction, AuditLog, AuditQuery};
+use crate::auth::{Role, User};
+use crate::handlers;
+use crate::session::SessionStore;
+use crate::store::{DocumentStore, StoreError, UserStore};
+use crate::utils::ApiResponse;
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+
+#[derive(Deserialize)]
+pub struct PageQuery {
+    skip: Option<usize>,
+    limit: Option<usize>,
+}
+
+/// What admins get to see of a user: no password hash or TOTP secret.
+#[derive(Serialize)]
+pub struct UserSummary {
+    email: String,
+    newsletter: bool,
+    roles: Vec<Role>,
+    uuids: Vec<String>,
+    last_uuid: String,
+    totp_enabled: bool,
+    disabled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disabled_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SuspendData {
+    reason: Option<String>,
+}
+
+impl From<User> for UserSummary {
+    fn from(user: User) -> Self {
+        UserSummary {
+            totp_enabled: user.has_totp(),
+            email: user.email,
+            newsletter: user.newsletter,
+            roles: user.roles,
+            uuids: user.uuids,
+            last_uuid: user.last_uuid,
+            disabled: user.disabled,
+            disabled_reason: user.disabled_reason,
+        }
+    }
+}
+
+pub async fn list_users(admin: Admin, query: web::Query<PageQuery>, users: web::Data<dyn UserStore>) -> impl Responder {
+    let skip = query.skip.unwrap_or(0);
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    match users.list_users(skip, limit).await {
+        Ok(users) => {
+            println!("admin list_users: OK ({})", admin.0.user.email);
+            let summaries: Vec<UserSummary> = users.into_iter().map(UserSummary::from).collect();
+            HttpResponse::Ok().json(summaries)
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("admin list_users: 500 users.list_users");
+            ApiResponse::InternalServerError.to_response()
+        }
+    }
+}
+
+pub async fn get_user(_admin: Admin, email: web::Path<String>, users: web::Data<dyn UserStore>) -> impl Responder {
+    match users.get_user(&email).await {
+        Ok(user) => {
+            println!("admin get_user: OK");
+            HttpResponse::Ok().json(UserSummary::from(user))
+        },
+        Err(StoreError::NotFound) => {
+            println!("admin get_user: 404");
+            ApiResponse::NotFound.to_response()
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("admin get_user: 500 users.get_user");
+            ApiResponse::InternalServerError.to_response()
+        }
+    }
+}
+
+pub async fn set_roles(admin: Admin, email: web::Path<String>, requested: web::Json<Vec<Role>>, users: web::Data<dyn UserStore>) -> impl Responder {
+    let mut roles: Vec<Role> = Vec::new();
+    for role in requested.into_inner() {
+        if !roles.contains(&role) {
+            roles.push(role);
+        }
+    }
+    // Otherwise the last admin could lock everyone out of /admin
+    if *email == admin.0.user.email && !roles.contains(&Role::Admin) {
+        println!("admin set_roles: 403 (admins can't drop their own admin role)");
+        return ApiResponse::Forbidden.to_response();
+    }
+
+    match users.update_user(&email, |user| user.roles = roles.clone()).await {
+        Ok(user) => {
+            println!("admin set_roles: OK ({} for {})", admin.0.user.email, user.email);
+            HttpResponse::Ok().json(UserSummary::from(user))
+        },
+        Err(StoreError::NotFound) => {
+            println!("admin set_roles: 404");
+            ApiResponse::NotFound.to_response()
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("admin set_roles: 500 users.update_user");
+            ApiResponse::InternalServerError.to_response()
+        }
+    }
+}
+
+pub async fn revoke_sessions(admin: Admin, email: web::Path<String>, sessions: web::Data<Arc<SessionStore>>) -> impl Responder {
+    // An admin signing themselves out elsewhere keeps the session making this request
+    let keep = (*email == admin.0.user.email).then_some(admin.0.session.family_id);
+    match sessions.revoke_all_for_user(&email, keep).await {
+        Ok(revoked) => {
+            println!("admin revoke_sessions: OK ({} for {}, {} revoked)", admin.0.user.email, email, revoked);
+            HttpResponse::Ok().json(revoked)
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("admin revoke_sessions: 500 sessions.revoke_all_for_user");
+            ApiResponse::InternalServerError.to_response()
+        }
+    }
+}
+
+pub async fn delete_user(admin: Admin, email: web::Path<String>, users: web::Data<dyn UserStore>, sessions: web::Data<Arc<SessionStore>>, api_keys: web::Data<Arc<ApiKeyStore>>, audit: web::Data<Arc<AuditLog>>, req: HttpRequest) -> impl Responder {
+    match handlers::remove_account(&**users, &sessions, &api_keys, &email).await {
+        Ok(()) => {
+            audit.record(&req, AuditAction::UserDeleted, &email, Some(&admin.0.user.email), None).await;
+            println!("admin delete_user: OK ({} deleted {})", admin.0.user.email, email);
+            ApiResponse::Ok.to_response()
+        },
+        Err(e) => {
+            println!("admin delete_user: failed (remove_account)");
+            e.to_response()
+        }
+    }
+}
+
+/// Locks the account and signs it out everywhere. API keys are kept but refused until it is reactivated.
+pub async fn suspend_user(admin: Admin, email: web::Path<String>, data: web::Json<SuspendData>, users: web::Data<dyn UserStore>, sessions: web::Data<Arc<SessionStore>>, audit: web::Data<Arc<AuditLog>>, req: HttpRequest) -> impl Responder {
+    // Otherwise the last admin could lock everyone out of /admin
+    if *email == admin.0.user.email {
+        println!("admin suspend_user: 403 (admins can't suspend themselves)");
+        return ApiResponse::Forbidden.to_response();
+    }
+    let reason = data.into_inner().reason.filter(|reason| !reason.trim().is_empty());
+    let user = match users.update_user(&email, |user| {
+        user.disabled = true;
+        user.disabled_reason = reason.clone();
+    }).await {
+        Ok(user) => user,
+        Err(StoreError::NotFound) => {
+            println!("admin suspend_user: 404");
+            return ApiResponse::NotFound.to_response();
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("admin suspend_user: 500 users.update_user");
+            return ApiResponse::InternalServerError.to_response();
+        }
+    };
+    // Access tokens of revoked sessions stop working, and refreshing them answers "Account disabled"
+    if let Err(e) = sessions.revoke_all_for_user(&email, None).await {
+        println!("Error: {:?}", e);
+        println!("admin suspend_user: 500 sessions.revoke_all_for_user");
+        return ApiResponse::InternalServerError.to_response();
+    }
+    audit.record(&req, AuditAction::UserSuspended, &email, Some(&admin.0.user.email), reason.as_deref()).await;
+    println!("admin suspend_user: OK ({} suspended {})", admin.0.user.email, email);
+    HttpResponse::Ok().json(UserSummary::from(user))
+}
+
+pub async fn reactivate_user(admin: Admin, email: web::Path<String>, users: web::Data<dyn UserStore>, audit: web::Data<Arc<AuditLog>>, req: HttpRequest) -> impl Responder {
+    let user = match users.update_user(&email, |user| {
+        user.disabled = false;
+        user.disabled_reason = None;
+    }).await {
+        Ok(user) => user,
+        Err(StoreError::NotFound) => {
+            println!("admin reactivate_user: 404");
+            return ApiResponse::NotFound.to_response();
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("admin reactivate_user: 500 users.update_user");
+            return ApiResponse::InternalServerError.to_response();
+        }
+    };
+    audit.record(&req, AuditAction::UserReactivated, &email, Some(&admin.0.user.email), None).await;
+    println!("admin reactivate_user: OK ({} reactivated {})", admin.0.user.email, email);
+    HttpResponse::Ok().json(UserSummary::from(user))
+}
+
+/// The whole project document, including its owner.
+pub async fn get_project(_admin: Admin, id: web::Path<String>, documents: web::Data<dyn DocumentStore>) -> impl Responder {
+    match documents.get_document(&id).await {
+        Ok(doc) => {
+            println!("admin get_project: OK");
+            HttpResponse::Ok().json(doc)
+        },
+        Err(StoreError::NotFound) => {
+            println!("admin get_project: 404");
+            ApiResponse::NotFound.to_response()
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("admin get_project: 500 documents.get_document");
+            ApiResponse::InternalServerError.to_response()
+        }
+    }
+}
+
+/// Audit entries of every account, filtered by `account`, `actor`, `action`, `since` and `until`.
+pub async fn list_audit(_admin: Admin, query: web::Query<AuditQuery>, audit: web::Data<Arc<AuditLog>>) -> impl Responder {
+    match audit.find(&query).await {
+        Ok(entries) => {
+            println!("admin list_audit: OK");
+            HttpResponse::Ok().json(entries)
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("admin list_audit: 500 audit.find");
+            ApiResponse::InternalServerError.to_response()
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use std::sync::Arc;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use crate::db::{CouchDB, DocRef};
+use crate::store::{ApiKeyRecords, StoreError, UserStore};
+use crate::utils::constant_time_eq;
+
+const API_KEYS_DB: &str = "api_keys";
+/// Marks a bearer token as an API key rather than a session token.
+pub const KEY_PREFIX: &str = "ctk_";
+const MAX_KEYS_PER_USER: usize = 50;
+/// `last_used` is only written back when it is older than this.
+const LAST_USED_RESOLUTION: chrono::Duration = chrono::Duration::minutes(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiKeyScope {
+    ReadOnly,
+    ReadWrite,
+}
+
+/// A long-lived key for scripts and CI. Only a hash of its secret is stored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    #[serde(rename = "_id")]
+    pub id: Uuid,
+    #[serde(rename = "_rev", default, skip_serializing_if = "Option::is_none")]
+    pub rev: Option<String>,
+    pub user_id: String,
+    pub name: String,
+    pub scope: ApiKeyScope,
+    secret_hash: String,
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub last_used: Option<DateTime<Utc>>,
+}
+
+/// API keys, stored in `ApiKeyRecords`, in production the CouchDB `api_keys`
+/// database keyed by key id.
+pub struct ApiKeyStore {
+    records: Arc<dyn ApiKeyRecords>,
+    users: Arc<dyn UserStore>,
+}
+
+impl ApiKeyStore {
+    pub fn new(records: Arc<dyn ApiKeyRecords>, users: Arc<dyn UserStore>) -> Self {
+        ApiKeyStore { records, users }
+    }
+
+    pub async fn init(&self) -> Result<(), StoreError> {
+        self.records.init().await
+    }
+
+    /// Creates a key and returns it together with the full key string, which
+    /// is not stored anywhere. `None` if the user already has too many keys.
+    pub async fn create(&self, user_id: String, name: String, scope: ApiKeyScope) -> Result<Option<(ApiKey, String)>, StoreError> {
+        if self.list_for_user(&user_id).await?.len() >= MAX_KEYS_PER_USER {
+            return Ok(None);
+        }
+        let id = Uuid::new_v4();
+        let secret = Uuid::new_v4().simple().to_string();
+        let mut key = ApiKey {
+            id,
+            rev: None,
+            user_id,
+            name,
+            scope,
+            secret_hash: hash_secret(&secret),
+            created_at: Utc::now(),
+            last_used: None,
+        };
+        let rev = self.records.put_key(&key).await?;
+        key.rev = Some(rev);
+        Ok(Some((key, format!("{}{}_{}", KEY_PREFIX, id.simple(), secret))))
+    }
+
+    /// Returns the key `presented` stands for, or `None` if it is malformed,
+    /// unknown or the secret doesn't match.
+    pub async fn verify(&self, presented: &str) -> Result<Option<ApiKey>, StoreError> {
+        let Some((id, secret)) = presented.strip_prefix(KEY_PREFIX).and_then(|rest| rest.split_once('_')) else {
+            return Ok(None);
+        };
+        let Ok(id) = Uuid::parse_str(id) else { return Ok(None) };
+        let key = match self.records.get_key(id).await {
+            Ok(key) => key,
+            Err(StoreError::NotFound) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if !constant_time_eq(hash_secret(secret).as_bytes(), key.secret_hash.as_bytes()) {
+            return Ok(None);
+        }
+        Ok(Some(key))
+    }
+
+    /// Whether the account `key` belongs to is suspended. Keys outlive a
+    /// suspension, so this is checked on every use.
+    pub async fn owner_disabled(&self, key: &ApiKey) -> Result<bool, StoreError> {
+        Ok(self.users.get_user(&key.user_id).await?.disabled)
+    }
+
+    /// Records that `key` was just used.
+    pub async fn touch(&self, mut key: ApiKey) {
+        let now = Utc::now();
+        if key.last_used.is_some_and(|last_used| now - last_used < LAST_USED_RESOLUTION) {
+            return;
+        }
+        key.last_used = Some(now);
+        if let Err(e) = self.records.put_key(&key).await {
+            // Most likely a concurrent request that recorded the same use
+            println!("api_keys: last_used not updated: {:?}", e);
+        }
+    }
+
+    pub async fn list_for_user(&self, user_id: &str) -> Result<Vec<ApiKey>, StoreError> {
+        self.records.list_keys(user_id, MAX_KEYS_PER_USER).await
+    }
+
+    /// Deletes key `id` if it belongs to `user_id`. Returns false if there was no such key.
+    pub async fn revoke(&self, user_id: &str, id: &str) -> Result<bool, StoreError> {
+        let Ok(id) = Uuid::parse_str(id) else { return Ok(false) };
+        let key = match self.records.get_key(id).await {
+            Ok(key) => key,
+            Err(StoreError::NotFound) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        if key.user_id != user_id {
+            return Ok(false);
+        }
+        self.records.delete_key(&key).await?;
+        Ok(true)
+    }
+
+    /// Moves every key of `from` to `to`, after the user changed their email.
+    pub async fn reassign_user(&self, from: &str, to: &str) -> Result<usize, StoreError> {
+        let mut moved = 0;
+        for mut key in self.list_for_user(from).await? {
+            key.user_id = to.to_string();
+            self.records.put_key(&key).await?;
+            moved += 1;
+        }
+        Ok(moved)
+    }
+
+    /// Deletes every key of `user_id`, returns how many there were.
+    pub async fn revoke_all_for_user(&self, user_id: &str) -> Result<usize, StoreError> {
+        self.records.delete_keys(user_id).await
+    }
+}
+
+#[async_trait]
+impl ApiKeyRecords for CouchDB {
+    /// Creates the `api_keys` database and its index if they are missing.
+    async fn init(&self) -> Result<(), StoreError> {
+        self.ensure_database(API_KEYS_DB).await?;
+        Ok(self.ensure_index(API_KEYS_DB, "user-id", &["user_id"]).await?)
+    }
+
+    async fn get_key(&self, id: Uuid) -> Result<ApiKey, StoreError> {
+        Ok(self.get_doc(API_KEYS_DB, &id.to_string()).await?)
+    }
+
+    async fn put_key(&self, key: &ApiKey) -> Result<String, StoreError> {
+        Ok(self.put_doc(API_KEYS_DB, &key.id.to_string(), key).await?)
+    }
+
+    async fn delete_key(&self, key: &ApiKey) -> Result<(), StoreError> {
+        Ok(self.delete_doc(API_KEYS_DB, &key.id.to_string(), key.rev.as_deref().unwrap_or_default()).await?)
+    }
+
+    async fn list_keys(&self, user_id: &str, limit: usize) -> Result<Vec<ApiKey>, StoreError> {
+        Ok(self.find_docs(API_KEYS_DB, json!({
+            "selector": { "user_id": user_id },
+            "limit": limit,
+        })).await?)
+    }
+
+    async fn delete_keys(&self, user_id: &str) -> Result<usize, StoreError> {
+        let keys: Vec<DocRef> = self.find_docs(API_KEYS_DB, json!({
+            "selector": { "user_id": user_id },
+            "fields": ["_id", "_rev"],
+            "limit": MAX_KEYS_PER_USER,
+        })).await?;
+        if !keys.is_empty() {
+            self.bulk_delete(API_KEYS_DB, &keys).await?;
+        }
+        Ok(keys.len())
+    }
+}
+
+impl ApiKeyScope {
+    pub fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Read => true,
+            Access::Write => *self == ApiKeyScope::ReadWrite,
+        }
+    }
+}
+
+/// What an endpoint does with the caller's data, for checking API key scopes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
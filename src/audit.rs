@@ -0,0 +1,159 @@
+use std::sync::Arc;
+use actix_web::HttpRequest;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use uuid::Uuid;
+use crate::db::CouchDB;
+use crate::store::{AuditRecords, StoreError};
+use crate::utils;
+
+const AUDIT_DB: &str = "audit";
+/// Longer User-Agent headers are cut off.
+const MAX_USER_AGENT_LENGTH: usize = 256;
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    LoginSucceeded,
+    LoginFailed,
+    Logout,
+    Registered,
+    PasswordReset,
+    PasswordChanged,
+    EmailChanged,
+    UuidAdded,
+    UuidRemoved,
+    DocumentWritten,
+    DocumentDeleted,
+    DocumentUndeleted,
+    DocumentRestored,
+    UserDeleted,
+    UserSuspended,
+    UserReactivated,
+}
+
+/// One thing that happened to an account. Entries are only ever added.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    #[serde(rename = "_id")]
+    pub id: Uuid,
+    pub action: AuditAction,
+    /// The account the event concerns, what `/me/audit` shows.
+    pub account: String,
+    /// Who did it, if they were signed in. Differs from `account` when an admin acts on a user.
+    #[serde(default)]
+    pub actor: Option<String>,
+    /// The project, uuid or sign-in method the action was about.
+    #[serde(default)]
+    pub target: Option<String>,
+    pub ip: String,
+    pub user_agent: String,
+    pub at: DateTime<Utc>,
+}
+
+/// What to look for in the audit log, as query parameters. Unset fields match everything.
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    pub account: Option<String>,
+    pub actor: Option<String>,
+    pub action: Option<AuditAction>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub skip: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+/// The audit trail, stored in `AuditRecords`, in production the CouchDB
+/// `audit` database.
+pub struct AuditLog {
+    records: Arc<dyn AuditRecords>,
+}
+
+impl AuditLog {
+    pub fn new(records: Arc<dyn AuditRecords>) -> Self {
+        AuditLog { records }
+    }
+
+    pub async fn init(&self) -> Result<(), StoreError> {
+        self.records.init().await
+    }
+
+    /// Records `action` on `account` by `actor`, with the IP and User-Agent of `req`.
+    /// A failed write is logged but doesn't fail the request being audited.
+    pub async fn record(&self, req: &HttpRequest, action: AuditAction, account: &str, actor: Option<&str>, target: Option<&str>) {
+        let user_agent = req.headers().get("User-Agent")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .chars()
+            .take(MAX_USER_AGENT_LENGTH)
+            .collect();
+        let entry = AuditEntry {
+            id: Uuid::new_v4(),
+            action,
+            account: account.to_string(),
+            actor: actor.map(str::to_string),
+            target: target.map(str::to_string),
+            ip: utils::client_ip(req),
+            user_agent,
+            at: Utc::now(),
+        };
+        if let Err(e) = self.records.append(&entry).await {
+            println!("audit: {:?} on {} not recorded: {:?}", action, account, e);
+        }
+    }
+
+    /// A page of entries matching `filter`, newest first.
+    pub async fn find(&self, filter: &AuditQuery) -> Result<Vec<AuditEntry>, StoreError> {
+        let skip = filter.skip.unwrap_or(0);
+        let limit = filter.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+        self.records.find_entries(filter, skip, limit).await
+    }
+}
+
+#[async_trait]
+impl AuditRecords for CouchDB {
+    /// Creates the `audit` database and its indexes if they are missing.
+    async fn init(&self) -> Result<(), StoreError> {
+        self.ensure_database(AUDIT_DB).await?;
+        self.ensure_index(AUDIT_DB, "account-at", &["account", "at"]).await?;
+        Ok(self.ensure_index(AUDIT_DB, "at", &["at"]).await?)
+    }
+
+    async fn append(&self, entry: &AuditEntry) -> Result<(), StoreError> {
+        self.put_doc(AUDIT_DB, &entry.id.to_string(), entry).await?;
+        Ok(())
+    }
+
+    async fn find_entries(&self, filter: &AuditQuery, skip: usize, limit: usize) -> Result<Vec<AuditEntry>, StoreError> {
+        let mut at = Map::new();
+        at.insert("$gte".to_string(), filter.since.map_or(Value::Null, |since| json!(since)));
+        if let Some(until) = filter.until {
+            at.insert("$lte".to_string(), json!(until));
+        }
+        let mut selector = Map::new();
+        selector.insert("at".to_string(), Value::Object(at));
+        let sort = match &filter.account {
+            Some(account) => {
+                selector.insert("account".to_string(), json!(account));
+                json!([{ "account": "desc" }, { "at": "desc" }])
+            },
+            None => json!([{ "at": "desc" }]),
+        };
+        if let Some(actor) = &filter.actor {
+            selector.insert("actor".to_string(), json!(actor));
+        }
+        if let Some(action) = filter.action {
+            selector.insert("action".to_string(), json!(action));
+        }
+        Ok(self.find_docs(AUDIT_DB, json!({
+            "selector": selector,
+            "sort": sort,
+            "skip": skip,
+            "limit": limit,
+        })).await?)
+    }
+}
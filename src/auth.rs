@@ -1,193 +1,81 @@
-use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
-use sha2::{Sha256, Digest};
-use hex;
-use chrono::{DateTime, Utc};
+use crate::totp::TotpState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Customer,
+    /// CouchTec staff who may read every customer's projects.
+    Consultant,
+    /// May use the `/admin` endpoints and act on any account.
+    Admin,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub email: String,
     pub newsletter: bool,
+    /// Argon2id PHC string, or a hex SHA-256 digest for accounts that have
+    /// not logged in since the Argon2 migration. Empty for accounts created
+    /// through OpenID Connect, which have no password.
     pub hashed: String,
+    /// Only used by legacy SHA-256 hashes, empty for Argon2 hashes.
+    #[serde(default)]
     pub salt: String,
     pub uuids: Vec<String>,
-    pub last_uuid: String
-}
-
-pub struct UserManager {
-    users_cache: HashMap<String, User>,
-    session_cache: HashMap<String, SessionToken>,
-    one_time_codes: HashMap<String, String>,
-    pre_registered: HashMap<String, User>
+    #[serde(default)]
+    pub last_uuid: String,
+    #[serde(default)]
+    pub roles: Vec<Role>,
+    /// Second factor, once the user started enrolling one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp: Option<TotpState>,
+    /// OpenID Connect accounts that sign in as this user.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub identities: Vec<ExternalIdentity>,
+    /// Suspended by an admin. A disabled account can't sign in or use its API keys.
+    #[serde(default)]
+    pub disabled: bool,
+    /// Why the account was suspended, for admins only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disabled_reason: Option<String>,
 }
 
-pub struct SessionToken {
-    token: Uuid,
-    user_id: String,
-    created_at: DateTime<Utc>,
-    expires_at: DateTime<Utc>,
-    last_used: DateTime<Utc>,
-    device_info: String,
-    is_revoked: bool,
+/// An account at an OpenID Connect provider, identified by the provider's
+/// name in our config and its `sub` claim.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExternalIdentity {
+    pub provider: String,
+    pub subject: String,
 }
 
-impl UserManager {
-    pub fn new() -> Self {
-        UserManager {
-            users_cache: HashMap::new(),
-            session_cache: HashMap::new(),
-            one_time_codes: HashMap::new(),
-            pre_registered: HashMap::new()
-        }
-    }
-    pub fn user_exists(&self, email: &str) -> bool {
-        self.users_cache.contains_key(email)
-    }
-
-    pub fn get_user(&self, email: &str) -> Option<&User> {
-        self.users_cache.get(email)
-    }
-
-    pub fn remove_user(&mut self, email: &str) {
-        self.users_cache.remove(email);
-    }
-
-    pub fn insert_user(&mut self, user: User) {
-        self.users_cache.insert(user.email.clone(), user);
-    }
-
-    pub fn session_token_valid(&self, uuid: String) -> bool {
-        match self.session_cache.get(&uuid) {
-            Some(token) => token.is_valid(),
-            None => false
-        }
-    }
-
-    pub fn register(&mut self, uuid: String) -> Result<User, &str> {
-        if let Some(user) = self.pre_registered.get(&uuid) {
-            self.users_cache.insert(user.clone().email, user.clone());
-            return Ok(user.clone());
-        }
-        Err("User not found")
-    }
-
-    pub fn print_out_session_cache(&self) {
-        println!("Session cache:");
-        for uuid in self.session_cache.keys() {
-            println!("{}",uuid);
-        }
-    }
-
-    pub fn pre_register(&mut self, email: String, password: String, newsletter: bool) -> String {
-        let salt = Uuid::new_v4().to_string();
-        let salted = format!("{}{}", password, salt);
-
-        let mut hasher = Sha256::new();
-        hasher.update(salted.as_bytes());
-        let result = hasher.finalize();
-
-        let hashed = hex::encode(result);
-
-        let user = User {
-            email: email.clone(),
+impl User {
+    pub fn new(email: String, hashed: String, newsletter: bool) -> Self {
+        User {
+            email,
             newsletter,
             hashed,
-            salt,
+            salt: String::new(),
             uuids: Vec::new(),
-            last_uuid: "".to_string()
-        };
-        let uuid = Uuid::new_v4().to_string();
-        self.pre_registered.insert(uuid.clone(), user);
-        uuid
-    }
-
-    pub fn hash_password(&self, password: String, salt: String) -> String {
-        let salted = format!("{}{}", password, salt);
-        let mut hasher = Sha256::new();
-        hasher.update(salted.as_bytes());
-        let result = hasher.finalize();
-        hex::encode(result)
-    }
-
-    pub fn login(&mut self, password: String, user: User) -> Result<Uuid, &'static str> {
-        let hashed = self.hash_password(password, user.salt.clone());
-
-        if hashed != user.hashed {
-            return Err("Password is incorrect");
+            last_uuid: "".to_string(),
+            roles: vec![Role::Customer],
+            totp: None,
+            identities: Vec::new(),
+            disabled: false,
+            disabled_reason: None,
         }
-
-        let session_token = SessionToken::new(user.email, "".to_string());
-        let uuid = session_token.token;
-        self.session_cache.insert(uuid.to_string(), session_token);
-        return Ok(uuid);
-    }
-
-    pub fn logout(&mut self, uuid: String) {
-        self.session_cache.retain(|x, _| !x.eq(&uuid));
     }
 
-    pub fn insert_reset_email_code(&mut self, email: String) -> String {
-        let one_time_code = Uuid::new_v4().to_string();
-        self.one_time_codes.insert(one_time_code.clone(), email.clone());
-        one_time_code
+    pub fn has_role(&self, role: Role) -> bool {
+        self.roles.contains(&role)
     }
 
-    pub fn get_email_from_code(&self, uuid: &str) -> Option<String> {
-        self.one_time_codes.get(uuid).cloned()
+    /// Staff may read projects they don't own.
+    pub fn can_read_any_project(&self) -> bool {
+        self.has_role(Role::Consultant) || self.has_role(Role::Admin)
     }
 
-    pub fn get_email_from_token(&self, uuid: &str) -> Option<String> {
-        self.session_cache.get(uuid).map(|token| token.user_id.clone())
-    }
-
-    pub fn change_password(&mut self, email: &str, password: &str, newsletter: bool) -> User {
-        let salt = Uuid::new_v4().to_string();
-        let salted = format!("{}{}", password, salt);
-
-        let mut hasher = Sha256::new();
-        hasher.update(salted.as_bytes());
-        let result = hasher.finalize();
-
-        let hashed = hex::encode(result);
-        let user = User {
-            email: email.to_string(),
-            newsletter,
-            hashed,
-            salt,
-            uuids: Vec::new(),
-            last_uuid: "".to_string()
-        };
-        // self.delete_user(&email);
-        self.users_cache.insert(email.to_string(), user.clone());
-        user
-    }
-
-    pub fn delete_user(&mut self, email: &str) {
-        self.users_cache.retain(|x, _| !email.eq(x));
-    }
-}
-
-impl SessionToken {
-    fn new(user_id: String, device_info: String) -> Self {
-        let now = Utc::now();
-        SessionToken {
-            token: Uuid::new_v4(),
-            user_id,
-            created_at: now,
-            expires_at: now + chrono::Duration::hours(24),
-            last_used: now,
-            device_info,
-            is_revoked: false,
-        }
-    }
-
-    fn is_valid(&self) -> bool {
-        !self.is_revoked && self.expires_at > Utc::now()
-    }
-
-    fn update_last_used(&mut self) {
-        self.last_used = Utc::now();
+    pub fn has_totp(&self) -> bool {
+        self.totp.as_ref().is_some_and(|totp| totp.confirmed)
     }
 }
-
@@ -0,0 +1,225 @@
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use crate::auth::User;
+use crate::db::{CouchDB, DocRef};
+use crate::store::{CodeRecords, StoreError};
+use crate::utils::env_or;
+
+const CODES_DB: &str = "codes";
+const PURGE_BATCH_SIZE: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CodePurpose {
+    Activation,
+    PasswordReset,
+    /// Issued by `/login` to accounts with two-factor authentication, and
+    /// exchanged for a session together with a TOTP code.
+    LoginChallenge,
+    /// The `state` of an OpenID Connect login, carrying its PKCE verifier and nonce.
+    OidcLogin,
+    /// Mailed to the new address of a user changing their email, with the
+    /// new address in `data`.
+    EmailChange,
+    /// A sign-in link, exchanged for a session like a password.
+    MagicLink,
+}
+
+/// A code mailed to a user. Only a hash of the code is stored, so the
+/// database alone can't be used to activate accounts or reset passwords.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OneTimeCode {
+    #[serde(rename = "_id")]
+    pub id: String,
+    #[serde(rename = "_rev", default, skip_serializing_if = "Option::is_none")]
+    pub rev: Option<String>,
+    pub purpose: CodePurpose,
+    pub email: String,
+    /// The account to create, for activation codes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<User>,
+    /// Whatever else the purpose needs to remember until the code is redeemed.
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub data: Value,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CodeConfig {
+    pub activation_ttl: chrono::Duration,
+    pub reset_ttl: chrono::Duration,
+    pub challenge_ttl: chrono::Duration,
+    pub oidc_ttl: chrono::Duration,
+    pub email_change_ttl: chrono::Duration,
+    pub magic_link_ttl: chrono::Duration,
+    /// How often expired codes are deleted.
+    pub purge_interval: Duration,
+}
+
+/// One-time codes for every `CodePurpose`, stored in `CodeRecords`, in
+/// production the CouchDB `codes` database.
+pub struct CodeStore {
+    records: Arc<dyn CodeRecords>,
+    config: CodeConfig,
+}
+
+impl CodeConfig {
+    pub fn from_env() -> Self {
+        CodeConfig {
+            activation_ttl: chrono::Duration::hours(env_or("ACTIVATION_CODE_TTL_HOURS", 48)),
+            reset_ttl: chrono::Duration::minutes(env_or("RESET_CODE_TTL_MINS", 60)),
+            challenge_ttl: chrono::Duration::minutes(env_or("LOGIN_CHALLENGE_TTL_MINS", 5)),
+            oidc_ttl: chrono::Duration::minutes(env_or("OIDC_LOGIN_TTL_MINS", 10)),
+            email_change_ttl: chrono::Duration::hours(env_or("EMAIL_CHANGE_TTL_HOURS", 24)),
+            magic_link_ttl: chrono::Duration::minutes(env_or("MAGIC_LINK_TTL_MINS", 15)),
+            purge_interval: Duration::from_secs(env_or("CODE_PURGE_INTERVAL_SECS", 3600)),
+        }
+    }
+
+    fn ttl(&self, purpose: CodePurpose) -> chrono::Duration {
+        match purpose {
+            CodePurpose::Activation => self.activation_ttl,
+            CodePurpose::PasswordReset => self.reset_ttl,
+            CodePurpose::LoginChallenge => self.challenge_ttl,
+            CodePurpose::OidcLogin => self.oidc_ttl,
+            CodePurpose::EmailChange => self.email_change_ttl,
+            CodePurpose::MagicLink => self.magic_link_ttl,
+        }
+    }
+}
+
+impl CodeStore {
+    pub fn new(records: Arc<dyn CodeRecords>, config: CodeConfig) -> Self {
+        CodeStore { records, config }
+    }
+
+    /// How long codes for `purpose` are valid.
+    pub fn ttl(&self, purpose: CodePurpose) -> chrono::Duration {
+        self.config.ttl(purpose)
+    }
+
+    pub async fn init(&self) -> Result<(), StoreError> {
+        self.records.init().await
+    }
+
+    /// Stores a new code and returns it. The returned code is the only copy.
+    pub async fn issue(&self, purpose: CodePurpose, email: String, user: Option<User>) -> Result<String, StoreError> {
+        self.issue_with_data(purpose, email, user, Value::Null).await
+    }
+
+    /// Like `issue`, with `data` stored alongside the code.
+    pub async fn issue_with_data(&self, purpose: CodePurpose, email: String, user: Option<User>, data: Value) -> Result<String, StoreError> {
+        let code = Uuid::new_v4().to_string();
+        let now = Utc::now();
+        let stored = OneTimeCode {
+            id: hash_code(&code),
+            rev: None,
+            purpose,
+            email,
+            user,
+            data,
+            created_at: now,
+            expires_at: now + self.config.ttl(purpose),
+        };
+        self.records.put_code(&stored).await?;
+        Ok(code)
+    }
+
+    /// Looks up `code` for `purpose` without using it up. Returns `Ok(None)`
+    /// for unknown, expired or mismatched codes.
+    pub async fn get(&self, code: &str, purpose: CodePurpose) -> Result<Option<OneTimeCode>, StoreError> {
+        let stored = match self.records.get_code(&hash_code(code)).await {
+            Ok(stored) => stored,
+            Err(StoreError::NotFound) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if stored.purpose != purpose || stored.expires_at <= Utc::now() {
+            return Ok(None);
+        }
+        Ok(Some(stored))
+    }
+
+    /// Redeems `code` for `purpose`. A code works once: it is deleted with the
+    /// revision that was read, so of two concurrent requests only one wins.
+    /// Returns `Ok(None)` for unknown, expired, already used or mismatched codes.
+    pub async fn consume(&self, code: &str, purpose: CodePurpose) -> Result<Option<OneTimeCode>, StoreError> {
+        let Some(stored) = self.get(code, purpose).await? else { return Ok(None) };
+        match self.records.delete_code(&stored).await {
+            Ok(()) => Ok(Some(stored)),
+            Err(StoreError::Conflict | StoreError::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Deletes expired codes, returns how many were removed.
+    pub async fn purge_expired(&self) -> Result<usize, StoreError> {
+        self.records.purge_codes(Utc::now()).await
+    }
+}
+
+#[async_trait]
+impl CodeRecords for CouchDB {
+    /// Creates the `codes` database and its index if they are missing.
+    async fn init(&self) -> Result<(), StoreError> {
+        self.ensure_database(CODES_DB).await?;
+        Ok(self.ensure_index(CODES_DB, "expires-at", &["expires_at"]).await?)
+    }
+
+    async fn put_code(&self, code: &OneTimeCode) -> Result<(), StoreError> {
+        self.put_doc(CODES_DB, &code.id, code).await?;
+        Ok(())
+    }
+
+    async fn get_code(&self, id: &str) -> Result<OneTimeCode, StoreError> {
+        Ok(self.get_doc(CODES_DB, id).await?)
+    }
+
+    async fn delete_code(&self, code: &OneTimeCode) -> Result<(), StoreError> {
+        Ok(self.delete_doc(CODES_DB, &code.id, code.rev.as_deref().unwrap_or_default()).await?)
+    }
+
+    async fn purge_codes(&self, now: DateTime<Utc>) -> Result<usize, StoreError> {
+        let mut purged = 0;
+        loop {
+            let expired: Vec<DocRef> = self.find_docs(CODES_DB, json!({
+                "selector": { "expires_at": { "$lt": now } },
+                "fields": ["_id", "_rev"],
+                "limit": PURGE_BATCH_SIZE,
+            })).await?;
+            if expired.is_empty() {
+                return Ok(purged);
+            }
+            self.bulk_delete(CODES_DB, &expired).await?;
+            purged += expired.len();
+            if expired.len() < PURGE_BATCH_SIZE {
+                return Ok(purged);
+            }
+        }
+    }
+}
+
+/// Periodically deletes expired codes for as long as the server runs.
+pub fn spawn_purge_task(store: Arc<CodeStore>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(store.config.purge_interval);
+        loop {
+            interval.tick().await;
+            match store.purge_expired().await {
+                Ok(0) => {},
+                Ok(purged) => println!("codes: purged {} expired codes", purged),
+                Err(e) => println!("codes: purge failed: {:?}", e),
+            }
+        }
+    });
+}
+
+fn hash_code(code: &str) -> String {
+    hex::encode(Sha256::digest(code.as_bytes()))
+}
@@ -0,0 +1,200 @@
+use std::env;
+use actix_web::cookie::time::Duration as CookieDuration;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::Method;
+use actix_web::{HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use crate::session::IssuedSession;
+use crate::utils::{constant_time_eq, env_or, ApiResponse};
+
+/// Holds the access token. `HttpOnly`, so scripts on the page can't read it.
+pub const SESSION_COOKIE: &str = "ct_session";
+/// Holds the refresh token, also `HttpOnly`.
+pub const REFRESH_COOKIE: &str = "ct_refresh";
+/// Holds the CSRF token. Readable by the frontend, which echoes it in `CSRF_HEADER`.
+pub const CSRF_COOKIE: &str = "ct_csrf";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+/// Holds a hash of the `state` of an OpenID Connect login, so only the
+/// browser that started it can finish it.
+pub const OIDC_STATE_COOKIE: &str = "ct_oidc_state";
+/// Sent by the frontend on sign-in requests to get cookies instead of tokens in the body.
+pub const TRANSPORT_HEADER: &str = "X-Session-Transport";
+
+#[derive(Debug, Clone)]
+pub struct CookieConfig {
+    /// Only unset for local development over plain HTTP.
+    pub secure: bool,
+    pub same_site: SameSite,
+    pub domain: Option<String>,
+}
+
+/// What a cookie sign-in returns instead of `TokenResponse`.
+#[derive(Serialize)]
+pub struct CookieSessionResponse {
+    expires_at: DateTime<Utc>,
+    refresh_expires_at: Option<DateTime<Utc>>,
+    csrf_token: String,
+}
+
+impl CookieConfig {
+    pub fn from_env() -> Self {
+        let same_site = match env::var("COOKIE_SAME_SITE").unwrap_or_default().to_ascii_lowercase().as_str() {
+            "lax" => SameSite::Lax,
+            "none" => SameSite::None,
+            _ => SameSite::Strict,
+        };
+        CookieConfig {
+            secure: env_or("COOKIE_SECURE", true),
+            same_site,
+            domain: env::var("COOKIE_DOMAIN").ok().filter(|domain| !domain.is_empty()),
+        }
+    }
+
+    fn cookie(&self, name: &'static str, value: String, http_only: bool, until: DateTime<Utc>) -> Cookie<'static> {
+        let max_age = (until - Utc::now()).num_seconds().max(0);
+        let mut cookie = Cookie::build(name, value)
+            .path("/")
+            .http_only(http_only)
+            .secure(self.secure)
+            .same_site(self.same_site)
+            .max_age(CookieDuration::seconds(max_age))
+            .finish();
+        if let Some(domain) = &self.domain {
+            cookie.set_domain(domain.clone());
+        }
+        cookie
+    }
+
+    /// Hands `issued` to the browser as cookies, with a fresh CSRF token.
+    pub fn session_response(&self, issued: IssuedSession) -> HttpResponse {
+        let session_end = issued.session.absolute_expires_at.unwrap_or(issued.session.expires_at);
+        let csrf_token = Uuid::new_v4().simple().to_string();
+        HttpResponse::Ok()
+            .cookie(self.cookie(SESSION_COOKIE, issued.access_token, true, issued.session.expires_at))
+            .cookie(self.cookie(REFRESH_COOKIE, issued.refresh_token, true, session_end))
+            .cookie(self.cookie(CSRF_COOKIE, csrf_token.clone(), false, session_end))
+            .json(CookieSessionResponse {
+                expires_at: issued.session.expires_at,
+                refresh_expires_at: issued.session.absolute_expires_at,
+                csrf_token,
+            })
+    }
+
+    /// Binds the OpenID Connect login with `state` to this browser until `until`.
+    /// `Lax`, so it still comes along when the provider redirects back.
+    pub fn oidc_state(&self, state: &str, until: DateTime<Utc>) -> Cookie<'static> {
+        let mut cookie = self.cookie(OIDC_STATE_COOKIE, hash_state(state), true, until);
+        cookie.set_same_site(SameSite::Lax);
+        cookie
+    }
+
+    /// Tells the browser to drop the OpenID Connect state cookie, once the login is over.
+    pub fn clear_oidc_state(&self, response: &mut HttpResponse) {
+        let mut cookie = self.oidc_state("", Utc::now());
+        cookie.make_removal();
+        if let Err(e) = response.add_cookie(&cookie) {
+            println!("cookies: {} not cleared: {:?}", OIDC_STATE_COOKIE, e);
+        }
+    }
+
+    /// Tells the browser to drop the session cookies, after a logout.
+    pub fn clear(&self, response: &mut HttpResponse) {
+        for name in [SESSION_COOKIE, REFRESH_COOKIE, CSRF_COOKIE] {
+            let mut cookie = self.cookie(name, String::new(), true, Utc::now());
+            cookie.make_removal();
+            if let Err(e) = response.add_cookie(&cookie) {
+                println!("cookies: {} not cleared: {:?}", name, e);
+            }
+        }
+    }
+}
+
+/// Whether the client asked for cookies rather than tokens in the response body.
+pub fn wants_cookies(req: &HttpRequest) -> bool {
+    req.headers().get(TRANSPORT_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("cookie"))
+}
+
+/// Double-submit check for requests authenticated by cookie: anything but a
+/// safe method must repeat the CSRF cookie in `CSRF_HEADER`. Another site can
+/// make the browser send our cookies, but can't read them to set the header.
+pub fn check_csrf(req: &HttpRequest) -> Result<(), ApiResponse> {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return Ok(());
+    }
+    let cookie = req.cookie(CSRF_COOKIE).ok_or(ApiResponse::Forbidden)?;
+    let header = req.headers().get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .ok_or(ApiResponse::Forbidden)?;
+    if cookie.value().is_empty() || !constant_time_eq(cookie.value().as_bytes(), header.as_bytes()) {
+        return Err(ApiResponse::Forbidden);
+    }
+    Ok(())
+}
+
+/// Whether `state` is the OpenID Connect login this browser started.
+pub fn check_oidc_state(req: &HttpRequest, state: &str) -> bool {
+    req.cookie(OIDC_STATE_COOKIE)
+        .is_some_and(|cookie| constant_time_eq(cookie.value().as_bytes(), hash_state(state).as_bytes()))
+}
+
+fn hash_state(state: &str) -> String {
+    hex::encode(Sha256::digest(state.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn lets_safe_methods_through() {
+        assert!(check_csrf(&TestRequest::get().to_http_request()).is_ok());
+        assert!(check_csrf(&TestRequest::default().method(Method::HEAD).to_http_request()).is_ok());
+    }
+
+    #[test]
+    fn requires_the_header_to_repeat_the_cookie() {
+        let req = TestRequest::post()
+            .cookie(Cookie::new(CSRF_COOKIE, "token"))
+            .insert_header((CSRF_HEADER, "token"))
+            .to_http_request();
+        assert!(check_csrf(&req).is_ok());
+
+        let mismatch = TestRequest::post()
+            .cookie(Cookie::new(CSRF_COOKIE, "token"))
+            .insert_header((CSRF_HEADER, "other"))
+            .to_http_request();
+        assert!(check_csrf(&mismatch).is_err());
+
+        let no_header = TestRequest::delete().cookie(Cookie::new(CSRF_COOKIE, "token")).to_http_request();
+        assert!(check_csrf(&no_header).is_err());
+
+        let no_cookie = TestRequest::put().insert_header((CSRF_HEADER, "token")).to_http_request();
+        assert!(check_csrf(&no_cookie).is_err());
+
+        let empty = TestRequest::patch()
+            .cookie(Cookie::new(CSRF_COOKIE, ""))
+            .insert_header((CSRF_HEADER, ""))
+            .to_http_request();
+        assert!(check_csrf(&empty).is_err());
+    }
+
+    #[test]
+    fn matches_the_oidc_state_cookie() {
+        let config = CookieConfig { secure: true, same_site: SameSite::Strict, domain: None };
+        let cookie = config.oidc_state("state", Utc::now());
+        assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_ne!(cookie.value(), "state");
+
+        let req = TestRequest::get().cookie(cookie).to_http_request();
+        assert!(check_oidc_state(&req, "state"));
+        assert!(!check_oidc_state(&req, "other"));
+        assert!(!check_oidc_state(&TestRequest::get().to_http_request(), "state"));
+    }
+}
@@ -1,183 +1,259 @@
-use reqwest::Client;
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use reqwest::{Client, StatusCode};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use serde_json::{Number, Value};
+use serde_json::{json, Value};
+use tokio::sync::broadcast;
+use uuid::Uuid;
 use crate::auth::User;
+use crate::store::{self, ConfigStore, DocumentPatch, DocumentStore, StoreError, UserStore};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Document {
     #[serde(rename = "_id")]
     pub id: Option<String>,
-    #[serde(rename = "_rev")]
+    /// Unset on a project that hasn't been written yet.
+    #[serde(rename = "_rev", skip_serializing_if = "Option::is_none")]
     pub rev: Option<String>,
+    /// Email of the user who created the project. Missing on projects created
+    /// before ownership was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// Set while the project is in the trash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted: Option<Deletion>,
     pub data: Value,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct NewDocument {
+/// A copy of a project's data as it was written, kept in `project_history`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Revision {
     #[serde(rename = "_id")]
-    pub id: String,
+    pub id: Uuid,
+    pub project_id: String,
+    #[serde(flatten)]
+    pub info: RevisionInfo,
     pub data: Value,
 }
 
+/// What `GET /{id}/history` lists of a revision.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RevisionInfo {
+    /// The project's `_rev` after the write, also its `ETag`.
+    pub rev: String,
+    pub author: String,
+    pub at: DateTime<Utc>,
+}
+
+/// A write to a project, as passed on to `GET /{id}/events` subscribers.
+#[derive(Debug, Clone)]
+pub struct ProjectChange {
+    pub id: String,
+    pub rev: String,
+    /// The project was deleted for good. Moving it to the trash is a normal write.
+    pub deleted: bool,
+}
+
+/// One line of the continuous `_changes` feed: a change, or `last_seq` when the feed ends.
+#[derive(Debug, Deserialize)]
+struct ChangeLine {
+    seq: Option<Value>,
+    id: Option<String>,
+    #[serde(default)]
+    changes: Vec<ChangeRev>,
+    #[serde(default)]
+    deleted: bool,
+    last_seq: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChangeRev {
+    rev: String,
+}
+
+/// When and by whom a project was moved to the trash.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Deletion {
+    pub at: DateTime<Utc>,
+    pub by: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
-pub struct UserPayload {
-    pub email: String,
-    pub newsletter: bool,
-    pub hashed: String,
-    pub salt: String,
-    pub uuids: Vec<String>,
+struct UserPayload {
+    #[serde(flatten)]
+    pub user: User,
+    #[serde(rename = "_rev", skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+}
+
+/// How often a read-modify-write is retried when CouchDB reports a conflict.
+const UPDATE_ATTEMPTS: u32 = 5;
+const PURGE_BATCH_SIZE: usize = 500;
+const HISTORY_DB: &str = "project_history";
+/// How many changes a slow `GET /{id}/events` subscriber may fall behind before it skips ahead.
+const CHANGES_BUFFER: usize = 1024;
+/// Asks CouchDB for an empty line this often, so a dead connection is noticed.
+const CHANGES_HEARTBEAT_MS: u64 = 30_000;
+const CHANGES_MIN_BACKOFF: Duration = Duration::from_secs(1);
+const CHANGES_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// The `_id`/`_rev` pair of a stored document, enough to update or delete it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DocRef {
+    #[serde(rename = "_id")]
+    pub id: String,
     #[serde(rename = "_rev")]
+    pub rev: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct FindResponse<T> {
+    docs: Vec<T>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WriteResponse {
     rev: String,
 }
 
+/// The production store: users, projects and config in CouchDB, plus the
+/// generic document access the session, code, API key and audit stores use.
 pub struct CouchDB {
     client: Client,
     url: String,
-    auth: (String, String)
+    auth: (String, String),
+    /// Fed by `spawn_changes_feed`.
+    changes: broadcast::Sender<ProjectChange>,
 }
 
-
-
 impl CouchDB {
     pub fn new(url: String, username: String, password: String) -> Self {
         CouchDB {
             client: Client::new(),
             url,
-            auth: (username, password)
+            auth: (username, password),
+            changes: broadcast::channel(CHANGES_BUFFER).0,
         }
     }
 
-    pub async fn get_document(&self, id: &str) -> Result<Document, reqwest::Error> {
-        let url = format!("{}/projects/{}", self.url, id);
-        let response = self
+    /// Reads the continuous `_changes` feed of `projects` from `since` until
+    /// CouchDB ends it or the connection fails, and broadcasts every project
+    /// write. `since` is moved along with each change, so a reconnect resumes
+    /// right after the last one seen.
+    async fn follow_changes(&self, since: &mut Value, backoff: &mut Duration) -> Result<(), reqwest::Error> {
+        let url = format!("{}/projects/_changes", self.url);
+        let since_param = match &*since {
+            Value::String(seq) => seq.clone(),
+            seq => seq.to_string(),
+        };
+        let mut response = self
             .client
             .get(&url)
-            .header("Content-Type", "application/json")
             .basic_auth(&self.auth.0, Some(&self.auth.1))
+            .query(&[("feed", "continuous"), ("since", &since_param), ("heartbeat", &CHANGES_HEARTBEAT_MS.to_string())])
             .send()
-        .await?;
-        let response = response.error_for_status()?;
-        let document: Document = response.json().await?;
-        Ok(document)
+            .await?
+            .error_for_status()?;
+        *backoff = CHANGES_MIN_BACKOFF;
+
+        let mut buffer = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            buffer.extend_from_slice(&chunk);
+            while let Some(end) = buffer.iter().position(|&byte| byte == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                // Heartbeats are empty lines
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                let change: ChangeLine = match serde_json::from_slice(&line) {
+                    Ok(change) => change,
+                    Err(e) => {
+                        println!("projects: skipping unreadable change: {:?}", e);
+                        continue;
+                    }
+                };
+                if let Some(seq) = change.last_seq.or(change.seq) {
+                    *since = seq;
+                }
+                let (Some(id), Some(latest)) = (change.id, change.changes.into_iter().next()) else { continue };
+                if id.starts_with("_design/") {
+                    continue;
+                }
+                // Nobody listening is not an error
+                let _ = self.changes.send(ProjectChange { id, rev: latest.rev, deleted: change.deleted });
+            }
+        }
+        Ok(())
     }
 
-    pub async fn get_document_data(&self, id: &str) -> Result<Value, reqwest::Error> {
-        match self.get_document(id).await {
-            Ok(doc) => Ok(doc.data),
-            Err(e) => Err(e)
+    /// Deletes every recorded revision of project `id`, after it was deleted for good.
+    async fn delete_revisions(&self, id: &str) -> Result<(), reqwest::Error> {
+        loop {
+            let revisions: Vec<DocRef> = self.find_docs(HISTORY_DB, json!({
+                "selector": { "project_id": id, "at": { "$gte": null } },
+                "fields": ["_id", "_rev"],
+                "limit": PURGE_BATCH_SIZE,
+            })).await?;
+            if !revisions.is_empty() {
+                self.bulk_delete(HISTORY_DB, &revisions).await?;
+            }
+            if revisions.len() < PURGE_BATCH_SIZE {
+                return Ok(());
+            }
         }
     }
 
-    pub async fn get_config_data(&self) -> Result<Value, reqwest::Error> {
-        let url = format!("{}/config/config", self.url);
+    async fn get_user_payload(&self, email: &str) -> Result<UserPayload, reqwest::Error> {
+        let url = format!("{}/users/{}", self.url, email);
         let response = self
             .client
             .get(&url)
             .header("Content-Type", "application/json")
             .basic_auth(&self.auth.0, Some(&self.auth.1))
             .send()
-        .await?;
+            .await?;
+
         let response = response.error_for_status()?;
-        let document: Document = response.json().await?;
-        Ok(document.data)
+        let user: UserPayload = response.json().await?;
+        Ok(user)
     }
 
-    pub async fn put_document(&self, id: &str, data: Value) -> Result<Value, reqwest::Error> {
-        let url = format!("{}/projects/{}", self.url, id);
-        match self.get_document(id).await {
-            Ok(doc) => {
-                let updated_doc = Document {
-                    id: doc.id.clone(),
-                    rev: doc.rev.clone(),
-                    data: CouchDB::combine_json_values(doc.data.clone(), data),
-                };
-                let response = self
-                    .client
-                    .put(&url)
-                    .header("Content-Type", "application/json")
-                    .basic_auth(&self.auth.0, Some(&self.auth.1))
-                    .json(&updated_doc)
-                    .send()
-                    .await?;
-
-                let _response = response.error_for_status()?;
-                Ok(updated_doc.data)
-            }
-            Err(e) if e.status() == Some(reqwest::StatusCode::NOT_FOUND) => {
-                let new_doc = NewDocument {
-                    id: id.to_string(),
-                    data,
-                };
-                let response = self
-                    .client
-                    .put(&url)
-                    .header("Content-Type", "application/json")
-                    .basic_auth(&self.auth.0, Some(&self.auth.1))
-                    .json(&new_doc)
-                    .send()
-                    .await?;
-
-                let _response = response.error_for_status()?;
-                let document: Document = self.get_document(id).await?;
-                Ok(document.data)
-            }
-            Err(e) => return Err(e),
+    /// Creates the database `name` unless it already exists.
+    pub async fn ensure_database(&self, name: &str) -> Result<(), reqwest::Error> {
+        let url = format!("{}/{}", self.url, name);
+        let response = self
+            .client
+            .put(&url)
+            .basic_auth(&self.auth.0, Some(&self.auth.1))
+            .send()
+            .await?;
+        if response.status() == StatusCode::PRECONDITION_FAILED {
+            return Ok(());
         }
+        response.error_for_status()?;
+        Ok(())
     }
 
-
-    pub async fn put_user(&self, user: User) -> Result<User , reqwest::Error> {
-        let url = format!("{}/users/{}", self.url, user.email);
-        match self.get_user_payload(&user.email).await {
-            Ok(user_payload) => {
-                let updated_user = UserPayload {
-                    email: user.email.clone(),
-                    newsletter: user.newsletter.clone(),
-                    hashed: user_payload.hashed.clone(),
-                    salt: user_payload.salt.clone(),
-                    uuids: user.uuids.clone(),
-                    rev: user_payload.rev.clone(),
-                };
-                let response = self
-                    .client
-                    .put(&url)
-                    .header("Content-Type", "application/json")
-                    .basic_auth(&self.auth.0, Some(&self.auth.1))
-                    .json(&updated_user)
-                    .send()
-                    .await?;
-
-                let _response = response.error_for_status()?;
-                Ok(user)
-            }
-        Err(_)  => {
-                println!("404");
-            let new_user = User {
-                email: user.email.clone(),
-                newsletter: user.newsletter.clone(),
-                hashed: user.hashed.clone(),
-                salt: user.salt.clone(),
-                uuids: user.uuids.clone(),
-                last_uuid: user.last_uuid.clone()
-            };
-            let response = self
-                .client
-                .put(&url)
-                .header("Content-Type", "application/json")
-                .basic_auth(&self.auth.0, Some(&self.auth.1))
-                .json(&new_user)
-                .send()
-                .await?;
-
-            let _response = response.error_for_status()?;
-            let user: User = self.get_user(&user.email).await?;
-            Ok(user)
-        }
-        }
+    /// Creates a Mango index on `fields` so `_find` queries on them don't scan the database.
+    pub async fn ensure_index(&self, db: &str, name: &str, fields: &[&str]) -> Result<(), reqwest::Error> {
+        let url = format!("{}/{}/_index", self.url, db);
+        let response = self
+            .client
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .basic_auth(&self.auth.0, Some(&self.auth.1))
+            .json(&json!({ "index": { "fields": fields }, "name": name, "type": "json" }))
+            .send()
+            .await?;
+        response.error_for_status()?;
+        Ok(())
     }
 
-    pub async fn get_user_payload(&self, email: &str) -> Result<UserPayload, reqwest::Error> {
-        let url = format!("{}/users/{}", self.url, email);
+    pub async fn get_doc<T: DeserializeOwned>(&self, db: &str, id: &str) -> Result<T, reqwest::Error> {
+        let url = format!("{}/{}/{}", self.url, db, id);
         let response = self
             .client
             .get(&url)
@@ -185,34 +261,29 @@ impl CouchDB {
             .basic_auth(&self.auth.0, Some(&self.auth.1))
             .send()
             .await?;
-
-        let user: UserPayload = response.json().await?;
-        println!("User: {:?}", user);
-        Ok(user)
+        let response = response.error_for_status()?;
+        response.json().await
     }
 
-    pub async fn get_user(&self, email: &str) -> Result<User, reqwest::Error> {
-        println!("Getting user...");
-        let url = format!("{}/users/{}", self.url, email);
-        println!("with URL: {}", url);
+    /// Writes `doc` as `db/id` and returns the new revision. Updates must carry
+    /// the current `_rev` inside `doc`.
+    pub async fn put_doc<T: Serialize>(&self, db: &str, id: &str, doc: &T) -> Result<String, reqwest::Error> {
+        let url = format!("{}/{}/{}", self.url, db, id);
         let response = self
             .client
-            .get(&url)
+            .put(&url)
             .header("Content-Type", "application/json")
             .basic_auth(&self.auth.0, Some(&self.auth.1))
+            .json(doc)
             .send()
             .await?;
-
         let response = response.error_for_status()?;
-        let user: User = response.json().await?;
-        println!("User name: {}", user.clone().email);
-        Ok(user)
+        let written: WriteResponse = response.json().await?;
+        Ok(written.rev)
     }
 
-    pub async fn delete_user(&self, email: &str) -> Result<bool, reqwest::Error> {
-        let user: UserPayload = self.get_user_payload(&email).await?;
-        let rev = user.rev;
-        let url = format!("{}/users/{}?rev={}", self.url, email, rev);
+    pub async fn delete_doc(&self, db: &str, id: &str, rev: &str) -> Result<(), reqwest::Error> {
+        let url = format!("{}/{}/{}?rev={}", self.url, db, id, rev);
         let response = self
             .client
             .delete(&url)
@@ -220,21 +291,324 @@ impl CouchDB {
             .basic_auth(&self.auth.0, Some(&self.auth.1))
             .send()
             .await?;
+        response.error_for_status()?;
+        Ok(())
+    }
+
+    /// Runs a Mango query (`POST /{db}/_find`) and returns the matching documents.
+    pub async fn find_docs<T: DeserializeOwned>(&self, db: &str, query: Value) -> Result<Vec<T>, reqwest::Error> {
+        let url = format!("{}/{}/_find", self.url, db);
+        let response = self
+            .client
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .basic_auth(&self.auth.0, Some(&self.auth.1))
+            .json(&query)
+            .send()
+            .await?;
+        let response = response.error_for_status()?;
+        let found: FindResponse<T> = response.json().await?;
+        Ok(found.docs)
+    }
 
+    /// Deletes all `docs` in a single `_bulk_docs` request.
+    pub async fn bulk_delete(&self, db: &str, docs: &[DocRef]) -> Result<(), reqwest::Error> {
+        let url = format!("{}/{}/_bulk_docs", self.url, db);
+        let docs: Vec<Value> = docs
+            .iter()
+            .map(|doc| json!({ "_id": doc.id, "_rev": doc.rev, "_deleted": true }))
+            .collect();
+        let response = self
+            .client
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .basic_auth(&self.auth.0, Some(&self.auth.1))
+            .json(&json!({ "docs": docs }))
+            .send()
+            .await?;
         response.error_for_status()?;
-        Ok(true)
+        Ok(())
     }
+}
 
-    pub fn combine_json_values(old_document: Value, new_content: Value) -> Value {
-        let combined = match old_document {
-            Value::Object(mut map) => {
-                if let Value::Object(new_map) = new_content {
-                    map.extend(new_map);
-                }
-                Value::Object(map)
-            },
-            _ => new_content,
+/// Follows the `projects` changes for as long as the server runs, starting
+/// from now. Errors are logged and retried with a growing delay, resuming
+/// after the last change seen, so subscribers miss nothing in between.
+pub fn spawn_changes_feed(db: Arc<CouchDB>) {
+    tokio::spawn(async move {
+        let mut since = Value::from("now");
+        let mut backoff = CHANGES_MIN_BACKOFF;
+        loop {
+            // Without an error the feed ended on CouchDB's side, pick it up again right away
+            if let Err(e) = db.follow_changes(&mut since, &mut backoff).await {
+                println!("projects: changes feed failed, retrying in {:?}: {:?}", backoff, e);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(CHANGES_MAX_BACKOFF);
+            }
+        }
+    });
+}
+
+#[async_trait]
+impl DocumentStore for CouchDB {
+    async fn init(&self) -> Result<(), StoreError> {
+        self.ensure_index("projects", "deleted-at", &["deleted.at"]).await?;
+        self.ensure_database(HISTORY_DB).await?;
+        self.ensure_index(HISTORY_DB, "project-id-at", &["project_id", "at"]).await?;
+        Ok(self.ensure_index(HISTORY_DB, "at", &["at"]).await?)
+    }
+
+    async fn get_document(&self, id: &str) -> Result<Document, StoreError> {
+        Ok(self.get_doc("projects", id).await?)
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<ProjectChange> {
+        self.changes.subscribe()
+    }
+
+    async fn put_document(&self, id: &str, data: Value, if_match: Option<&str>) -> Result<Document, StoreError> {
+        let mut attempt = 1;
+        loop {
+            let mut doc = match self.get_document(id).await {
+                Ok(doc) => doc,
+                Err(StoreError::NotFound) if if_match.is_none() => Document {
+                    id: Some(id.to_string()),
+                    rev: None,
+                    owner: None,
+                    deleted: None,
+                    data: Value::Null,
+                },
+                Err(e) => return Err(e),
+            };
+            if if_match.is_some_and(|rev| doc.rev.as_deref() != Some(rev)) {
+                return Err(StoreError::Conflict);
+            }
+            doc.data = store::combine_json_values(doc.data, data.clone());
+            match self.put_doc("projects", id, &doc).await {
+                Ok(rev) => {
+                    doc.rev = Some(rev);
+                    return Ok(doc);
+                },
+                // Someone else wrote in between. Merge into their version, unless the caller named the revision it expects
+                Err(e) if e.status() == Some(StatusCode::CONFLICT) && if_match.is_none() && attempt < UPDATE_ATTEMPTS => {
+                    attempt += 1;
+                },
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    async fn patch_document(&self, id: &str, patch: &DocumentPatch, if_match: Option<&str>) -> Result<Document, StoreError> {
+        let mut attempt = 1;
+        loop {
+            let mut doc = self.get_document(id).await?;
+            if if_match.is_some_and(|rev| doc.rev.as_deref() != Some(rev)) {
+                return Err(StoreError::Conflict);
+            }
+            patch.apply(&mut doc.data)?;
+            match self.put_doc("projects", id, &doc).await {
+                Ok(rev) => {
+                    doc.rev = Some(rev);
+                    return Ok(doc);
+                },
+                // Apply it again to the version that got in between
+                Err(e) if e.status() == Some(StatusCode::CONFLICT) && if_match.is_none() && attempt < UPDATE_ATTEMPTS => {
+                    attempt += 1;
+                },
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    async fn create_document(&self, id: &str, owner: &str, data: Value) -> Result<Document, StoreError> {
+        let mut doc = Document {
+            id: Some(id.to_string()),
+            rev: None,
+            owner: Some(owner.to_string()),
+            deleted: None,
+            data,
         };
-        combined
+        doc.rev = Some(self.put_doc("projects", id, &doc).await?);
+        Ok(doc)
+    }
+
+    async fn delete_document(&self, id: &str) -> Result<(), StoreError> {
+        let doc = self.get_document(id).await?;
+        self.delete_doc("projects", id, &doc.rev.unwrap_or_default()).await?;
+        Ok(self.delete_revisions(id).await?)
+    }
+
+    async fn set_deleted(&self, id: &str, deleted: Option<Deletion>) -> Result<Document, StoreError> {
+        let mut attempt = 1;
+        loop {
+            let mut doc = self.get_document(id).await?;
+            doc.deleted = deleted.clone();
+            match self.put_doc("projects", id, &doc).await {
+                Ok(rev) => {
+                    doc.rev = Some(rev);
+                    return Ok(doc);
+                },
+                Err(e) if e.status() == Some(StatusCode::CONFLICT) && attempt < UPDATE_ATTEMPTS => {
+                    attempt += 1;
+                },
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    async fn purge_deleted(&self, before: DateTime<Utc>) -> Result<usize, StoreError> {
+        let mut purged = 0;
+        loop {
+            let expired: Vec<DocRef> = self.find_docs("projects", json!({
+                "selector": { "deleted.at": { "$lt": before } },
+                "fields": ["_id", "_rev"],
+                "limit": PURGE_BATCH_SIZE,
+            })).await?;
+            if expired.is_empty() {
+                return Ok(purged);
+            }
+            self.bulk_delete("projects", &expired).await?;
+            for doc in &expired {
+                self.delete_revisions(&doc.id).await?;
+            }
+            purged += expired.len();
+            if expired.len() < PURGE_BATCH_SIZE {
+                return Ok(purged);
+            }
+        }
+    }
+
+    async fn record_revision(&self, doc: &Document, author: &str) -> Result<(), StoreError> {
+        let (Some(id), Some(rev)) = (&doc.id, &doc.rev) else { return Ok(()) };
+        let revision = Revision {
+            id: Uuid::new_v4(),
+            project_id: id.clone(),
+            info: RevisionInfo { rev: rev.clone(), author: author.to_string(), at: Utc::now() },
+            data: doc.data.clone(),
+        };
+        self.put_doc(HISTORY_DB, &revision.id.to_string(), &revision).await?;
+        Ok(())
+    }
+
+    async fn list_revisions(&self, id: &str, skip: usize, limit: usize) -> Result<Vec<RevisionInfo>, StoreError> {
+        Ok(self.find_docs(HISTORY_DB, json!({
+            "selector": { "project_id": id, "at": { "$gte": null } },
+            "fields": ["rev", "author", "at"],
+            "sort": [{ "project_id": "desc" }, { "at": "desc" }],
+            "skip": skip,
+            "limit": limit,
+        })).await?)
+    }
+
+    async fn get_revision(&self, id: &str, rev: &str) -> Result<Revision, StoreError> {
+        let found: Vec<Revision> = self.find_docs(HISTORY_DB, json!({
+            "selector": { "project_id": id, "at": { "$gte": null }, "rev": rev },
+            "limit": 1,
+        })).await?;
+        found.into_iter().next().ok_or(StoreError::NotFound)
+    }
+
+    async fn purge_revisions(&self, before: DateTime<Utc>) -> Result<usize, StoreError> {
+        let mut purged = 0;
+        loop {
+            let expired: Vec<DocRef> = self.find_docs(HISTORY_DB, json!({
+                "selector": { "at": { "$lt": before } },
+                "fields": ["_id", "_rev"],
+                "limit": PURGE_BATCH_SIZE,
+            })).await?;
+            if expired.is_empty() {
+                return Ok(purged);
+            }
+            self.bulk_delete(HISTORY_DB, &expired).await?;
+            purged += expired.len();
+            if expired.len() < PURGE_BATCH_SIZE {
+                return Ok(purged);
+            }
+        }
+    }
+
+    async fn transfer_document(&self, id: &str, from: &str, to: &str) -> Result<bool, StoreError> {
+        let url = format!("{}/projects/{}", self.url, id);
+        let mut attempt = 1;
+        loop {
+            let mut doc = self.get_document(id).await?;
+            if doc.owner.as_deref() != Some(from) {
+                return Ok(false);
+            }
+            doc.owner = Some(to.to_string());
+            let response = self
+                .client
+                .put(&url)
+                .header("Content-Type", "application/json")
+                .basic_auth(&self.auth.0, Some(&self.auth.1))
+                .json(&doc)
+                .send()
+                .await?;
+            if response.status() == StatusCode::CONFLICT && attempt < UPDATE_ATTEMPTS {
+                attempt += 1;
+                continue;
+            }
+            response.error_for_status()?;
+            return Ok(true);
+        }
+    }
+}
+
+#[async_trait]
+impl UserStore for CouchDB {
+    async fn get_user(&self, email: &str) -> Result<User, StoreError> {
+        Ok(self.get_user_payload(email).await?.user)
+    }
+
+    async fn list_users(&self, skip: usize, limit: usize) -> Result<Vec<User>, StoreError> {
+        Ok(self.find_docs("users", json!({
+            "selector": { "email": { "$exists": true } },
+            "skip": skip,
+            "limit": limit,
+        })).await?)
+    }
+
+    async fn create_user(&self, user: User) -> Result<User, StoreError> {
+        let payload = UserPayload { user, rev: None };
+        self.put_doc("users", &payload.user.email, &payload).await?;
+        Ok(payload.user)
+    }
+
+    async fn modify_user(&self, email: &str, change: &mut (dyn for<'u> FnMut(&'u mut User) -> bool + Send)) -> Result<Option<User>, StoreError> {
+        let url = format!("{}/users/{}", self.url, email);
+        let mut attempt = 1;
+        loop {
+            let mut payload = self.get_user_payload(email).await?;
+            if !change(&mut payload.user) {
+                return Ok(None);
+            }
+            let response = self
+                .client
+                .put(&url)
+                .header("Content-Type", "application/json")
+                .basic_auth(&self.auth.0, Some(&self.auth.1))
+                .json(&payload)
+                .send()
+                .await?;
+            if response.status() == StatusCode::CONFLICT && attempt < UPDATE_ATTEMPTS {
+                attempt += 1;
+                continue;
+            }
+            response.error_for_status()?;
+            return Ok(Some(payload.user));
+        }
+    }
+
+    async fn delete_user(&self, email: &str) -> Result<(), StoreError> {
+        let user = self.get_user_payload(email).await?;
+        Ok(self.delete_doc("users", email, &user.rev.unwrap_or_default()).await?)
+    }
+}
+
+#[async_trait]
+impl ConfigStore for CouchDB {
+    async fn get_config_data(&self) -> Result<Value, StoreError> {
+        let document: Document = self.get_doc("config", "config").await?;
+        Ok(document.data)
     }
 }
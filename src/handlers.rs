@@ -1,12 +1,28 @@
-use actix_web::{web, HttpRequest, HttpResponse, Responder};
-use std::sync::{Arc, Mutex};
-use crate::db::CouchDB;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse, HttpResponseBuilder, Responder};
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use std::sync::Arc;
+use crate::db::{Deletion, Document, RevisionInfo};
+use crate::store::{ConfigStore, DocumentPatch, DocumentStore, StoreError, UserStore};
 use crate::utils::{self, ApiResponse};
 use crate::AppConfig;
 use serde_json::Value;
-use crate::auth::UserManager;
+use crate::auth::{ExternalIdentity, User};
+use crate::codes::{CodePurpose, CodeStore};
+use crate::session::{IssuedSession, RefreshError, SessionStore};
+use crate::rate_limit::{Action, RateLimiter};
+use crate::api_keys::{Access, ApiKey, ApiKeyScope, ApiKeyStore};
 use crate::email::EmailManager;
-use serde::Deserialize;
+use crate::password::{self, PasswordConfig, PasswordError, Verification};
+use crate::totp::TotpState;
+use crate::oidc::{OidcClient, OidcError, PendingLogin, VerifiedIdentity};
+use crate::audit::{AuditAction, AuditLog, AuditQuery};
+use crate::cookies::{self, CookieConfig, REFRESH_COOKIE, SESSION_COOKIE};
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
 
 #[derive(Deserialize)]
 pub struct LoginData {
@@ -26,6 +42,16 @@ pub struct RegisterData{
     uuid: String,
 }
 
+#[derive(Deserialize)]
+pub struct MagicLinkData {
+    email: String,
+}
+
+#[derive(Deserialize)]
+pub struct MagicLinkVerifyData {
+    code: String,
+}
+
 #[derive(Deserialize)]
 pub struct PreResetData {
     email: String,
@@ -37,465 +63,2366 @@ pub struct ResetData {
     password: String,
 }
 
+#[derive(Deserialize)]
+pub struct DeleteDocumentQuery {
+    /// Skip the trash.
+    #[serde(default)]
+    permanent: bool,
+}
+
+#[derive(Serialize)]
+struct DeletedDocument {
+    /// Until when `POST /{id}/undelete` brings it back, unless it was deleted permanently.
+    restorable_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    skip: Option<usize>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct RevisionData {
+    #[serde(flatten)]
+    info: RevisionInfo,
+    data: Value,
+}
+
+/// How often `GET /{id}/events` sends a comment, so proxies don't close an idle stream.
+const EVENTS_KEEPALIVE: Duration = Duration::from_secs(25);
+
+const DELETED_EVENT: &[u8] = b"event: deleted\ndata: {}\n\n";
+
+const DEFAULT_HISTORY_PAGE: usize = 50;
+const MAX_HISTORY_PAGE: usize = 500;
+
 #[derive(Deserialize)]
 pub struct AddUuid {
     uuid: String
 }
 
-pub async fn pre_register(auth_data: web::Json<PreRegisterData>, db: web::Data<Arc<CouchDB>>, user_manager: web::Data<Arc<Mutex<UserManager>>>, email_manager: web::Data<Arc<EmailManager>>, app_config: web::Data<AppConfig>) -> impl Responder {
+#[derive(Deserialize)]
+pub struct VerifyLoginData {
+    challenge: String,
+    /// A TOTP code or one of the recovery codes.
+    code: String,
+}
+
+#[derive(Deserialize)]
+pub struct TotpCodeData {
+    code: String,
+}
+
+#[derive(Deserialize)]
+pub struct CreateApiKeyData {
+    name: String,
+    scope: ApiKeyScope,
+}
+
+#[derive(Deserialize)]
+pub struct OidcCallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshData {
+    /// Left out by cookie sessions, which send it as a cookie.
+    #[serde(default)]
+    refresh_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordData {
+    current_password: String,
+    new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChangeEmailData {
+    new_email: String,
+    /// The current password. Accounts without one, created through OpenID Connect, leave it out.
+    #[serde(default)]
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmEmailData {
+    code: String,
+}
+
+#[derive(Serialize)]
+pub struct TokenResponse {
+    access_token: String,
+    refresh_token: String,
+    expires_at: DateTime<Utc>,
+    refresh_expires_at: Option<DateTime<Utc>>,
+}
+
+impl From<IssuedSession> for TokenResponse {
+    fn from(issued: IssuedSession) -> Self {
+        TokenResponse {
+            access_token: issued.access_token,
+            refresh_token: issued.refresh_token,
+            expires_at: issued.session.expires_at,
+            refresh_expires_at: issued.session.absolute_expires_at,
+        }
+    }
+}
+
+/// Returned by `/login` instead of tokens when the account has a second factor.
+#[derive(Serialize)]
+pub struct ChallengeResponse {
+    mfa_required: bool,
+    challenge: String,
+}
+
+#[derive(Serialize)]
+pub struct TotpEnrollment {
+    secret: String,
+    otpauth_uri: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct ApiKeyInfo {
+    id: String,
+    name: String,
+    scope: ApiKeyScope,
+    created_at: DateTime<Utc>,
+    last_used: Option<DateTime<Utc>>,
+    /// Only set in the response to creating the key.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+}
+
+impl From<ApiKey> for ApiKeyInfo {
+    fn from(key: ApiKey) -> Self {
+        ApiKeyInfo {
+            id: key.id.to_string(),
+            name: key.name,
+            scope: key.scope,
+            created_at: key.created_at,
+            last_used: key.last_used,
+            key: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct SessionInfo {
+    /// The login's family id, which stays the same across refreshes.
+    id: String,
+    device_info: String,
+    ip: String,
+    created_at: DateTime<Utc>,
+    last_used: DateTime<Utc>,
+    current: bool,
+}
+
+pub async fn pre_register(auth_data: web::Json<PreRegisterData>, users: web::Data<dyn UserStore>, codes: web::Data<Arc<CodeStore>>, email_manager: web::Data<Arc<EmailManager>>, limiter: web::Data<Arc<RateLimiter>>, app_config: web::Data<AppConfig>, req: HttpRequest) -> impl Responder {
     let url = &app_config.url;
-    let mut user_manager = match user_manager.lock() {
-        Ok(manager) => manager,
-        Err(_) =>  {
-            println!("pre-register: 500 (user_manager)");
+    let Some(email) = utils::normalize_email(&auth_data.email) else {
+        println!("pre-register: 400 (invalid email)");
+        return ApiResponse::BadRequest("Invalid email address".to_string()).to_response()
+    };
+    if let Err(wait) = limiter.check(Action::SendEmail, &utils::client_ip(&req), Some(&email)) {
+        println!("pre-register: 429 (rate limit)");
+        return ApiResponse::TooManyRequests(wait).to_response()
+    }
+    if let Err(violation) = password::check_policy(&app_config.password, &auth_data.password) {
+        println!("pre-register: 400 ({})", violation);
+        return ApiResponse::BadRequest(violation.to_string()).to_response()
+    }
+    // Hashed before the lookup, so taken addresses don't answer faster
+    let hashed = match hash_password(&app_config.password, &auth_data.password).await {
+        Ok(hashed) => hashed,
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("pre-register: 500 (hash_password)");
             return ApiResponse::InternalServerError.to_response()
         }
     };
-    if user_manager.user_exists(&auth_data.email) || db.get_user(&auth_data.email).await.is_ok() {
-        return ApiResponse::Conflict.to_response()
+    // Taken addresses get the same answer and a mail to the owner, so registering can't be used to probe for accounts
+    match users.get_user(&email).await {
+        Ok(existing) if existing.disabled => {
+            println!("pre-register: OK (account disabled, nothing sent)");
+            return ApiResponse::Ok.to_response()
+        },
+        Ok(_) => {
+            let subject = "You already have an account";
+            let body = format!("Someone tried to register with this email address, but it already has an account. Sign in at {}/auth, or reset your password there if you forgot it. If this wasn't you, you can ignore this email.", url);
+            return match email_manager.send_email(&email, subject, &body) {
+                Ok(_) => {
+                    println!("pre-register: OK (account exists, notified)");
+                    ApiResponse::Ok.to_response()
+                },
+                Err(_) => {
+                    println!("pre-register: 500 (email)");
+                    ApiResponse::InternalServerError.to_response()
+                }
+            }
+        },
+        Err(StoreError::NotFound) => {},
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("pre-register: 500 (users.get_user)");
+            return ApiResponse::InternalServerError.to_response()
+        }
     }
 
-    let user_uuid = user_manager.pre_register(auth_data.email.clone(), auth_data.password.clone(), auth_data.newsletter.clone());
+    let user = User::new(email.clone(), hashed, auth_data.newsletter);
+    let user_uuid = match codes.issue(CodePurpose::Activation, user.email.clone(), Some(user)).await {
+        Ok(code) => code,
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("pre-register: 500 (codes.issue)");
+            return ApiResponse::InternalServerError.to_response()
+        }
+    };
     let subject = "Activate Account";
     let body = format!("Click this link to activate your account: {}/auth?activate={}", url, user_uuid);
-    match email_manager.send_email(&auth_data.email, subject, &body) {
+    match email_manager.send_email(&email, subject, &body) {
         Ok(_) => {
             println!("pre-register: OK");
             ApiResponse::Ok.to_response()
         },
-        Err(_) => {
-            println!("pre-register: 500 (email)");
+        Err(_) => {
+            println!("pre-register: 500 (email)");
+            ApiResponse::InternalServerError.to_response()
+        }
+    }
+}
+
+pub async fn register(auth_data: web::Json<RegisterData>, users: web::Data<dyn UserStore>, codes: web::Data<Arc<CodeStore>>, audit: web::Data<Arc<AuditLog>>, limiter: web::Data<Arc<RateLimiter>>, req: HttpRequest) -> impl Responder {
+    if let Err(wait) = limiter.check(Action::RedeemCode, &utils::client_ip(&req), None) {
+        println!("register: 429 (rate limit)");
+        return ApiResponse::TooManyRequests(wait).to_response()
+    }
+    let user = match codes.consume(&auth_data.uuid, CodePurpose::Activation).await {
+        Ok(Some(code)) => code.user,
+        Ok(None) => None,
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("register: 500 (codes.consume)");
+            return ApiResponse::InternalServerError.to_response()
+        }
+    };
+    let user = match user {
+        Some(user) => user,
+        None => {
+            println!("register: 404 (codes.consume)");
+            return ApiResponse::NotFound.to_response()
+        }
+    };
+    // Someone may have registered the same address with another activation
+    // code or signed in with OpenID Connect, never overwrite their account
+    match users.create_user(user.clone()).await {
+        Ok(_) => {
+            audit.record(&req, AuditAction::Registered, &user.email, None, None).await;
+            println!("register: OK");
+            ApiResponse::Ok.to_response()
+        },
+        Err(StoreError::Conflict) => {
+            println!("register: 409 (users.create_user)");
+            ApiResponse::Conflict.to_response()
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("register: 500 (create_user)");
+            ApiResponse::InternalServerError.to_response()
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn login(auth_data: web::Json<LoginData>, users: web::Data<dyn UserStore>, sessions: web::Data<Arc<SessionStore>>, codes: web::Data<Arc<CodeStore>>, audit: web::Data<Arc<AuditLog>>, limiter: web::Data<Arc<RateLimiter>>, app_config: web::Data<AppConfig>, req: HttpRequest) -> impl Responder {
+    if let Err(wait) = limiter.check(Action::Login, &utils::client_ip(&req), Some(&auth_data.email)) {
+        println!("login: 429 (rate limit)");
+        return ApiResponse::TooManyRequests(wait).to_response()
+    }
+    if let Some(wait) = limiter.login_blocked(&auth_data.email) {
+        println!("login: 429 (too many failed attempts)");
+        return ApiResponse::TooManyRequests(wait).to_response()
+    }
+    let mut user_data = match users.get_user(&auth_data.email).await {
+        Ok(user) => user,
+        // Unknown accounts look like a wrong password, so logins can't be used to probe for accounts.
+        // Hashing anyway keeps them from answering faster.
+        Err(StoreError::NotFound) => {
+            if let Err(e) = verify_dummy(&app_config.password, &auth_data.password).await {
+                println!("login: dummy verification failed: {:?}", e);
+            }
+            limiter.login_failed(&auth_data.email);
+            audit.record(&req, AuditAction::LoginFailed, &auth_data.email, None, Some("password")).await;
+            println!("login: 401 (user not found in db)");
+            return ApiResponse::Unauthorized.to_response();
+        },
+        Err(e) => {
+            println!("login: user not found in db: {:?}", e);
+            return ApiResponse::InternalServerError.to_response();
+        }
+    };
+
+    let verification = match verify_password(&app_config.password, &auth_data.password, &user_data.hashed, &user_data.salt).await {
+        Ok(verification) => verification,
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("login: 500 (verify_password)");
+            return ApiResponse::InternalServerError.to_response()
+        }
+    };
+    match verification {
+        Verification::Invalid => {
+            limiter.login_failed(&auth_data.email);
+            audit.record(&req, AuditAction::LoginFailed, &auth_data.email, None, Some("password")).await;
+            println!("login: 401 (username & password don't match)");
+            return ApiResponse::Unauthorized.to_response()
+        },
+        Verification::NeedsRehash => {
+            // A failed migration must not block the login, the old hash stays valid
+            match hash_password(&app_config.password, &auth_data.password).await {
+                Ok(hashed) => {
+                    // Only replaces the hash that was verified, anything else written
+                    // while hashing is kept and a password changed meanwhile wins
+                    let verified = user_data.hashed.clone();
+                    let rehashed = users.update_user_if(&auth_data.email, |user| {
+                        if user.hashed != verified {
+                            return false;
+                        }
+                        user.hashed = hashed.clone();
+                        user.salt = String::new();
+                        true
+                    }).await;
+                    match rehashed {
+                        Ok(Some(user)) => {
+                            user_data = user;
+                            println!("login: password rehashed");
+                        },
+                        Ok(None) => println!("login: rehash skipped (password changed meanwhile)"),
+                        Err(e) => println!("login: rehash not stored: {:?}", e),
+                    }
+                },
+                Err(e) => println!("login: rehash failed: {:?}", e),
+            }
+        },
+        Verification::Valid => {}
+    }
+    limiter.login_succeeded(&auth_data.email);
+
+    start_session(user_data, &sessions, &codes, &audit, &app_config.cookies, &req, "login", "password").await
+}
+
+/// Ends a successful first sign-in step: tokens, or a TOTP challenge when the
+/// account has a second factor. `method` is how the user signed in, for the audit log.
+#[allow(clippy::too_many_arguments)]
+async fn start_session(user: User, sessions: &SessionStore, codes: &CodeStore, audit: &AuditLog, cookies: &CookieConfig, req: &HttpRequest, handler: &str, method: &str) -> HttpResponse {
+    // Only reached with valid credentials, so this doesn't tell strangers the account exists
+    if user.disabled {
+        println!("{}: 403 (account disabled)", handler);
+        return ApiResponse::AccountDisabled.to_response();
+    }
+    // The first factor alone is not enough, the client has to come back with a TOTP code
+    if user.has_totp() {
+        return match codes.issue(CodePurpose::LoginChallenge, user.email, None).await {
+            Ok(challenge) => {
+                println!("{}: OK (second factor required)", handler);
+                HttpResponse::Ok().json(ChallengeResponse { mfa_required: true, challenge })
+            },
+            Err(e) => {
+                println!("Error: {:?}", e);
+                println!("{}: 500 (codes.issue)", handler);
+                ApiResponse::InternalServerError.to_response()
+            }
+        };
+    }
+
+    match sessions.create(user.email.clone(), utils::describe_user_agent(req), utils::client_ip(req)).await {
+        Ok(issued) => {
+            audit.record(req, AuditAction::LoginSucceeded, &user.email, Some(&user.email), Some(method)).await;
+            println!("{}: OK", handler);
+            session_response(issued, cookies, req)
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("{}: 500 (sessions.create)", handler);
+            ApiResponse::InternalServerError.to_response()
+        }
+    }
+}
+
+pub async fn logout(sessions: web::Data<Arc<SessionStore>>, audit: web::Data<Arc<AuditLog>>, app_config: web::Data<AppConfig>, req: HttpRequest) -> impl Responder {
+    // Verify Session Token
+    let session = match utils::verfiy_session_token(&req, &sessions).await {
+        Ok(session) => session,
+        Err(e) => {
+            println!("logout: invalid session token");
+            return e.to_response();
+        }
+    };
+
+    if let Err(e) = sessions.remove(&session.token.to_string()).await {
+        println!("Error: {:?}", e);
+        println!("logout: 500 (sessions.remove)");
+        return ApiResponse::InternalServerError.to_response();
+    }
+    audit.record(&req, AuditAction::Logout, &session.user_id, Some(&session.user_id), None).await;
+    println!("logout: OK");
+    let mut response = ApiResponse::Ok.to_response();
+    if req.cookie(SESSION_COOKIE).is_some() {
+        app_config.cookies.clear(&mut response);
+    }
+    response
+}
+
+/// Tokens in the body, or cookies for browsers that asked for them.
+fn session_response(issued: IssuedSession, cookies: &CookieConfig, req: &HttpRequest) -> HttpResponse {
+    if cookies::wants_cookies(req) {
+        cookies.session_response(issued)
+    } else {
+        HttpResponse::Ok().json(TokenResponse::from(issued))
+    }
+}
+
+pub async fn send_reset_email(data: web::Json<PreResetData>, codes: web::Data<Arc<CodeStore>>, users: web::Data<dyn UserStore>, email_manager: web::Data<Arc<EmailManager>>, limiter: web::Data<Arc<RateLimiter>>, app_config: web::Data<AppConfig>, req: HttpRequest) -> impl Responder {
+    let url = &app_config.url;
+    println!("Sending Reset email request for: {}", data.email);
+    if let Err(wait) = limiter.check(Action::SendEmail, &utils::client_ip(&req), Some(&data.email)) {
+        println!("send_reset_email: 429 (rate limit)");
+        return ApiResponse::TooManyRequests(wait).to_response()
+    }
+    // Answer as if the mail was sent, so this can't be used to probe for
+    // accounts or find out which ones are suspended. /reset refuses the latter.
+    match users.get_user(&data.email).await {
+        Ok(user) if user.disabled => {
+            println!("send_reset_email: OK (account disabled, nothing sent)");
+            return ApiResponse::Ok.to_response()
+        },
+        Ok(_) => {},
+        Err(StoreError::NotFound) => {
+            println!("send_reset_email: OK (no such user, nothing sent)");
+            return ApiResponse::Ok.to_response()
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("send_reset_email: 500 (users.get_user)");
+            return ApiResponse::InternalServerError.to_response()
+        }
+    }
+    let onetimepassword = match codes.issue(CodePurpose::PasswordReset, data.email.clone(), None).await {
+        Ok(code) => code,
+        Err(e) =>  {
+            println!("Error: {:?}", e);
+            println!("send_reset_email: 500 (codes.issue)");
+            return ApiResponse::InternalServerError.to_response()
+        }
+    };
+    let subject = "Password Zurücksetzung";
+    let body = format!("Klicken Sie diesen Link um Ihr Password zurückzusetzen: {}/auth?code={}", url, onetimepassword);
+    match email_manager.send_email(&data.email, subject, &body) {
+        Ok(_) => {
+            println!("send_reset_email: OK");
+            ApiResponse::Ok.to_response()
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("send_reset_email: 500 (send_email)");
+            ApiResponse::InternalServerError.to_response()
+        }
+    }
+}
+
+pub async fn reset_password(data: web::Json<ResetData>, codes: web::Data<Arc<CodeStore>>, users: web::Data<dyn UserStore>, audit: web::Data<Arc<AuditLog>>, limiter: web::Data<Arc<RateLimiter>>, app_config: web::Data<AppConfig>, req: HttpRequest) -> impl Responder {
+    if let Err(wait) = limiter.check(Action::RedeemCode, &utils::client_ip(&req), None) {
+        println!("reset_password: 429 (rate limit)");
+        return ApiResponse::TooManyRequests(wait).to_response()
+    }
+    // Checked first, so a refused password doesn't use up the link
+    if let Err(violation) = password::check_policy(&app_config.password, &data.password) {
+        println!("reset_password: 400 ({})", violation);
+        return ApiResponse::BadRequest(violation.to_string()).to_response()
+    }
+    // Does code exist?
+    let email = match codes.consume(&data.uuid, CodePurpose::PasswordReset).await {
+        Ok(Some(code)) => code.email,
+        Ok(None) => {
+            println!("reset_password: 404 (codes.consume)");
+            return ApiResponse::NotFound.to_response()
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("reset_password: 500 (codes.consume)");
+            return ApiResponse::InternalServerError.to_response()
+        }
+    };
+    // The link may have been sent before the account was suspended
+    match users.get_user(&email).await {
+        Ok(user) if user.disabled => {
+            println!("reset_password: 403 (account disabled)");
+            return ApiResponse::AccountDisabled.to_response()
+        },
+        Ok(_) => {},
+        Err(StoreError::NotFound) => {
+            println!("reset_password: 404 (users.get_user)");
+            return ApiResponse::NotFound.to_response()
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("reset_password: 500 (users.get_user)");
+            return ApiResponse::InternalServerError.to_response()
+        }
+    }
+    let hashed = match hash_password(&app_config.password, &data.password).await {
+        Ok(hashed) => hashed,
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("reset_password: 500 (hash_password)");
+            return ApiResponse::InternalServerError.to_response()
+        }
+    };
+    match set_password(&**users, &email, hashed).await {
+        Ok(_) => {
+            audit.record(&req, AuditAction::PasswordReset, &email, None, None).await;
+            println!("reset_password: OK");
+            ApiResponse::Ok.to_response()
+        },
+        Err(e) => {
+            println!("reset_password: failed (set_password)");
+            e.to_response()
+        }
+    }
+}
+
+pub async fn change_password(req: HttpRequest, data: web::Json<ChangePasswordData>, users: web::Data<dyn UserStore>, sessions: web::Data<Arc<SessionStore>>, audit: web::Data<Arc<AuditLog>>, limiter: web::Data<Arc<RateLimiter>>, app_config: web::Data<AppConfig>) -> impl Responder {
+    let session = match utils::verfiy_session_token(&req, &sessions).await {
+        Ok(session) => session,
+        Err(e) => return e.to_response(),
+    };
+    // Guessing the current password counts like guessing it at /login
+    if let Err(wait) = limiter.check(Action::Login, &utils::client_ip(&req), Some(&session.user_id)) {
+        println!("change_password: 429 (rate limit)");
+        return ApiResponse::TooManyRequests(wait).to_response()
+    }
+    if let Some(wait) = limiter.login_blocked(&session.user_id) {
+        println!("change_password: 429 (too many failed attempts)");
+        return ApiResponse::TooManyRequests(wait).to_response()
+    }
+    if let Err(violation) = password::check_policy(&app_config.password, &data.new_password) {
+        println!("change_password: 400 ({})", violation);
+        return ApiResponse::BadRequest(violation.to_string()).to_response()
+    }
+    let user = match users.get_user(&session.user_id).await {
+        Ok(user) => user,
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("change_password: 500 (users.get_user)");
+            return ApiResponse::InternalServerError.to_response()
+        }
+    };
+    // Accounts from OpenID Connect prove their email through /pre-reset before getting a password
+    if user.hashed.is_empty() {
+        println!("change_password: 403 (account has no password)");
+        return ApiResponse::Forbidden.to_response()
+    }
+    match verify_password(&app_config.password, &data.current_password, &user.hashed, &user.salt).await {
+        Ok(Verification::Invalid) => {
+            limiter.login_failed(&user.email);
+            println!("change_password: 403 (wrong password)");
+            return ApiResponse::Forbidden.to_response()
+        },
+        Ok(_) => {},
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("change_password: 500 (verify_password)");
+            return ApiResponse::InternalServerError.to_response()
+        }
+    }
+    limiter.login_succeeded(&user.email);
+
+    let hashed = match hash_password(&app_config.password, &data.new_password).await {
+        Ok(hashed) => hashed,
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("change_password: 500 (hash_password)");
+            return ApiResponse::InternalServerError.to_response()
+        }
+    };
+    if let Err(e) = set_password(&**users, &user.email, hashed).await {
+        println!("change_password: failed (set_password)");
+        return e.to_response()
+    }
+    audit.record(&req, AuditAction::PasswordChanged, &user.email, Some(&user.email), None).await;
+    // Whoever else knew the old password is signed out
+    match sessions.revoke_all_for_user(&user.email, Some(session.family_id)).await {
+        Ok(revoked) => {
+            println!("change_password: OK ({} other sessions revoked)", revoked);
+            ApiResponse::Ok.to_response()
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("change_password: 500 (sessions.revoke_all_for_user)");
+            ApiResponse::InternalServerError.to_response()
+        }
+    }
+}
+
+/// Replaces the password hash of `email`, keeping everything else on the user.
+async fn set_password(users: &dyn UserStore, email: &str, hashed: String) -> Result<User, ApiResponse> {
+    let updated = users.update_user(email, |user| {
+        user.hashed = hashed.clone();
+        user.salt = String::new();
+    }).await;
+    match updated {
+        Ok(user) => Ok(user),
+        Err(StoreError::NotFound) => Err(ApiResponse::NotFound),
+        Err(e) => {
+            println!("Error: {:?}", e);
+            Err(ApiResponse::InternalServerError)
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn change_email(req: HttpRequest, data: web::Json<ChangeEmailData>, users: web::Data<dyn UserStore>, sessions: web::Data<Arc<SessionStore>>, codes: web::Data<Arc<CodeStore>>, email_manager: web::Data<Arc<EmailManager>>, limiter: web::Data<Arc<RateLimiter>>, app_config: web::Data<AppConfig>) -> impl Responder {
+    let session = match utils::verfiy_session_token(&req, &sessions).await {
+        Ok(session) => session,
+        Err(e) => return e.to_response(),
+    };
+    let Some(new_email) = utils::normalize_email(&data.new_email) else {
+        println!("change_email: 400 (invalid email)");
+        return ApiResponse::BadRequest("Invalid email address".to_string()).to_response()
+    };
+    if let Err(wait) = limiter.check(Action::SendEmail, &utils::client_ip(&req), Some(&new_email)) {
+        println!("change_email: 429 (rate limit)");
+        return ApiResponse::TooManyRequests(wait).to_response()
+    }
+    // Guessing the current password counts like guessing it at /login
+    if let Some(wait) = limiter.login_blocked(&session.user_id) {
+        println!("change_email: 429 (too many failed attempts)");
+        return ApiResponse::TooManyRequests(wait).to_response()
+    }
+    let user = match users.get_user(&session.user_id).await {
+        Ok(user) => user,
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("change_email: 500 (users.get_user)");
+            return ApiResponse::InternalServerError.to_response()
+        }
+    };
+    // A stolen session alone must not be enough to take over the account
+    if !user.hashed.is_empty() {
+        match verify_password(&app_config.password, &data.password, &user.hashed, &user.salt).await {
+            Ok(Verification::Invalid) => {
+                limiter.login_failed(&user.email);
+                println!("change_email: 403 (wrong password)");
+                return ApiResponse::Forbidden.to_response()
+            },
+            Ok(_) => {},
+            Err(e) => {
+                println!("Error: {:?}", e);
+                println!("change_email: 500 (verify_password)");
+                return ApiResponse::InternalServerError.to_response()
+            }
+        }
+        limiter.login_succeeded(&user.email);
+    }
+    if users.get_user(&new_email).await.is_ok() {
+        println!("change_email: 409 (address taken)");
+        return ApiResponse::Conflict.to_response()
+    }
+
+    let data = serde_json::json!({ "new_email": new_email });
+    let code = match codes.issue_with_data(CodePurpose::EmailChange, user.email.clone(), None, data).await {
+        Ok(code) => code,
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("change_email: 500 (codes.issue_with_data)");
+            return ApiResponse::InternalServerError.to_response()
+        }
+    };
+    let subject = "Bestätigung Ihrer neuen E-Mail-Adresse";
+    let body = format!("Klicken Sie diesen Link um Ihre neue E-Mail-Adresse zu bestätigen: {}/auth?email-code={}", app_config.url, code);
+    if let Err(e) = email_manager.send_email(&new_email, subject, &body) {
+        println!("Error: {:?}", e);
+        println!("change_email: 500 (send_email)");
+        return ApiResponse::InternalServerError.to_response()
+    }
+    // Tell the old address too, in case this wasn't the account owner
+    let subject = "Änderung Ihrer E-Mail-Adresse";
+    let body = format!("Für Ihr Konto wurde die Änderung der E-Mail-Adresse auf {} angefordert. Falls Sie das nicht waren, ändern Sie bitte umgehend Ihr Password.", new_email);
+    if let Err(e) = email_manager.send_email(&user.email, subject, &body) {
+        println!("Error: {:?}", e);
+        println!("change_email: 500 (send_email notice)");
+        return ApiResponse::InternalServerError.to_response()
+    }
+    println!("change_email: OK");
+    ApiResponse::Ok.to_response()
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn confirm_email_change(data: web::Json<ConfirmEmailData>, users: web::Data<dyn UserStore>, documents: web::Data<dyn DocumentStore>, sessions: web::Data<Arc<SessionStore>>, api_keys: web::Data<Arc<ApiKeyStore>>, codes: web::Data<Arc<CodeStore>>, audit: web::Data<Arc<AuditLog>>, limiter: web::Data<Arc<RateLimiter>>, req: HttpRequest) -> impl Responder {
+    if let Err(wait) = limiter.check(Action::RedeemCode, &utils::client_ip(&req), None) {
+        println!("confirm_email_change: 429 (rate limit)");
+        return ApiResponse::TooManyRequests(wait).to_response()
+    }
+    let change = match codes.consume(&data.code, CodePurpose::EmailChange).await {
+        Ok(Some(code)) => code.data.get("new_email")
+            .and_then(|new_email| new_email.as_str())
+            .map(|new_email| (code.email.clone(), new_email.to_string())),
+        Ok(None) => None,
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("confirm_email_change: 500 (codes.consume)");
+            return ApiResponse::InternalServerError.to_response()
+        }
+    };
+    let Some((old_email, new_email)) = change else {
+        println!("confirm_email_change: 404 (codes.consume)");
+        return ApiResponse::NotFound.to_response()
+    };
+
+    match move_account(&**users, &**documents, &sessions, &api_keys, &old_email, &new_email).await {
+        Ok(_) => {
+            audit.record(&req, AuditAction::EmailChanged, &new_email, None, Some(&old_email)).await;
+            println!("confirm_email_change: OK");
+            ApiResponse::Ok.to_response()
+        },
+        Err(e) => {
+            println!("confirm_email_change: failed (move_account)");
+            e.to_response()
+        }
+    }
+}
+
+/// Re-keys the account `from` as `to`: a copy of the user document under the
+/// new id, its projects, sessions and API keys. If the projects can't all be
+/// handed over, everything done so far is undone.
+async fn move_account(users: &dyn UserStore, documents: &dyn DocumentStore, sessions: &SessionStore, api_keys: &ApiKeyStore, from: &str, to: &str) -> Result<User, ApiResponse> {
+    let mut user = match users.get_user(from).await {
+        Ok(user) => user,
+        Err(StoreError::NotFound) => return Err(ApiResponse::NotFound),
+        Err(e) => {
+            println!("Error: {:?}", e);
+            return Err(ApiResponse::InternalServerError);
+        }
+    };
+    user.email = to.to_string();
+    let user = match users.create_user(user).await {
+        Ok(user) => user,
+        // Someone registered the address since the link was sent
+        Err(StoreError::Conflict) => return Err(ApiResponse::Conflict),
+        Err(e) => {
+            println!("Error: {:?}", e);
+            return Err(ApiResponse::InternalServerError);
+        }
+    };
+
+    let mut transferred = Vec::new();
+    for id in &user.uuids {
+        match documents.transfer_document(id, from, to).await {
+            Ok(true) => transferred.push(id),
+            // Projects without a recorded owner only need to stay in uuids
+            Ok(false) => {},
+            Err(StoreError::NotFound) => {},
+            Err(e) => {
+                println!("Error: {:?}", e);
+                for id in transferred {
+                    if let Err(e) = documents.transfer_document(id, to, from).await {
+                        println!("move_account: rollback of {} failed: {:?}", id, e);
+                    }
+                }
+                if let Err(e) = users.delete_user(to).await {
+                    println!("move_account: rollback of {} failed: {:?}", to, e);
+                }
+                return Err(ApiResponse::InternalServerError);
+            }
+        }
+    }
+
+    if let Err(e) = sessions.reassign_user(from, to).await {
+        println!("move_account: sessions not moved: {:?}", e);
+    }
+    if let Err(e) = api_keys.reassign_user(from, to).await {
+        println!("move_account: API keys not moved: {:?}", e);
+    }
+    if let Err(e) = users.delete_user(from).await {
+        println!("move_account: {} not deleted: {:?}", from, e);
+    }
+    Ok(user)
+}
+
+pub async fn get_document(id: web::Path<String>, sessions: web::Data<Arc<SessionStore>>, api_keys: web::Data<Arc<ApiKeyStore>>, users: web::Data<dyn UserStore>, documents: web::Data<dyn DocumentStore>, req: HttpRequest) -> impl Responder {
+    // Verify Session Token
+    let caller = match utils::authenticate(&req, &sessions, &api_keys, Access::Read).await {
+        Ok(caller) => caller,
+        Err(e) => return e.to_response(),
+    };
+
+    match readable_document(&**users, &**documents, &caller, &id).await {
+        Ok(Some(doc)) => {
+            println!("get_document: OK");
+            document_response(HttpResponse::Ok(), doc)
+        },
+        Ok(None) => {
+            println!("get_document: 404 (readable_document)");
+            ApiResponse::NotFound.to_response()
+        },
+        Err(e) => {
+            println!("get_document: denied (readable_document)");
+            e.to_response()
+        }
+    }
+}
+
+pub async fn get_config(config: web::Data<dyn ConfigStore>,  _req: HttpRequest) -> impl Responder {
+    // Verify Session Token
+    // let _ = match utils::verfiy_session_token(&req, &sessions) {
+    //     Ok(token) => token,
+    //     Err(e) => return e.to_response(),
+    // };
+
+    match config.get_config_data().await {
+        Ok(doc) => {
+            println!("get_config: OK");
+            HttpResponse::Ok().json(doc)
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("get_config: 404 (config.get_config_data)");
+            ApiResponse::NotFound.to_response()
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn put_document(id: web::Path<String>, sessions: web::Data<Arc<SessionStore>>, api_keys: web::Data<Arc<ApiKeyStore>>, users: web::Data<dyn UserStore>, documents: web::Data<dyn DocumentStore>, audit: web::Data<Arc<AuditLog>>, data: web::Json<Value>, req: HttpRequest) -> impl Responder {
+    // Verify Session Token
+    let caller = match utils::authenticate(&req, &sessions, &api_keys, Access::Write).await {
+        Ok(caller) => caller,
+        Err(e) => return e.to_response(),
+    };
+
+    let if_match = if_match(&req);
+    match owned_document(&**users, &**documents, &caller, &id).await {
+        Ok(Some(_)) => {},
+        Ok(None) if if_match.is_some() => {
+            println!("put_document: 404 (If-Match on a missing project)");
+            return ApiResponse::NotFound.to_response();
+        },
+        Ok(None) => {
+            let response = create_document(&**users, &**documents, &caller, &id, data.into_inner()).await;
+            if response.status().is_success() {
+                audit.record(&req, AuditAction::DocumentWritten, &caller, Some(&caller), Some(&id)).await;
+            }
+            return response;
+        },
+        Err(e) => {
+            println!("put_document: denied (owned_document)");
+            return e.to_response();
+        }
+    }
+
+    // Put document
+    match documents.put_document(&id, data.into_inner(), if_match.as_deref()).await {
+        Ok(doc) => {
+            record_revision(&**documents, &doc, &caller, "put_document").await;
+            audit.record(&req, AuditAction::DocumentWritten, &caller, Some(&caller), Some(&id)).await;
+            println!("put_document: OK");
+            document_response(HttpResponse::Ok(), doc)
+        },
+        // The client edited an outdated copy, send the current one so it can merge
+        Err(StoreError::Conflict) => match documents.get_document(&id).await {
+            Ok(current) => {
+                println!("put_document: 409 (stale revision)");
+                document_response(HttpResponse::Conflict(), current)
+            },
+            Err(e) => {
+                println!("Error: {:?}", e);
+                println!("put_document: 500 documents.get_document");
+                ApiResponse::InternalServerError.to_response()
+            }
+        },
+        Err(StoreError::NotFound) => {
+            println!("put_document: 404 (deleted in the meantime)");
+            ApiResponse::NotFound.to_response()
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("put_document: 500 documents.put_document");
+            ApiResponse::InternalServerError.to_response()
+        }
+    }
+}
+
+/// Changes part of a project, as a merge patch (`application/merge-patch+json`)
+/// or JSON Patch (`application/json-patch+json`) depending on `Content-Type`.
+#[allow(clippy::too_many_arguments)]
+pub async fn patch_document(id: web::Path<String>, sessions: web::Data<Arc<SessionStore>>, api_keys: web::Data<Arc<ApiKeyStore>>, users: web::Data<dyn UserStore>, documents: web::Data<dyn DocumentStore>, audit: web::Data<Arc<AuditLog>>, body: web::Bytes, req: HttpRequest) -> impl Responder {
+    let caller = match utils::authenticate(&req, &sessions, &api_keys, Access::Write).await {
+        Ok(caller) => caller,
+        Err(e) => return e.to_response(),
+    };
+
+    let content_type = req.headers().get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|essence| essence.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+    let patch = match content_type.as_str() {
+        "application/merge-patch+json" => serde_json::from_slice(&body).map(DocumentPatch::Merge),
+        "application/json-patch+json" => serde_json::from_slice(&body).map(DocumentPatch::Json),
+        _ => {
+            println!("patch_document: 415 ({})", content_type);
+            return ApiResponse::UnsupportedMediaType.to_response();
+        }
+    };
+    let patch = match patch {
+        Ok(patch) => patch,
+        Err(e) => {
+            println!("patch_document: 400 ({})", e);
+            return ApiResponse::BadRequest(format!("Invalid patch: {}", e)).to_response();
+        }
+    };
+
+    match owned_document(&**users, &**documents, &caller, &id).await {
+        Ok(Some(_)) => {},
+        Ok(None) => {
+            println!("patch_document: 404 (owned_document)");
+            return ApiResponse::NotFound.to_response();
+        },
+        Err(e) => {
+            println!("patch_document: denied (owned_document)");
+            return e.to_response();
+        }
+    }
+
+    match documents.patch_document(&id, &patch, if_match(&req).as_deref()).await {
+        Ok(doc) => {
+            record_revision(&**documents, &doc, &caller, "patch_document").await;
+            audit.record(&req, AuditAction::DocumentWritten, &caller, Some(&caller), Some(&id)).await;
+            println!("patch_document: OK");
+            document_response(HttpResponse::Ok(), doc)
+        },
+        // A stale If-Match or a failed `test` operation: the patch was meant for other data
+        Err(StoreError::Conflict | StoreError::Patch(json_patch::PatchError { kind: json_patch::PatchErrorKind::TestFailed, .. })) => match documents.get_document(&id).await {
+            Ok(current) => {
+                println!("patch_document: 409 (stale revision or failed test)");
+                document_response(HttpResponse::Conflict(), current)
+            },
+            Err(e) => {
+                println!("Error: {:?}", e);
+                println!("patch_document: 500 documents.get_document");
+                ApiResponse::InternalServerError.to_response()
+            }
+        },
+        Err(StoreError::Patch(e)) => {
+            println!("patch_document: 400 ({})", e);
+            ApiResponse::BadRequest(format!("Patch failed: {}", e)).to_response()
+        },
+        Err(StoreError::NotFound) => {
+            println!("patch_document: 404 (deleted in the meantime)");
+            ApiResponse::NotFound.to_response()
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("patch_document: 500 documents.patch_document");
+            ApiResponse::InternalServerError.to_response()
+        }
+    }
+}
+
+/// Creates project `id` for `email` and adds it to their uuids. If the uuid
+/// list can't be updated the project is removed again, so it never exists
+/// without an owner.
+async fn create_document(users: &dyn UserStore, documents: &dyn DocumentStore, email: &str, id: &str, data: Value) -> HttpResponse {
+    let doc = match documents.create_document(id, email, data).await {
+        Ok(doc) => doc,
+        Err(StoreError::Conflict) => {
+            println!("put_document: 403 (created by someone else in the meantime)");
+            return ApiResponse::Forbidden.to_response();
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("put_document: 500 documents.create_document");
+            return ApiResponse::InternalServerError.to_response();
+        }
+    };
+    let registered = users.update_user(email, |user| {
+        if !user.uuids.iter().any(|uuid| uuid == id) {
+            user.uuids.push(id.to_string());
+        }
+    }).await;
+    if let Err(e) = registered {
+        println!("Error: {:?}", e);
+        if let Err(e) = documents.delete_document(id).await {
+            println!("put_document: rollback failed: {:?}", e);
+        }
+        println!("put_document: 500 users.update_user");
+        return ApiResponse::InternalServerError.to_response();
+    }
+    record_revision(documents, &doc, email, "put_document").await;
+    println!("put_document: OK (created)");
+    document_response(HttpResponse::Ok(), doc)
+}
+
+/// Adds a freshly written `doc` to the project's history. The write itself
+/// already succeeded, so a failure here is only logged.
+async fn record_revision(documents: &dyn DocumentStore, doc: &Document, author: &str, handler: &str) {
+    if let Err(e) = documents.record_revision(doc, author).await {
+        println!("Error: {:?}", e);
+        println!("{}: revision not recorded (documents.record_revision)", handler);
+    }
+}
+
+/// Answers with the project data, and its revision as `ETag` for a later `If-Match`.
+fn document_response(mut response: HttpResponseBuilder, doc: Document) -> HttpResponse {
+    if let Some(rev) = &doc.rev {
+        response.insert_header((header::ETAG, format!("\"{}\"", rev)));
+    }
+    response.json(doc.data)
+}
+
+/// The revision the client expects to overwrite, from `If-Match`. `*` matches
+/// any revision, just like leaving the header out.
+fn if_match(req: &HttpRequest) -> Option<String> {
+    let value = req.headers().get(header::IF_MATCH)?.to_str().ok()?.trim();
+    if value == "*" {
+        return None;
+    }
+    Some(value.trim_start_matches("W/").trim_matches('"').to_string())
+}
+
+/// Moves a project to the trash, or with `?permanent=true` deletes it for
+/// good. Either way it leaves the owner's uuids and `last_uuid`.
+#[allow(clippy::too_many_arguments)]
+pub async fn delete_document(id: web::Path<String>, query: web::Query<DeleteDocumentQuery>, sessions: web::Data<Arc<SessionStore>>, api_keys: web::Data<Arc<ApiKeyStore>>, users: web::Data<dyn UserStore>, documents: web::Data<dyn DocumentStore>, audit: web::Data<Arc<AuditLog>>, app_config: web::Data<AppConfig>, req: HttpRequest) -> impl Responder {
+    let caller = match utils::authenticate(&req, &sessions, &api_keys, Access::Write).await {
+        Ok(caller) => caller,
+        Err(e) => return e.to_response(),
+    };
+
+    match owned_document(&**users, &**documents, &caller, &id).await {
+        Ok(Some(_)) => {},
+        Ok(None) => {
+            println!("delete_document: 404 (owned_document)");
+            return ApiResponse::NotFound.to_response();
+        },
+        Err(e) => {
+            println!("delete_document: denied (owned_document)");
+            return e.to_response();
+        }
+    }
+
+    let mut was_last = false;
+    let forgotten = users.update_user(&caller, |user| {
+        user.uuids.retain(|uuid| uuid != id.as_str());
+        was_last = user.last_uuid == *id;
+        if was_last {
+            user.last_uuid.clear();
+        }
+    }).await;
+    if let Err(e) = forgotten {
+        println!("Error: {:?}", e);
+        println!("delete_document: 500 users.update_user");
+        return ApiResponse::InternalServerError.to_response();
+    }
+
+    let deleted = if query.permanent {
+        documents.delete_document(&id).await
+    } else {
+        documents.set_deleted(&id, Some(Deletion { at: Utc::now(), by: caller.clone() })).await.map(|_| ())
+    };
+    if let Err(e) = deleted {
+        println!("Error: {:?}", e);
+        // Put it back in the list, so the project isn't left without a way to reach it
+        let relisted = users.update_user(&caller, |user| {
+            if !user.uuids.iter().any(|uuid| uuid == id.as_str()) {
+                user.uuids.push(id.to_string());
+            }
+            if was_last && user.last_uuid.is_empty() {
+                user.last_uuid = id.to_string();
+            }
+        }).await;
+        if let Err(e) = relisted {
+            println!("delete_document: rollback failed: {:?}", e);
+        }
+        println!("delete_document: failed (documents.delete_document)");
+        return match e {
+            StoreError::NotFound => ApiResponse::NotFound.to_response(),
+            _ => ApiResponse::InternalServerError.to_response(),
+        };
+    }
+
+    let target = if query.permanent { format!("{} (permanent)", id) } else { id.to_string() };
+    audit.record(&req, AuditAction::DocumentDeleted, &caller, Some(&caller), Some(&target)).await;
+    println!("delete_document: OK (permanent: {})", query.permanent);
+    HttpResponse::Ok().json(DeletedDocument {
+        restorable_until: (!query.permanent).then(|| Utc::now() + app_config.projects.retention),
+    })
+}
+
+/// Takes a project back out of the trash and lists it for its owner again.
+#[allow(clippy::too_many_arguments)]
+pub async fn undelete_document(id: web::Path<String>, sessions: web::Data<Arc<SessionStore>>, api_keys: web::Data<Arc<ApiKeyStore>>, users: web::Data<dyn UserStore>, documents: web::Data<dyn DocumentStore>, audit: web::Data<Arc<AuditLog>>, app_config: web::Data<AppConfig>, req: HttpRequest) -> impl Responder {
+    let caller = match utils::authenticate(&req, &sessions, &api_keys, Access::Write).await {
+        Ok(caller) => caller,
+        Err(e) => return e.to_response(),
+    };
+
+    let doc = match documents.get_document(&id).await {
+        Ok(doc) => doc,
+        Err(StoreError::NotFound) => {
+            println!("undelete_document: 404");
+            return ApiResponse::NotFound.to_response();
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("undelete_document: 500 documents.get_document");
+            return ApiResponse::InternalServerError.to_response();
+        }
+    };
+    // Past the retention it is about to be purged, and may already be on other instances
+    let Some(deleted) = doc.deleted.clone().filter(|deleted| deleted.at + app_config.projects.retention > Utc::now()) else {
+        println!("undelete_document: 404 (not in the trash)");
+        return ApiResponse::NotFound.to_response();
+    };
+    if doc.owner.as_deref().unwrap_or(&deleted.by) != caller {
+        println!("undelete_document: 403 (project belongs to someone else)");
+        return ApiResponse::Forbidden.to_response();
+    }
+
+    let doc = match documents.set_deleted(&id, None).await {
+        Ok(doc) => doc,
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("undelete_document: 500 documents.set_deleted");
+            return ApiResponse::InternalServerError.to_response();
+        }
+    };
+    let relisted = users.update_user(&caller, |user| {
+        if !user.uuids.iter().any(|uuid| uuid == id.as_str()) {
+            user.uuids.push(id.to_string());
+        }
+    }).await;
+    if let Err(e) = relisted {
+        println!("Error: {:?}", e);
+        if let Err(e) = documents.set_deleted(&id, Some(deleted)).await {
+            println!("undelete_document: rollback failed: {:?}", e);
+        }
+        println!("undelete_document: 500 users.update_user");
+        return ApiResponse::InternalServerError.to_response();
+    }
+
+    audit.record(&req, AuditAction::DocumentUndeleted, &caller, Some(&caller), Some(&id)).await;
+    println!("undelete_document: OK");
+    document_response(HttpResponse::Ok(), doc)
+}
+
+/// Lists the recorded revisions of a project, newest first, without their data.
+pub async fn list_history(id: web::Path<String>, query: web::Query<HistoryQuery>, sessions: web::Data<Arc<SessionStore>>, api_keys: web::Data<Arc<ApiKeyStore>>, users: web::Data<dyn UserStore>, documents: web::Data<dyn DocumentStore>, req: HttpRequest) -> impl Responder {
+    let caller = match utils::authenticate(&req, &sessions, &api_keys, Access::Read).await {
+        Ok(caller) => caller,
+        Err(e) => return e.to_response(),
+    };
+
+    match readable_document(&**users, &**documents, &caller, &id).await {
+        Ok(Some(_)) => {},
+        Ok(None) => {
+            println!("list_history: 404 (readable_document)");
+            return ApiResponse::NotFound.to_response();
+        },
+        Err(e) => {
+            println!("list_history: denied (readable_document)");
+            return e.to_response();
+        }
+    }
+
+    let skip = query.skip.unwrap_or(0);
+    let limit = query.limit.unwrap_or(DEFAULT_HISTORY_PAGE).min(MAX_HISTORY_PAGE);
+    match documents.list_revisions(&id, skip, limit).await {
+        Ok(revisions) => {
+            println!("list_history: OK");
+            HttpResponse::Ok().json(revisions)
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("list_history: 500 documents.list_revisions");
+            ApiResponse::InternalServerError.to_response()
+        }
+    }
+}
+
+/// One recorded revision of a project, with the data as it was then.
+pub async fn get_history_revision(path: web::Path<(String, String)>, sessions: web::Data<Arc<SessionStore>>, api_keys: web::Data<Arc<ApiKeyStore>>, users: web::Data<dyn UserStore>, documents: web::Data<dyn DocumentStore>, req: HttpRequest) -> impl Responder {
+    let caller = match utils::authenticate(&req, &sessions, &api_keys, Access::Read).await {
+        Ok(caller) => caller,
+        Err(e) => return e.to_response(),
+    };
+    let (id, rev) = path.into_inner();
+
+    match readable_document(&**users, &**documents, &caller, &id).await {
+        Ok(Some(_)) => {},
+        Ok(None) => {
+            println!("get_history_revision: 404 (readable_document)");
+            return ApiResponse::NotFound.to_response();
+        },
+        Err(e) => {
+            println!("get_history_revision: denied (readable_document)");
+            return e.to_response();
+        }
+    }
+
+    match documents.get_revision(&id, &rev).await {
+        Ok(revision) => {
+            println!("get_history_revision: OK");
+            HttpResponse::Ok().json(RevisionData { info: revision.info, data: revision.data })
+        },
+        Err(StoreError::NotFound) => {
+            println!("get_history_revision: 404 (unknown revision)");
+            ApiResponse::NotFound.to_response()
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("get_history_revision: 500 documents.get_revision");
+            ApiResponse::InternalServerError.to_response()
+        }
+    }
+}
+
+/// Writes the data of an earlier revision back as the project's current
+/// data. The restore is itself recorded as a new revision, so it can be undone
+/// the same way.
+pub async fn restore_revision(path: web::Path<(String, String)>, sessions: web::Data<Arc<SessionStore>>, api_keys: web::Data<Arc<ApiKeyStore>>, users: web::Data<dyn UserStore>, documents: web::Data<dyn DocumentStore>, audit: web::Data<Arc<AuditLog>>, req: HttpRequest) -> impl Responder {
+    let caller = match utils::authenticate(&req, &sessions, &api_keys, Access::Write).await {
+        Ok(caller) => caller,
+        Err(e) => return e.to_response(),
+    };
+    let (id, rev) = path.into_inner();
+
+    match owned_document(&**users, &**documents, &caller, &id).await {
+        Ok(Some(_)) => {},
+        Ok(None) => {
+            println!("restore_revision: 404 (owned_document)");
+            return ApiResponse::NotFound.to_response();
+        },
+        Err(e) => {
+            println!("restore_revision: denied (owned_document)");
+            return e.to_response();
+        }
+    }
+
+    let revision = match documents.get_revision(&id, &rev).await {
+        Ok(revision) => revision,
+        Err(StoreError::NotFound) => {
+            println!("restore_revision: 404 (unknown revision)");
+            return ApiResponse::NotFound.to_response();
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("restore_revision: 500 documents.get_revision");
+            return ApiResponse::InternalServerError.to_response();
+        }
+    };
+
+    match documents.patch_document(&id, &DocumentPatch::Replace(revision.data), if_match(&req).as_deref()).await {
+        Ok(doc) => {
+            record_revision(&**documents, &doc, &caller, "restore_revision").await;
+            audit.record(&req, AuditAction::DocumentRestored, &caller, Some(&caller), Some(&format!("{}@{}", id, rev))).await;
+            println!("restore_revision: OK");
+            document_response(HttpResponse::Ok(), doc)
+        },
+        Err(StoreError::Conflict) => match documents.get_document(&id).await {
+            Ok(current) => {
+                println!("restore_revision: 409 (stale revision)");
+                document_response(HttpResponse::Conflict(), current)
+            },
+            Err(e) => {
+                println!("Error: {:?}", e);
+                println!("restore_revision: 500 documents.get_document");
+                ApiResponse::InternalServerError.to_response()
+            }
+        },
+        Err(StoreError::NotFound) => {
+            println!("restore_revision: 404 (deleted in the meantime)");
+            ApiResponse::NotFound.to_response()
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("restore_revision: 500 documents.patch_document");
+            ApiResponse::InternalServerError.to_response()
+        }
+    }
+}
+
+/// Streams a project's writes as Server-Sent Events: first the current data,
+/// then an `update` event with the data after every write, its revision as
+/// the event `id`. When the project is trashed or deleted a `deleted` event
+/// ends the stream. Access is checked again on every write, so a project
+/// handed to someone else or a suspended account ends it without an event.
+/// The credential is checked again on every write and keepalive too, and the
+/// stream ends when the access token expires; clients reconnect with a fresh one.
+pub async fn project_events(id: web::Path<String>, sessions: web::Data<Arc<SessionStore>>, api_keys: web::Data<Arc<ApiKeyStore>>, users: web::Data<dyn UserStore>, documents: web::Data<dyn DocumentStore>, req: HttpRequest) -> impl Responder {
+    let caller = match utils::authenticate(&req, &sessions, &api_keys, Access::Read).await {
+        Ok(caller) => caller,
+        Err(e) => return e.to_response(),
+    };
+    let Some(token) = utils::presented_token(&req) else { return ApiResponse::Unauthorized.to_response() };
+    let expires_at = match utils::recheck(&token, &caller, &sessions, &api_keys).await {
+        Ok(expires_at) => expires_at,
+        Err(e) => return e.to_response(),
+    };
+    let id = id.into_inner();
+
+    // Subscribed before reading, so no write can slip in between
+    let mut changes = documents.subscribe();
+    let doc = match readable_document(&**users, &**documents, &caller, &id).await {
+        Ok(Some(doc)) => doc,
+        Ok(None) => {
+            println!("project_events: 404 (readable_document)");
+            return ApiResponse::NotFound.to_response();
+        },
+        Err(e) => {
+            println!("project_events: denied (readable_document)");
+            return e.to_response();
+        }
+    };
+
+    let users = users.into_inner();
+    let documents = documents.into_inner();
+    let sessions = sessions.into_inner();
+    let api_keys = api_keys.into_inner();
+    let events = async_stream::stream! {
+        let mut last_rev = doc.rev.clone();
+        yield Ok::<_, Infallible>(update_event(doc));
+        let mut keepalive = tokio::time::interval(EVENTS_KEEPALIVE);
+        keepalive.tick().await;
+        // API keys don't expire, their stream ends when the key is revoked
+        let lifetime = expires_at.map_or(Duration::MAX, |at| (at - Utc::now()).to_std().unwrap_or_default());
+        let expiry = tokio::time::sleep(lifetime);
+        tokio::pin!(expiry);
+        loop {
+            let change = tokio::select! {
+                change = changes.recv() => Some(change),
+                _ = keepalive.tick() => None,
+                _ = &mut expiry => {
+                    println!("project_events: ended (access token expired)");
+                    break;
+                },
+            };
+            if let Some(Ok(change)) = &change {
+                if change.id != id || Some(&change.rev) == last_rev.as_ref() {
+                    continue;
+                }
+            }
+            // A logout, revocation or deleted key ends the stream, a failed lookup doesn't
+            match utils::recheck(&token, &caller, &sessions, &api_keys).await {
+                Ok(_) | Err(ApiResponse::InternalServerError) => {},
+                Err(_) => {
+                    println!("project_events: ended (credential no longer valid)");
+                    break;
+                }
+            }
+            let Some(change) = change else {
+                yield Ok(web::Bytes::from_static(b": keepalive\n\n"));
+                continue;
+            };
+            match change {
+                Ok(change) if change.deleted => {
+                    yield Ok(web::Bytes::from_static(DELETED_EVENT));
+                    break;
+                },
+                Ok(_) => {},
+                // Some changes were dropped, one of them may have been ours
+                Err(RecvError::Lagged(_)) => {},
+                Err(RecvError::Closed) => break,
+            }
+            match users.get_user(&caller).await {
+                Ok(user) if !user.disabled => {},
+                _ => {
+                    println!("project_events: ended (account gone or suspended)");
+                    break;
+                }
+            }
+            match readable_document(&*users, &*documents, &caller, &id).await {
+                Ok(Some(doc)) => {
+                    if doc.rev == last_rev {
+                        continue;
+                    }
+                    last_rev = doc.rev.clone();
+                    yield Ok(update_event(doc));
+                },
+                Ok(None) | Err(ApiResponse::NotFound) => {
+                    yield Ok(web::Bytes::from_static(DELETED_EVENT));
+                    break;
+                },
+                Err(ApiResponse::InternalServerError) => continue,
+                Err(_) => {
+                    println!("project_events: ended (no longer readable)");
+                    break;
+                }
+            }
+        }
+    };
+
+    println!("project_events: OK");
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        // Stops nginx from holding events back in its buffer
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(events)
+}
+
+/// An SSE `update` event with the project data, its revision as the event id.
+fn update_event(doc: Document) -> web::Bytes {
+    let rev = doc.rev.unwrap_or_default();
+    web::Bytes::from(format!("event: update\nid: {}\ndata: {}\n\n", rev, doc.data))
+}
+
+/// Loads project `id` if `email` owns it: the id must be in their uuids and,
+/// where the project records an owner, that owner must be them.
+/// `Ok(None)` means the project doesn't exist yet.
+async fn owned_document(users: &dyn UserStore, documents: &dyn DocumentStore, email: &str, id: &str) -> Result<Option<Document>, ApiResponse> {
+    let doc = match documents.get_document(id).await {
+        Ok(doc) => doc,
+        Err(StoreError::NotFound) => return Ok(None),
+        Err(e) => {
+            println!("Error: {:?}", e);
+            return Err(ApiResponse::InternalServerError);
+        }
+    };
+    // A trashed project can't be read or written, and its id can't be reused until it is purged
+    if doc.deleted.is_some() {
+        return Err(ApiResponse::NotFound);
+    }
+    let user = users.get_user(email).await.map_err(|_| ApiResponse::Unauthorized)?;
+    let listed = user.uuids.iter().any(|uuid| uuid == id);
+    let owner_matches = doc.owner.as_ref().is_none_or(|owner| owner == email);
+    if listed && owner_matches {
+        Ok(Some(doc))
+    } else {
+        Err(ApiResponse::Forbidden)
+    }
+}
+
+/// Like `owned_document`, but consultants and admins may also read projects
+/// they don't own.
+async fn readable_document(users: &dyn UserStore, documents: &dyn DocumentStore, email: &str, id: &str) -> Result<Option<Document>, ApiResponse> {
+    match owned_document(users, documents, email, id).await {
+        Err(ApiResponse::Forbidden) => {},
+        owned => return owned,
+    }
+    let user = users.get_user(email).await.map_err(|_| ApiResponse::Unauthorized)?;
+    if !user.can_read_any_project() {
+        return Err(ApiResponse::Forbidden);
+    }
+    match documents.get_document(id).await {
+        Ok(doc) if doc.deleted.is_some() => Ok(None),
+        Ok(doc) => Ok(Some(doc)),
+        Err(StoreError::NotFound) => Ok(None),
+        Err(e) => {
+            println!("Error: {:?}", e);
+            Err(ApiResponse::InternalServerError)
+        }
+    }
+}
+
+pub async fn get_uuids(id: web::Path<String>, sessions: web::Data<Arc<SessionStore>>, api_keys: web::Data<Arc<ApiKeyStore>>, users: web::Data<dyn UserStore>,  req: HttpRequest) -> impl Responder {
+    // Verify Session Token
+    let caller = match utils::authenticate(&req, &sessions, &api_keys, Access::Read).await {
+        Ok(caller) => caller,
+        Err(e) => return e.to_response(),
+    };
+    let email = match utils::authorize_account(&caller, &**users, &id).await {
+        Ok(email) => email,
+        Err(e) => {
+            println!("get_uuids: denied (authorize_account)");
+            return e.to_response();
+        }
+    };
+
+    match users.get_user(&email).await {
+        Ok(user) => {
+            println!("get_uuids: OK");
+            HttpResponse::Ok().json(user.uuids)
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("get_uuids: 404 users.get_user");
+            ApiResponse::NotFound.to_response()
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn post_uuid(sessions: web::Data<Arc<SessionStore>>, api_keys: web::Data<Arc<ApiKeyStore>>, audit: web::Data<Arc<AuditLog>>, req: HttpRequest, users: web::Data<dyn UserStore>, documents: web::Data<dyn DocumentStore>, id: web::Path<String>, data: web::Json<AddUuid>) -> impl Responder {
+    // Verify Session Token
+    let caller = match utils::authenticate(&req, &sessions, &api_keys, Access::Write).await {
+        Ok(caller) => caller,
+        Err(e) => return e.to_response(),
+    };
+    let email = match utils::authorize_account(&caller, &**users, &id).await {
+        Ok(email) => email,
+        Err(e) => {
+            println!("post_uuid: denied (authorize_account)");
+            return e.to_response();
+        }
+    };
+
+    let user = match users.get_user(&email).await {
+        Ok(user) => user,
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("post_uuid: 404 users.get_user");
+            return ApiResponse::NotFound.to_response()
+        }
+    };
+    // Listing someone else's existing project would grant access to it
+    match documents.get_document(&data.uuid).await {
+        Ok(doc) => {
+            let owned = doc.owner.as_deref() == Some(email.as_str()) || user.uuids.contains(&data.uuid);
+            if !owned {
+                println!("post_uuid: 403 (project belongs to someone else)");
+                return ApiResponse::Forbidden.to_response();
+            }
+        },
+        // Not created yet, the first to list it claims it
+        Err(StoreError::NotFound) => {},
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("post_uuid: 500 (documents.get_document)");
+            return ApiResponse::InternalServerError.to_response()
+        }
+    }
+    let updated = users.update_user(&email, |user| {
+        if !user.uuids.contains(&data.uuid) {
+            user.uuids.push(data.uuid.clone());
+        }
+    }).await;
+    match updated {
+        Ok(_) => {
+            audit.record(&req, AuditAction::UuidAdded, &email, Some(&caller), Some(&data.uuid)).await;
+            println!("post_uuid: OK");
+            HttpResponse::Ok().json("UUIDs updated successfully")
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("post_uuid: 500 users.update_user");
+            ApiResponse::InternalServerError.to_response()
+        }
+    }
+}
+
+pub async fn delete_uuid(path: web::Path<(String, String)>, sessions: web::Data<Arc<SessionStore>>, api_keys: web::Data<Arc<ApiKeyStore>>, audit: web::Data<Arc<AuditLog>>, req: HttpRequest, users: web::Data<dyn UserStore>) -> impl Responder {
+    // Verify Session Token
+    let caller = match utils::authenticate(&req, &sessions, &api_keys, Access::Write).await {
+        Ok(caller) => caller,
+        Err(e) => return e.to_response(),
+    };
+
+    let (id, uuid) = path.into_inner();
+    let email = match utils::authorize_account(&caller, &**users, &id).await {
+        Ok(email) => email,
+        Err(e) => {
+            println!("delete_uuid: denied (authorize_account)");
+            return e.to_response();
+        }
+    };
+    let updated = users.update_user(&email, |user| user.uuids.retain(|x| !x.eq(&uuid.as_str()))).await;
+    match updated {
+        Ok(_) => {
+            audit.record(&req, AuditAction::UuidRemoved, &email, Some(&caller), Some(&uuid)).await;
+            println!("delete_uuid: OK");
+            HttpResponse::Ok().json("UUIDs updated successfully")
+        },
+        Err(StoreError::NotFound) => {
+            println!("delete_uuid: 404 users.update_user");
+            HttpResponse::NotFound().body(format!("User with email {} not found", email))
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("delete_uuid: 500 users.update_user");
+            ApiResponse::InternalServerError.to_response()
+        }
+    }
+}
+
+pub async fn delete_user(req: HttpRequest, id: web::Path<String> , sessions: web::Data<Arc<SessionStore>>, api_keys: web::Data<Arc<ApiKeyStore>>, audit: web::Data<Arc<AuditLog>>, users: web::Data<dyn UserStore>) -> impl Responder {
+    // Verify Session Token
+    let session = match utils::verfiy_session_token(&req, &sessions).await {
+        Ok(session) => session,
+        Err(e) => return e.to_response(),
+    };
+    let email = match utils::authorize_account(&session.user_id, &**users, &id).await {
+        Ok(email) => email,
+        Err(e) => {
+            println!("delete_user: denied (authorize_account)");
+            return e.to_response();
+        }
+    };
+
+    if let Err(e) = remove_account(&**users, &sessions, &api_keys, &email).await {
+        println!("delete_user: failed (remove_account)");
+        return e.to_response();
+    }
+
+    audit.record(&req, AuditAction::UserDeleted, &email, Some(&session.user_id), None).await;
+    println!("delete_user: OK");
+    HttpResponse::Ok().body("User deleted successfully")
+}
+
+/// Deletes the account `email`, signs it out everywhere and deletes its API keys.
+pub async fn remove_account(users: &dyn UserStore, sessions: &SessionStore, api_keys: &ApiKeyStore, email: &str) -> Result<(), ApiResponse> {
+    match users.delete_user(email).await {
+        Ok(_) => {},
+        Err(StoreError::NotFound) => return Err(ApiResponse::NotFound),
+        Err(e) => {
+            println!("Error: {:?}", e);
+            return Err(ApiResponse::InternalServerError);
+        }
+    }
+    if let Err(e) = sessions.revoke_all_for_user(email, None).await {
+        println!("remove_account: sessions not revoked: {:?}", e);
+    }
+    if let Err(e) = api_keys.revoke_all_for_user(email).await {
+        println!("remove_account: API keys not revoked: {:?}", e);
+    }
+    Ok(())
+}
+
+pub async fn get_last_uuid(req: HttpRequest, sessions: web::Data<Arc<SessionStore>>, api_keys: web::Data<Arc<ApiKeyStore>>, users: web::Data<dyn UserStore>) -> impl Responder {
+    let caller = match utils::authenticate(&req, &sessions, &api_keys, Access::Read).await {
+        Ok(caller) => caller,
+        Err(e) => return e.to_response(),
+    };
+
+    let email = caller;
+
+    println!("Got email: {}", &email);
+
+    let user = match users.get_user(&email).await {
+        Ok(user) => user,
+        Err(_) => {
+            println!("get_last_uuid: 404 users.get_user");
+            return ApiResponse::NotFound.to_response();
+        }
+    };
+
+    println!("Got user with email: {}", user.email);
+    println!("Got user with last_uuid: {}", user.email);
+
+    println!("get_last_uuid: OK");
+    HttpResponse::Ok().body(user.last_uuid)
+}
+
+pub async fn refresh_token(data: Option<web::Json<RefreshData>>, sessions: web::Data<Arc<SessionStore>>, app_config: web::Data<AppConfig>, req: HttpRequest) -> impl Responder {
+    let (refresh_token, from_cookie) = match data.and_then(|data| data.into_inner().refresh_token) {
+        Some(refresh_token) => (refresh_token, false),
+        None => match req.cookie(REFRESH_COOKIE) {
+            Some(cookie) => {
+                if let Err(e) = cookies::check_csrf(&req) {
+                    println!("refresh_token: 403 (CSRF token missing or wrong)");
+                    return e.to_response();
+                }
+                (cookie.value().to_string(), true)
+            },
+            None => {
+                println!("refresh_token: 401 (no refresh token)");
+                return ApiResponse::Unauthorized.to_response();
+            }
+        },
+    };
+    match sessions.refresh(&refresh_token, utils::client_ip(&req)).await {
+        Ok(issued) => {
+            println!("refresh_token: OK");
+            // A cookie session stays one without the header on every refresh
+            if from_cookie {
+                app_config.cookies.session_response(issued)
+            } else {
+                session_response(issued, &app_config.cookies, &req)
+            }
+        },
+        Err(e @ (RefreshError::Store(_) | RefreshError::Signing(_))) => {
+            println!("Error: {:?}", e);
+            println!("refresh_token: 500 sessions.refresh");
+            ApiResponse::InternalServerError.to_response()
+        },
+        Err(RefreshError::Disabled) => {
+            println!("refresh_token: 403 (account disabled)");
+            ApiResponse::AccountDisabled.to_response()
+        },
+        Err(e) => {
+            println!("refresh_token: 401 ({})", e);
+            ApiResponse::Unauthorized.to_response()
+        }
+    }
+}
+
+pub async fn list_sessions(req: HttpRequest, sessions: web::Data<Arc<SessionStore>>) -> impl Responder {
+    let session = match utils::verfiy_session_token(&req, &sessions).await {
+        Ok(session) => session,
+        Err(e) => return e.to_response(),
+    };
+
+    match sessions.list_for_user(&session.user_id).await {
+        Ok(active) => {
+            let mut infos: Vec<SessionInfo> = active.into_iter()
+                .map(|other| SessionInfo {
+                    id: other.family_id.to_string(),
+                    device_info: other.device_info,
+                    ip: other.ip,
+                    created_at: other.created_at,
+                    last_used: other.last_used,
+                    current: other.family_id == session.family_id,
+                })
+                .collect();
+            infos.sort_by_key(|info| std::cmp::Reverse(info.last_used));
+            println!("list_sessions: OK");
+            HttpResponse::Ok().json(infos)
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("list_sessions: 500 sessions.list_for_user");
+            ApiResponse::InternalServerError.to_response()
+        }
+    }
+}
+
+pub async fn revoke_session(req: HttpRequest, id: web::Path<String>, sessions: web::Data<Arc<SessionStore>>) -> impl Responder {
+    let session = match utils::verfiy_session_token(&req, &sessions).await {
+        Ok(session) => session,
+        Err(e) => return e.to_response(),
+    };
+
+    // Someone else's session is reported as missing, not as forbidden
+    let Ok(family_id) = Uuid::parse_str(&id) else {
+        println!("revoke_session: 404 (malformed id)");
+        return ApiResponse::NotFound.to_response();
+    };
+    match sessions.revoke_family(&session.user_id, family_id).await {
+        Ok(0) => {
+            println!("revoke_session: 404 sessions.revoke_family");
+            ApiResponse::NotFound.to_response()
+        },
+        Ok(_) => {
+            println!("revoke_session: OK");
+            ApiResponse::Ok.to_response()
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("revoke_session: 500 sessions.revoke_family");
             ApiResponse::InternalServerError.to_response()
         }
     }
 }
 
-pub async fn register(auth_data: web::Json<RegisterData>, db: web::Data<Arc<CouchDB>>, user_manager: web::Data<Arc<Mutex<UserManager>>>) -> impl Responder {
-    let mut user_manager = match user_manager.lock() {
-        Ok(manager) => manager,
-        Err(_) =>  {
-            println!("register: 500 (user_manager)");
-            return ApiResponse::InternalServerError.to_response()
-        }
+pub async fn revoke_other_sessions(req: HttpRequest, sessions: web::Data<Arc<SessionStore>>) -> impl Responder {
+    let session = match utils::verfiy_session_token(&req, &sessions).await {
+        Ok(session) => session,
+        Err(e) => return e.to_response(),
     };
-    if let Ok(user) = user_manager.register(auth_data.uuid.clone()) {
-        match db.put_user(user.clone()).await {
-            Ok(_) => {
-                println!("register: OK");
-                return ApiResponse::Ok.to_response();
-            },
-            Err(e) => {
-                user_manager.remove_user(&user.email);
-                println!("Error: {:?}", e);
-                println!("register: 500 (put_user)");
-                return ApiResponse::InternalServerError.to_response()
-            }
+
+    match sessions.revoke_all_for_user(&session.user_id, Some(session.family_id)).await {
+        Ok(revoked) => {
+            println!("revoke_other_sessions: OK ({} revoked)", revoked);
+            HttpResponse::Ok().json(revoked)
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("revoke_other_sessions: 500 sessions.revoke_all_for_user");
+            ApiResponse::InternalServerError.to_response()
         }
     }
-    ApiResponse::NotFound.to_response()
 }
 
-pub async fn login(auth_data: web::Json<LoginData>, db: web::Data<Arc<CouchDB>>, user_manager: web::Data<Arc<Mutex<UserManager>>>) -> impl Responder {
-    let mut user_manager = match user_manager.lock() {
-        Ok(manager) => manager,
-        Err(_) =>  {
-            println!("login: 500 (user_manager)");
+#[allow(clippy::too_many_arguments)]
+pub async fn verify_login(data: web::Json<VerifyLoginData>, users: web::Data<dyn UserStore>, sessions: web::Data<Arc<SessionStore>>, codes: web::Data<Arc<CodeStore>>, audit: web::Data<Arc<AuditLog>>, limiter: web::Data<Arc<RateLimiter>>, app_config: web::Data<AppConfig>, req: HttpRequest) -> impl Responder {
+    if let Err(wait) = limiter.check(Action::RedeemCode, &utils::client_ip(&req), None) {
+        println!("verify_login: 429 (rate limit)");
+        return ApiResponse::TooManyRequests(wait).to_response()
+    }
+    // The challenge stays valid through wrong codes, so a typo doesn't mean logging in again
+    let challenge = match codes.get(&data.challenge, CodePurpose::LoginChallenge).await {
+        Ok(Some(challenge)) => challenge,
+        Ok(None) => {
+            println!("verify_login: 401 (unknown or expired challenge)");
+            return ApiResponse::Unauthorized.to_response()
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("verify_login: 500 (codes.get)");
             return ApiResponse::InternalServerError.to_response()
         }
     };
-    let user_data = {
-        let user = user_manager.get_user(&auth_data.email);
-        if user.is_none() {
-            println!("login: user not found in cache");
+    if let Some(wait) = limiter.login_blocked(&challenge.email) {
+        println!("verify_login: 429 (too many failed attempts)");
+        return ApiResponse::TooManyRequests(wait).to_response()
+    }
 
-            let fetched_user = match db.get_user(&auth_data.email).await {
-                Ok(user) => user,
-                Err(e) => {
-                    println!("login: user not found in db: {:?}", e);
-                    return ApiResponse::InternalServerError.to_response();
-                }
-            };
-            user_manager.insert_user(fetched_user.clone());
-            // let fetched_user = db.get_user(&auth_data.email).await.ok();
-            // if let Some(ref user) = fetched_user {
-            //     println!("login: user found in db");
-            //     user_manager.insert_user(user.clone());
-            // }
-            Some(fetched_user)
-        } else {
-            user.map(|u| u.clone())
-        }
-    };
-    let user_data = match user_data {
-        Some(data) => data,
-        None => {
-            println!("login: 404 (get_user cache & db)");
-            return ApiResponse::NotFound.to_response()
-        }
-    };
-    match user_manager.login(auth_data.password.clone(), user_data) {
-        Ok(session_id) => {
-            println!("login: OK");
-            HttpResponse::Ok().json(session_id.to_string())
+    let now = Utc::now();
+    let verified = users.update_user_if(&challenge.email, |user| match user.totp.as_mut() {
+        Some(totp) if totp.confirmed => totp.accept_code(&data.code, now) || totp.accept_recovery_code(&data.code),
+        _ => false,
+    }).await;
+    let user = match verified {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            limiter.login_failed(&challenge.email);
+            audit.record(&req, AuditAction::LoginFailed, &challenge.email, None, Some("totp")).await;
+            println!("verify_login: 401 (wrong code)");
+            return ApiResponse::Unauthorized.to_response()
         },
         Err(e) => {
             println!("Error: {:?}", e);
-            println!("login: 401 (username & password don't match)");
-            ApiResponse::Unauthorized.to_response()
+            println!("verify_login: 500 (users.update_user_if)");
+            return ApiResponse::InternalServerError.to_response()
         }
+    };
+    if user.disabled {
+        println!("verify_login: 403 (account disabled)");
+        return ApiResponse::AccountDisabled.to_response()
     }
-}
 
-pub async fn logout(user_manager: web::Data<Arc<Mutex<UserManager>>>, req: HttpRequest) -> impl Responder {
-    let mut user_manager = match user_manager.lock() {
-        Ok(user_manager) => user_manager,
-        Err(_) =>  {
-            println!("logout: 500 (user_manager)");
+    match codes.consume(&data.challenge, CodePurpose::LoginChallenge).await {
+        Ok(Some(_)) => {},
+        Ok(None) => {
+            println!("verify_login: 401 (challenge already used)");
+            return ApiResponse::Unauthorized.to_response()
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("verify_login: 500 (codes.consume)");
             return ApiResponse::InternalServerError.to_response()
         }
-    };
-    
-    // Verify Session Token
-    let token_id = match utils::verfiy_session_token(&req, &user_manager) {
-        Ok(token) => token,
+    }
+    limiter.login_succeeded(&user.email);
+
+    match sessions.create(user.email.clone(), utils::describe_user_agent(&req), utils::client_ip(&req)).await {
+        Ok(issued) => {
+            audit.record(&req, AuditAction::LoginSucceeded, &user.email, Some(&user.email), Some("totp")).await;
+            println!("verify_login: OK");
+            session_response(issued, &app_config.cookies, &req)
+        },
         Err(e) => {
-            println!("logout: invalid session token");
-            return e.to_response();
+            println!("Error: {:?}", e);
+            println!("verify_login: 500 (sessions.create)");
+            ApiResponse::InternalServerError.to_response()
         }
-    };
-
-    user_manager.logout(token_id);
-    println!("logout: OK");
-    ApiResponse::Ok.to_response()
+    }
 }
 
-pub async fn send_reset_email(data: web::Json<PreResetData>, user_manager: web::Data<Arc<Mutex<UserManager>>>, db: web::Data<Arc<CouchDB>>, email_manager: web::Data<Arc<EmailManager>>, app_config: web::Data<AppConfig>) -> impl Responder {
-    let url = &app_config.url;
-    println!("Sending Reset email request for: {}", data.email);
-    let mut user_manager = match user_manager.lock() {
-        Ok(manager) => manager,
-        Err(_) =>  {
-            println!("send_reset_email: 500 (user_manager)");
+pub async fn send_magic_link(data: web::Json<MagicLinkData>, users: web::Data<dyn UserStore>, codes: web::Data<Arc<CodeStore>>, email_manager: web::Data<Arc<EmailManager>>, limiter: web::Data<Arc<RateLimiter>>, app_config: web::Data<AppConfig>, req: HttpRequest) -> impl Responder {
+    if let Err(wait) = limiter.check(Action::SendEmail, &utils::client_ip(&req), Some(&data.email)) {
+        println!("send_magic_link: 429 (rate limit)");
+        return ApiResponse::TooManyRequests(wait).to_response()
+    }
+    // Answer as if the mail was sent, so this can't be used to probe for
+    // accounts or find out which ones are suspended
+    match users.get_user(&data.email).await {
+        Ok(user) if user.disabled => {
+            println!("send_magic_link: OK (account disabled, nothing sent)");
+            return ApiResponse::Ok.to_response()
+        },
+        Ok(_) => {},
+        Err(StoreError::NotFound) => {
+            println!("send_magic_link: OK (no such user, nothing sent)");
+            return ApiResponse::Ok.to_response()
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("send_magic_link: 500 (users.get_user)");
             return ApiResponse::InternalServerError.to_response()
         }
-    };
-    if !(user_manager.user_exists(&data.email) || db.get_user(&data.email).await.is_ok()) {
-        println!("send_reset_email: 404 (user_exists & db.get_user)");
-        return ApiResponse::NotFound.to_response()
     }
-    let onetimepassword = user_manager.insert_reset_email_code(data.email.clone());
-    println!("Reset code: {}", onetimepassword);
-    let subject = "Password Zurücksetzung";
-    let body = format!("Klicken Sie diesen Link um Ihr Password zurückzusetzen: {}/auth?code={}", url, onetimepassword);
+    let code = match codes.issue(CodePurpose::MagicLink, data.email.clone(), None).await {
+        Ok(code) => code,
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("send_magic_link: 500 (codes.issue)");
+            return ApiResponse::InternalServerError.to_response()
+        }
+    };
+    let subject = "Ihr Anmeldelink";
+    let body = format!("Klicken Sie diesen Link um sich anzumelden: {}/auth?magic={}", app_config.url, code);
     match email_manager.send_email(&data.email, subject, &body) {
         Ok(_) => {
-            println!("send_reset_email: OK");
+            println!("send_magic_link: OK");
             ApiResponse::Ok.to_response()
         },
         Err(e) => {
             println!("Error: {:?}", e);
-            println!("send_reset_email: 500 (send_email)");
+            println!("send_magic_link: 500 (send_email)");
             ApiResponse::InternalServerError.to_response()
         }
     }
 }
 
-pub async fn reset_password(data: web::Json<ResetData>, user_manager: web::Data<Arc<Mutex<UserManager>>>, db: web::Data<Arc<CouchDB>>) -> impl Responder {
-    let mut user_manager = match user_manager.lock() {
-        Ok(manager) => manager,
-        Err(_) => {
-            println!("reset_password: 500 (user_manager)");
+#[allow(clippy::too_many_arguments)]
+pub async fn verify_magic_link(data: web::Json<MagicLinkVerifyData>, users: web::Data<dyn UserStore>, sessions: web::Data<Arc<SessionStore>>, codes: web::Data<Arc<CodeStore>>, audit: web::Data<Arc<AuditLog>>, limiter: web::Data<Arc<RateLimiter>>, app_config: web::Data<AppConfig>, req: HttpRequest) -> impl Responder {
+    if let Err(wait) = limiter.check(Action::RedeemCode, &utils::client_ip(&req), None) {
+        println!("verify_magic_link: 429 (rate limit)");
+        return ApiResponse::TooManyRequests(wait).to_response()
+    }
+    let email = match codes.consume(&data.code, CodePurpose::MagicLink).await {
+        Ok(Some(code)) => code.email,
+        Ok(None) => {
+            println!("verify_magic_link: 401 (unknown, used or expired code)");
+            return ApiResponse::Unauthorized.to_response()
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("verify_magic_link: 500 (codes.consume)");
             return ApiResponse::InternalServerError.to_response()
         }
     };
-       
-    // Does code exist?
-    if let Some(email) = user_manager.get_email_from_code(&data.uuid) {
-        // Does User exist?
-        if !(user_manager.user_exists(&email) || db.get_user(&email).await.is_ok()) {
-            println!("reset_password: 404 (user_exists & db.get_user)");
-            return ApiResponse::NotFound.to_response()
-        }
-        let newsletter = match db.get_user(&email).await {
-            Ok(user) => user.newsletter,
-            Err(_) => {
-                println!("reset_password: 404 (db.get_user)");
-                return ApiResponse::NotFound.to_response();
-            }
-        };
-        // Get new user && insert into cache
-        let user = user_manager.change_password(&email, &data.password, newsletter);
-        // Put new user into db
-        return match db.put_user(user.clone()).await {
-            Ok(_) => {
-                println!("reset_password: OK");
-                ApiResponse::Ok.to_response()
-            },
-            Err(e) => {
-                user_manager.remove_user(&user.email);
-                println!("Error {:?}", e);
-                println!("reset_password: 500 (db.put_user)");
-                ApiResponse::InternalServerError.to_response()
-            }
+    let user = match users.get_user(&email).await {
+        Ok(user) => user,
+        // Deleted since the link was sent
+        Err(StoreError::NotFound) => {
+            println!("verify_magic_link: 401 (no such user)");
+            return ApiResponse::Unauthorized.to_response()
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("verify_magic_link: 500 (users.get_user)");
+            return ApiResponse::InternalServerError.to_response()
         }
-    }
-    println!("reset_password: 404 (get_email_from_code)");
-    ApiResponse::NotFound.to_response()
+    };
+
+    // The link replaces the password only, a second factor is still asked for
+    start_session(user, &sessions, &codes, &audit, &app_config.cookies, &req, "verify_magic_link", "magic_link").await
 }
 
-pub async fn get_document(id: web::Path<String>, user_manager: web::Data<Arc<Mutex<UserManager>>>, db: web::Data<Arc<CouchDB>>,  req: HttpRequest) -> impl Responder {
-    let user_manager = match user_manager.lock() {
-        Ok(manager) => manager,
-        Err(_) => {
-            println!("get_document: 500 (user_manager)");
-            return ApiResponse::InternalServerError.to_response();
+pub async fn enroll_totp(req: HttpRequest, users: web::Data<dyn UserStore>, sessions: web::Data<Arc<SessionStore>>, app_config: web::Data<AppConfig>) -> impl Responder {
+    let session = match utils::verfiy_session_token(&req, &sessions).await {
+        Ok(session) => session,
+        Err(e) => return e.to_response(),
+    };
+
+    // Starting over replaces an unconfirmed secret, a confirmed one has to be disabled first
+    let enrolled = users.update_user_if(&session.user_id, |user| {
+        if user.has_totp() {
+            return false;
+        }
+        user.totp = Some(TotpState::new());
+        true
+    }).await;
+    let user = match enrolled {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            println!("enroll_totp: 409 (already enrolled)");
+            return ApiResponse::Conflict.to_response()
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("enroll_totp: 500 (users.update_user_if)");
+            return ApiResponse::InternalServerError.to_response()
         }
     };
 
-    // Verify Session Token
-    let _ = match utils::verfiy_session_token(&req, &user_manager) {
-        Ok(token) => token,
+    let Some(totp) = user.totp.as_ref() else { return ApiResponse::InternalServerError.to_response() };
+    println!("enroll_totp: OK");
+    HttpResponse::Ok().json(TotpEnrollment {
+        secret: totp.secret.clone(),
+        otpauth_uri: totp.provisioning_uri(&app_config.totp.issuer, &user.email),
+    })
+}
+
+pub async fn confirm_totp(req: HttpRequest, data: web::Json<TotpCodeData>, users: web::Data<dyn UserStore>, sessions: web::Data<Arc<SessionStore>>, limiter: web::Data<Arc<RateLimiter>>) -> impl Responder {
+    let session = match utils::verfiy_session_token(&req, &sessions).await {
+        Ok(session) => session,
         Err(e) => return e.to_response(),
     };
+    if let Err(wait) = limiter.check(Action::RedeemCode, &utils::client_ip(&req), None) {
+        println!("confirm_totp: 429 (rate limit)");
+        return ApiResponse::TooManyRequests(wait).to_response()
+    }
 
-    match db.get_document_data(&id).await {
-        Ok(doc) => {
-            println!("get_document: OK");
-            HttpResponse::Ok().json(doc)
+    let now = Utc::now();
+    let mut recovery_codes = Vec::new();
+    let confirmed = users.update_user_if(&session.user_id, |user| match user.totp.as_mut() {
+        Some(totp) if !totp.confirmed => {
+            if !totp.accept_code(&data.code, now) {
+                return false;
+            }
+            totp.confirmed = true;
+            recovery_codes = totp.reset_recovery_codes();
+            true
+        },
+        _ => false,
+    }).await;
+    match confirmed {
+        Ok(Some(_)) => {
+            println!("confirm_totp: OK");
+            HttpResponse::Ok().json(RecoveryCodes { recovery_codes })
+        },
+        Ok(None) => {
+            println!("confirm_totp: 403 (wrong code or nothing to confirm)");
+            ApiResponse::Forbidden.to_response()
         },
         Err(e) => {
             println!("Error: {:?}", e);
-            println!("get_document: 404 (get_document_data)");
-            ApiResponse::NotFound.to_response()
+            println!("confirm_totp: 500 (users.update_user_if)");
+            ApiResponse::InternalServerError.to_response()
         }
     }
 }
 
-pub async fn get_config(user_manager: web::Data<Arc<Mutex<UserManager>>>, db: web::Data<Arc<CouchDB>>,  req: HttpRequest) -> impl Responder {
-    // let user_manager = match user_manager.lock() {
-    //     Ok(manager) => manager,
-    //     Err(_) => return ApiResponse::InternalServerError.to_response()
-    // };
-
-    // Verify Session Token
-    // let _ = match utils::verfiy_session_token(&req, &user_manager) {
-    //     Ok(token) => token,
-    //     Err(e) => return e.to_response(),
-    // };
+pub async fn regenerate_recovery_codes(req: HttpRequest, data: web::Json<TotpCodeData>, users: web::Data<dyn UserStore>, sessions: web::Data<Arc<SessionStore>>, limiter: web::Data<Arc<RateLimiter>>) -> impl Responder {
+    let session = match utils::verfiy_session_token(&req, &sessions).await {
+        Ok(session) => session,
+        Err(e) => return e.to_response(),
+    };
+    if let Err(wait) = limiter.check(Action::RedeemCode, &utils::client_ip(&req), None) {
+        println!("regenerate_recovery_codes: 429 (rate limit)");
+        return ApiResponse::TooManyRequests(wait).to_response()
+    }
 
-    match db.get_config_data().await {
-        Ok(doc) => {
-            println!("get_config: OK");
-            HttpResponse::Ok().json(doc)
+    let now = Utc::now();
+    let mut recovery_codes = Vec::new();
+    let regenerated = users.update_user_if(&session.user_id, |user| match user.totp.as_mut() {
+        Some(totp) if totp.confirmed => {
+            if !totp.accept_code(&data.code, now) {
+                return false;
+            }
+            recovery_codes = totp.reset_recovery_codes();
+            true
+        },
+        _ => false,
+    }).await;
+    match regenerated {
+        Ok(Some(_)) => {
+            println!("regenerate_recovery_codes: OK");
+            HttpResponse::Ok().json(RecoveryCodes { recovery_codes })
+        },
+        Ok(None) => {
+            println!("regenerate_recovery_codes: 403 (wrong code or not enrolled)");
+            ApiResponse::Forbidden.to_response()
         },
         Err(e) => {
             println!("Error: {:?}", e);
-            println!("get_config: 404 (db.get_config_data)");
-            ApiResponse::NotFound.to_response()
+            println!("regenerate_recovery_codes: 500 (users.update_user_if)");
+            ApiResponse::InternalServerError.to_response()
         }
     }
 }
 
-pub async fn put_document(id: web::Path<String>, user_manager: web::Data<Arc<Mutex<UserManager>>>, db: web::Data<Arc<CouchDB>>,  data: web::Json<Value>, req: HttpRequest) -> impl Responder {
-    let user_manager = match user_manager.lock() {
-        Ok(manager) => manager,
-        Err(_) => {
-            println!("put_document: 500 user_manager");
-            return ApiResponse::InternalServerError.to_response();
-        }
-    };
-
-    // Verify Session Token
-    let _ = match utils::verfiy_session_token(&req, &user_manager) {
-        Ok(token) => token,
+pub async fn disable_totp(req: HttpRequest, data: web::Json<TotpCodeData>, users: web::Data<dyn UserStore>, sessions: web::Data<Arc<SessionStore>>, limiter: web::Data<Arc<RateLimiter>>) -> impl Responder {
+    let session = match utils::verfiy_session_token(&req, &sessions).await {
+        Ok(session) => session,
         Err(e) => return e.to_response(),
     };
+    if let Err(wait) = limiter.check(Action::RedeemCode, &utils::client_ip(&req), None) {
+        println!("disable_totp: 429 (rate limit)");
+        return ApiResponse::TooManyRequests(wait).to_response()
+    }
 
-    // Put document
-    match db.put_document(&id, data.into_inner()).await {
-        Ok(doc) => {
-            println!("put_document: OK");
-            HttpResponse::Ok().json(doc)
+    // A stolen session alone must not be enough to remove the second factor
+    let now = Utc::now();
+    let disabled = users.update_user_if(&session.user_id, |user| {
+        let accepted = match user.totp.as_mut() {
+            Some(totp) if totp.confirmed => totp.accept_code(&data.code, now) || totp.accept_recovery_code(&data.code),
+            // Abandoning an unfinished enrollment needs no code
+            Some(_) => true,
+            None => false,
+        };
+        if accepted {
+            user.totp = None;
+        }
+        accepted
+    }).await;
+    match disabled {
+        Ok(Some(_)) => {
+            println!("disable_totp: OK");
+            ApiResponse::Ok.to_response()
+        },
+        Ok(None) => {
+            println!("disable_totp: 403 (wrong code or not enrolled)");
+            ApiResponse::Forbidden.to_response()
         },
         Err(e) => {
             println!("Error: {:?}", e);
-            println!("put_document: 500 db.put_document");
+            println!("disable_totp: 500 (users.update_user_if)");
             ApiResponse::InternalServerError.to_response()
         }
     }
 }
 
+/// The caller's own audit trail. `account` in the query is ignored, `actor`
+/// narrows it down, e.g. to what admins did to the account.
+pub async fn list_audit(req: HttpRequest, query: web::Query<AuditQuery>, sessions: web::Data<Arc<SessionStore>>, audit: web::Data<Arc<AuditLog>>) -> impl Responder {
+    let session = match utils::verfiy_session_token(&req, &sessions).await {
+        Ok(session) => session,
+        Err(e) => return e.to_response(),
+    };
 
-pub async fn get_uuids(id: web::Path<String>, user_manager: web::Data<Arc<Mutex<UserManager>>>, db: web::Data<Arc<CouchDB>>,  req: HttpRequest) -> impl Responder {
-    let user_manager = match user_manager.lock() {
-        Ok(manager) => manager,
-        Err(_) => {
-            println!("get_uuids: 500 user_manager");
-            return ApiResponse::InternalServerError.to_response();
+    let query = AuditQuery { account: Some(session.user_id), ..query.into_inner() };
+    match audit.find(&query).await {
+        Ok(entries) => {
+            println!("list_audit: OK");
+            HttpResponse::Ok().json(entries)
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("list_audit: 500 audit.find");
+            ApiResponse::InternalServerError.to_response()
         }
-    };
+    }
+}
 
-    // Verify Session Token
-    let _ = match utils::verfiy_session_token(&req, &user_manager) {
-        Ok(token) => token,
+pub async fn list_api_keys(req: HttpRequest, sessions: web::Data<Arc<SessionStore>>, api_keys: web::Data<Arc<ApiKeyStore>>) -> impl Responder {
+    let session = match utils::verfiy_session_token(&req, &sessions).await {
+        Ok(session) => session,
         Err(e) => return e.to_response(),
     };
 
-    match db.get_user(&id).await {
-        Ok(user) => {
-            println!("get_uuids: OK");
-            HttpResponse::Ok().json(user.uuids)
+    match api_keys.list_for_user(&session.user_id).await {
+        Ok(keys) => {
+            let mut infos: Vec<ApiKeyInfo> = keys.into_iter().map(ApiKeyInfo::from).collect();
+            infos.sort_by_key(|info| info.created_at);
+            println!("list_api_keys: OK");
+            HttpResponse::Ok().json(infos)
         },
         Err(e) => {
             println!("Error: {:?}", e);
-            println!("get_uuids: 404 db.get_user");
-            ApiResponse::NotFound.to_response()
+            println!("list_api_keys: 500 api_keys.list_for_user");
+            ApiResponse::InternalServerError.to_response()
         }
     }
 }
 
-pub async fn post_uuid(user_manager: web::Data<Arc<Mutex<UserManager>>>, req: HttpRequest, db: web::Data<Arc<CouchDB>>, id: web::Path<String>, data: web::Json<AddUuid>) -> impl Responder {
-    let user_manager = match user_manager.lock() {
-        Ok(manager) => manager,
-        Err(_) => {
-            println!("post_uuid: 500 user_manager");
-            return ApiResponse::InternalServerError.to_response();
-        }
-    };
-
-    // Verify Session Token
-    let _ = match utils::verfiy_session_token(&req, &user_manager) {
-        Ok(token) => token,
+pub async fn create_api_key(req: HttpRequest, data: web::Json<CreateApiKeyData>, sessions: web::Data<Arc<SessionStore>>, api_keys: web::Data<Arc<ApiKeyStore>>) -> impl Responder {
+    let session = match utils::verfiy_session_token(&req, &sessions).await {
+        Ok(session) => session,
         Err(e) => return e.to_response(),
     };
 
-    let mut user = match db.get_user(&id).await {
-        Ok(user) => user,
+    let data = data.into_inner();
+    match api_keys.create(session.user_id, data.name, data.scope).await {
+        Ok(Some((key, secret))) => {
+            println!("create_api_key: OK");
+            HttpResponse::Ok().json(ApiKeyInfo { key: Some(secret), ..ApiKeyInfo::from(key) })
+        },
+        Ok(None) => {
+            println!("create_api_key: 409 (too many keys)");
+            ApiResponse::Conflict.to_response()
+        },
         Err(e) => {
             println!("Error: {:?}", e);
-            println!("post_uuid: 404 db.get_user");
-            return ApiResponse::NotFound.to_response()
+            println!("create_api_key: 500 api_keys.create");
+            ApiResponse::InternalServerError.to_response()
         }
+    }
+}
+
+pub async fn revoke_api_key(req: HttpRequest, id: web::Path<String>, sessions: web::Data<Arc<SessionStore>>, api_keys: web::Data<Arc<ApiKeyStore>>) -> impl Responder {
+    let session = match utils::verfiy_session_token(&req, &sessions).await {
+        Ok(session) => session,
+        Err(e) => return e.to_response(),
     };
-    user.uuids.push(data.uuid.clone());
-    match db.put_user(user).await {
-        Ok(_) => {
-            println!("post_uuid: OK");
-            HttpResponse::Ok().json("UUIDs updated successfully")
+
+    // Someone else's key is reported as missing, not as forbidden
+    match api_keys.revoke(&session.user_id, &id).await {
+        Ok(true) => {
+            println!("revoke_api_key: OK");
+            ApiResponse::Ok.to_response()
+        },
+        Ok(false) => {
+            println!("revoke_api_key: 404 api_keys.revoke");
+            ApiResponse::NotFound.to_response()
         },
         Err(e) => {
             println!("Error: {:?}", e);
-            println!("post_uuid: 500 db.put_user");
+            println!("revoke_api_key: 500 api_keys.revoke");
             ApiResponse::InternalServerError.to_response()
         }
     }
 }
 
-pub async fn delete_uuid(path: web::Path<(String, String)>, user_manager: web::Data<Arc<Mutex<UserManager>>>, req: HttpRequest, db: web::Data<Arc<CouchDB>>) -> impl Responder {
-    let user_manager = match user_manager.lock() {
-        Ok(manager) => manager,
-        Err(_) => {
-            println!("delete_uuid: 500 user_manager");
-            return ApiResponse::InternalServerError.to_response();
-        }
-    };
-
-    // Verify Session Token
-    let _ = match utils::verfiy_session_token(&req, &user_manager) {
-        Ok(token) => token,
-        Err(e) => return e.to_response(),
+pub async fn oidc_start(provider: web::Path<String>, codes: web::Data<Arc<CodeStore>>, oidc: web::Data<Arc<OidcClient>>, app_config: web::Data<AppConfig>) -> impl Responder {
+    if !oidc.has_provider(&provider) {
+        println!("oidc_start: 404 (unknown provider)");
+        return ApiResponse::NotFound.to_response()
+    }
+    let pending = PendingLogin::new(provider.into_inner());
+    let data = match serde_json::to_value(&pending) {
+        Ok(data) => data,
+        Err(_) => return ApiResponse::InternalServerError.to_response(),
     };
-
-    let (id, uuid) = path.into_inner();
-    let mut user = match db.get_user(&id).await {
-        Ok(user) => user,
+    // The state code ties the provider's redirect back to this PKCE verifier and nonce
+    let state = match codes.issue_with_data(CodePurpose::OidcLogin, String::new(), None, data).await {
+        Ok(state) => state,
         Err(e) => {
             println!("Error: {:?}", e);
-            println!("delete_uuid: 404 db.get_user");
-            return HttpResponse::NotFound().body(format!("User with email {} not found", id));
+            println!("oidc_start: 500 (codes.issue_with_data)");
+            return ApiResponse::InternalServerError.to_response()
         }
     };
-    user.uuids.retain(|x| !x.eq(&uuid.as_str()));
-    match db.put_user(user).await {
-        Ok(_) => {
-            println!("delete_uuid: OK");
-            HttpResponse::Ok().json("UUIDs updated successfully")
+    match oidc.authorization_url(&pending, &state).await {
+        Ok(Some(url)) => {
+            println!("oidc_start: OK");
+            let until = Utc::now() + codes.ttl(CodePurpose::OidcLogin);
+            HttpResponse::Found()
+                .cookie(app_config.cookies.oidc_state(&state, until))
+                .insert_header(("Location", url))
+                .finish()
         },
+        Ok(None) => ApiResponse::NotFound.to_response(),
         Err(e) => {
             println!("Error: {:?}", e);
-            println!("delete_uuid: 500 db.put_user");
+            println!("oidc_start: 500 (oidc.authorization_url)");
             ApiResponse::InternalServerError.to_response()
         }
     }
 }
 
-pub async fn delete_user(req: HttpRequest, email: web::Path<String> , user_manager: web::Data<Arc<Mutex<UserManager>>>, db: web::Data<Arc<CouchDB>>) -> impl Responder {
-    let mut user_manager = match user_manager.lock() {
-        Ok(manager) => manager,
-        Err(_) => {
-            println!("delete_user: 500 user_manager");
-            return ApiResponse::InternalServerError.to_response();
-        }
-    };
-
-    // Verify Session Token
-    let token_id = match utils::verfiy_session_token(&req, &user_manager) {
-        Ok(token) => token,
-        Err(e) => return e.to_response(),
-    };
+#[allow(clippy::too_many_arguments)]
+pub async fn oidc_callback(provider: web::Path<String>, query: web::Query<OidcCallbackQuery>, users: web::Data<dyn UserStore>, sessions: web::Data<Arc<SessionStore>>, codes: web::Data<Arc<CodeStore>>, audit: web::Data<Arc<AuditLog>>, oidc: web::Data<Arc<OidcClient>>, limiter: web::Data<Arc<RateLimiter>>, app_config: web::Data<AppConfig>, req: HttpRequest) -> impl Responder {
+    // The state is good for one attempt, whatever its outcome
+    let mut response = finish_oidc_login(&provider, &query, &**users, &sessions, &codes, &audit, &oidc, &limiter, &app_config, &req).await;
+    app_config.cookies.clear_oidc_state(&mut response);
+    response
+}
 
-    let user_db_deleted = match db.delete_user(&email.as_str()).await {
-        Ok(_) => true,
-        Err(_) => false
+#[allow(clippy::too_many_arguments)]
+async fn finish_oidc_login(provider: &str, query: &OidcCallbackQuery, users: &dyn UserStore, sessions: &SessionStore, codes: &CodeStore, audit: &AuditLog, oidc: &OidcClient, limiter: &RateLimiter, app_config: &AppConfig, req: &HttpRequest) -> HttpResponse {
+    if let Err(wait) = limiter.check(Action::RedeemCode, &utils::client_ip(req), None) {
+        println!("oidc_callback: 429 (rate limit)");
+        return ApiResponse::TooManyRequests(wait).to_response()
+    }
+    if let Some(error) = &query.error {
+        println!("oidc_callback: 401 (provider reported {})", error);
+        return ApiResponse::Unauthorized.to_response()
+    }
+    let (Some(code), Some(state)) = (&query.code, &query.state) else {
+        println!("oidc_callback: 401 (code or state missing)");
+        return ApiResponse::Unauthorized.to_response()
     };
-    if !user_db_deleted {
-        println!("delete_user: 500 db.delete_user");
-        return ApiResponse::InternalServerError.to_response();
+    // Otherwise an attacker could finish a login they started in someone else's browser
+    if !cookies::check_oidc_state(req, state) {
+        println!("oidc_callback: 401 (state not started in this browser)");
+        return ApiResponse::Unauthorized.to_response()
     }
-    user_manager.logout(token_id);
-    user_manager.delete_user(email.as_str());
-
-    println!("delete_user: OK");
-    HttpResponse::Ok().body("User deleted successfully")
-}
 
-pub async fn get_last_uuid(req: HttpRequest, user_manager: web::Data<Arc<Mutex<UserManager>>>, db: web::Data<Arc<CouchDB>>) -> impl Responder {
-    let user_manager = match user_manager.lock() {
-        Ok(manager) => manager,
-        Err(_) => {
-            println!("get_last_uuid: 500 user_manager");
-            return ApiResponse::InternalServerError.to_response();
+    let pending = match codes.consume(state, CodePurpose::OidcLogin).await {
+        Ok(Some(stored)) => serde_json::from_value::<PendingLogin>(stored.data).ok(),
+        Ok(None) => None,
+        Err(e) => {
+            println!("Error: {:?}", e);
+            println!("oidc_callback: 500 (codes.consume)");
+            return ApiResponse::InternalServerError.to_response()
         }
     };
-
-    let token = match utils::verfiy_session_token(&req, &user_manager) {
-        Ok(token) => token,
-        Err(e) => return e.to_response(),
+    let pending = match pending {
+        Some(pending) if pending.provider == *provider => pending,
+        _ => {
+            println!("oidc_callback: 401 (unknown, used or expired state)");
+            return ApiResponse::Unauthorized.to_response()
+        }
     };
 
-    println!("Verified token: {}", &token);
-    
-    let email = match user_manager.get_email_from_token(&token) {
-        Some(email) => email,
-        None => {
-            println!("get_last_uuid: 404 get_email_from_token");
-            return ApiResponse::NotFound.to_response();
+    let identity = match oidc.finish(&pending, code).await {
+        Ok(Some(identity)) => identity,
+        Ok(None) => return ApiResponse::NotFound.to_response(),
+        Err(OidcError::Http(e)) => {
+            println!("Error: {:?}", e);
+            println!("oidc_callback: 500 (oidc.finish)");
+            return ApiResponse::InternalServerError.to_response()
+        },
+        Err(e) => {
+            println!("oidc_callback: 401 ({})", e);
+            return ApiResponse::Unauthorized.to_response()
+        }
+    };
+    let user = match link_identity(users, &pending.provider, identity).await {
+        Ok(user) => user,
+        Err(e) => {
+            println!("oidc_callback: failed (link_identity)");
+            return e.to_response()
         }
     };
 
-    println!("Got email: {}", &email);
+    let method = format!("oidc:{}", pending.provider);
+    start_session(user, sessions, codes, audit, &app_config.cookies, req, "oidc_callback", &method).await
+}
 
-    let user = match db.get_user(&email).await {
+/// Finds the user an OpenID Connect identity signs in as. An existing account
+/// with the verified email gets the identity linked, otherwise an account
+/// without a password is created.
+async fn link_identity(users: &dyn UserStore, provider: &str, identity: VerifiedIdentity) -> Result<User, ApiResponse> {
+    let linked = ExternalIdentity { provider: provider.to_string(), subject: identity.subject };
+    let user = match users.get_user(&identity.email).await {
         Ok(user) => user,
-        Err(_) => {
-            println!("get_last_uuid: 404 db.get_user");
-            return ApiResponse::NotFound.to_response();
+        Err(StoreError::NotFound) => {
+            let mut user = User::new(identity.email.clone(), String::new(), false);
+            user.identities.push(linked.clone());
+            match users.create_user(user).await {
+                Ok(user) => {
+                    println!("link_identity: created {}", user.email);
+                    return Ok(user);
+                },
+                // Registered in the meantime, link that account like any existing one
+                Err(StoreError::Conflict) => users.get_user(&identity.email).await.map_err(|e| {
+                    println!("Error: {:?}", e);
+                    ApiResponse::InternalServerError
+                })?,
+                Err(e) => {
+                    println!("Error: {:?}", e);
+                    return Err(ApiResponse::InternalServerError);
+                }
+            }
+        },
+        Err(e) => {
+            println!("Error: {:?}", e);
+            return Err(ApiResponse::InternalServerError);
         }
     };
 
-    println!("Got user with email: {}", user.email);
-    println!("Got user with last_uuid: {}", user.email);
+    if user.identities.contains(&linked) {
+        return Ok(user);
+    }
+    // One account per provider, a second one with the same email is someone else
+    if user.identities.iter().any(|existing| existing.provider == linked.provider) {
+        println!("link_identity: 403 (another {} account is linked)", provider);
+        return Err(ApiResponse::Forbidden);
+    }
+    users.update_user(&identity.email, |user| {
+        if !user.identities.contains(&linked) {
+            user.identities.push(linked.clone());
+        }
+    }).await.map_err(|e| {
+        println!("Error: {:?}", e);
+        ApiResponse::InternalServerError
+    })
+}
 
-    println!("get_last_uuid: OK");
-    HttpResponse::Ok().body(user.last_uuid)
+async fn hash_password(config: &PasswordConfig, password: &str) -> Result<String, PasswordError> {
+    let config = config.clone();
+    let password = password.to_string();
+    // Argon2 is deliberately slow, keep it off the worker's event loop
+    web::block(move || password::hash_password(&config, &password))
+        .await
+        .unwrap_or(Err(PasswordError::Aborted))
+}
+
+async fn verify_dummy(config: &PasswordConfig, password: &str) -> Result<(), PasswordError> {
+    let config = config.clone();
+    let password = password.to_string();
+    web::block(move || password::verify_dummy(&config, &password))
+        .await
+        .unwrap_or(Err(PasswordError::Aborted))
+}
+
+async fn verify_password(config: &PasswordConfig, password: &str, hashed: &str, salt: &str) -> Result<Verification, PasswordError> {
+    let config = config.clone();
+    let (password, hashed, salt) = (password.to_string(), hashed.to_string(), salt.to_string());
+    web::block(move || password::verify_password(&config, &password, &hashed, &salt))
+        .await
+        .unwrap_or(Err(PasswordError::Aborted))
 }
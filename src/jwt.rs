@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::str::FromStr;
+use std::sync::RwLock;
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+use crate::auth::Role;
+use crate::utils::env_name;
+
+/// HS256 secrets shorter than this are rejected at startup.
+const MIN_SECRET_BYTES: usize = 32;
+/// Tolerated clock difference between instances when checking `exp`.
+const LEEWAY_SECS: u64 = 5;
+
+/// How access tokens are issued and checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionMode {
+    /// Access tokens are session ids, looked up in CouchDB.
+    Opaque,
+    /// Access tokens are signed JWTs, checked without CouchDB.
+    Jwt,
+}
+
+impl FromStr for SessionMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "opaque" => Ok(SessionMode::Opaque),
+            "jwt" => Ok(SessionMode::Jwt),
+            other => Err(format!("unknown session mode {}", other)),
+        }
+    }
+}
+
+/// What a signed access token says about its session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessClaims {
+    /// The user's email.
+    pub sub: String,
+    /// The session this token belongs to, what `/sessions` calls its id.
+    pub jti: Uuid,
+    /// The session family, i.e. the login this token was refreshed from.
+    pub sid: Uuid,
+    pub roles: Vec<Role>,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+#[derive(Error, Debug)]
+pub enum KeyError {
+    #[error("JWT_KEY_IDS is empty")]
+    NoKeys,
+    #[error("Unsupported JWT_ALGORITHM {0}, use HS256 or EdDSA")]
+    UnsupportedAlgorithm(String),
+    #[error("{0} must be set")]
+    Missing(String),
+    #[error("{0} must be at least {MIN_SECRET_BYTES} bytes")]
+    WeakSecret(String),
+    #[error("Could not read the key file in {0}: {1}")]
+    Unreadable(String, std::io::Error),
+    #[error("Invalid key in {0}: {1}")]
+    Invalid(String, jsonwebtoken::errors::Error),
+}
+
+/// Signing and verification keys. Tokens are signed with the first key id in
+/// `JWT_KEY_IDS` and accepted with any of them, so a key can be rotated out by
+/// adding a new id in front and dropping the old one once its tokens expired.
+pub struct JwtKeys {
+    algorithm: Algorithm,
+    signing_kid: String,
+    signing_key: EncodingKey,
+    verifying_keys: HashMap<String, DecodingKey>,
+}
+
+impl JwtKeys {
+    /// Reads `JWT_ALGORITHM` and `JWT_KEY_IDS`. For HS256 every key id needs a
+    /// `JWT_SECRET_<ID>`. For EdDSA every key id needs a `JWT_PUBLIC_KEY_<ID>`
+    /// and the signing one also a `JWT_PRIVATE_KEY_<ID>`, both paths to PEM files.
+    pub fn from_env() -> Result<Self, KeyError> {
+        let algorithm = match env::var("JWT_ALGORITHM").unwrap_or_else(|_| "HS256".to_string()).as_str() {
+            "HS256" => Algorithm::HS256,
+            "EdDSA" => Algorithm::EdDSA,
+            other => return Err(KeyError::UnsupportedAlgorithm(other.to_string())),
+        };
+        let kids: Vec<String> = env::var("JWT_KEY_IDS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|kid| kid.trim().to_string())
+            .filter(|kid| !kid.is_empty())
+            .collect();
+        let signing_kid = kids.first().cloned().ok_or(KeyError::NoKeys)?;
+
+        let mut verifying_keys = HashMap::new();
+        for kid in &kids {
+            let key = match algorithm {
+                Algorithm::EdDSA => {
+                    let (var, pem) = read_pem("JWT_PUBLIC_KEY_", kid)?;
+                    DecodingKey::from_ed_pem(&pem).map_err(|e| KeyError::Invalid(var, e))?
+                },
+                _ => DecodingKey::from_secret(&read_secret(kid)?),
+            };
+            verifying_keys.insert(kid.clone(), key);
+        }
+        let signing_key = match algorithm {
+            Algorithm::EdDSA => {
+                let (var, pem) = read_pem("JWT_PRIVATE_KEY_", &signing_kid)?;
+                EncodingKey::from_ed_pem(&pem).map_err(|e| KeyError::Invalid(var, e))?
+            },
+            _ => EncodingKey::from_secret(&read_secret(&signing_kid)?),
+        };
+        Ok(JwtKeys { algorithm, signing_kid, signing_key, verifying_keys })
+    }
+
+    pub fn sign(&self, claims: &AccessClaims) -> Result<String, jsonwebtoken::errors::Error> {
+        let mut header = Header::new(self.algorithm);
+        header.kid = Some(self.signing_kid.clone());
+        jsonwebtoken::encode(&header, claims, &self.signing_key)
+    }
+
+    /// Returns the claims if the token was signed with a known key and has not expired.
+    pub fn verify(&self, token: &str) -> Option<AccessClaims> {
+        let header = jsonwebtoken::decode_header(token).ok()?;
+        let key = self.verifying_keys.get(header.kid.as_deref()?)?;
+        let mut validation = Validation::new(self.algorithm);
+        validation.leeway = LEEWAY_SECS;
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        jsonwebtoken::decode::<AccessClaims>(token, key, &validation).ok().map(|data| data.claims)
+    }
+}
+
+/// Revoked access tokens that have not expired yet. Signed tokens can't be
+/// recalled, so logouts and revocations are remembered here until the token
+/// would have expired anyway.
+pub struct Denylist {
+    revoked: RwLock<HashMap<Uuid, DateTime<Utc>>>,
+}
+
+impl Denylist {
+    pub fn new() -> Self {
+        Denylist { revoked: RwLock::new(HashMap::new()) }
+    }
+
+    pub fn insert(&self, jti: Uuid, until: DateTime<Utc>) {
+        if let Ok(mut revoked) = self.revoked.write() {
+            revoked.insert(jti, until);
+        }
+    }
+
+    pub fn contains(&self, jti: &Uuid) -> bool {
+        // Failing closed: a poisoned lock rejects every token
+        self.revoked.read().map_or(true, |revoked| revoked.contains_key(jti))
+    }
+
+    /// Forgets entries whose tokens have expired.
+    pub fn prune(&self, now: DateTime<Utc>) {
+        if let Ok(mut revoked) = self.revoked.write() {
+            revoked.retain(|_, until| *until > now);
+        }
+    }
+}
+
+fn read_secret(kid: &str) -> Result<Vec<u8>, KeyError> {
+    let var = env_name("JWT_SECRET_", kid);
+    let secret = env::var(&var).map_err(|_| KeyError::Missing(var.clone()))?;
+    if secret.len() < MIN_SECRET_BYTES {
+        return Err(KeyError::WeakSecret(var));
+    }
+    Ok(secret.into_bytes())
+}
+
+fn read_pem(prefix: &str, kid: &str) -> Result<(String, Vec<u8>), KeyError> {
+    let var = env_name(prefix, kid);
+    let path = env::var(&var).map_err(|_| KeyError::Missing(var.clone()))?;
+    match fs::read(&path) {
+        Ok(pem) => Ok((var, pem)),
+        Err(e) => Err(KeyError::Unreadable(var, e)),
+    }
+}
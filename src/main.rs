@@ -1,18 +1,52 @@
 mod db;
+mod access;
+mod admin;
 mod handlers;
 mod auth;
 mod email;
 mod utils;
+mod password;
+mod session;
+mod codes;
+mod rate_limit;
+mod totp;
+mod jwt;
+mod api_keys;
+mod oidc;
+mod audit;
+mod cookies;
+mod store;
+mod memory;
+#[cfg(test)]
+mod tests;
 
 use actix_web::{web, App, HttpServer};
 use email::EmailManager;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use db::CouchDB;
-use auth::UserManager;
+use memory::MemoryStore;
+use store::{ProjectConfig, Storage};
+use password::PasswordConfig;
+use session::{SessionConfig, SessionStore};
+use jwt::{JwtKeys, SessionMode};
+use api_keys::ApiKeyStore;
+use oidc::OidcClient;
+use audit::AuditLog;
+use cookies::CookieConfig;
+use codes::{CodeConfig, CodeStore};
+use rate_limit::{RateLimitConfig, RateLimiter};
+use totp::TotpConfig;
 use std::env;
+use std::net::IpAddr;
 
 pub struct AppConfig {
-    pub url: String
+    pub url: String,
+    pub password: PasswordConfig,
+    pub totp: TotpConfig,
+    pub cookies: CookieConfig,
+    pub projects: ProjectConfig,
+    /// Reverse proxies whose `X-Forwarded-For` is believed, see `utils::client_ip`.
+    pub trusted_proxies: Vec<IpAddr>,
 }
 
 #[actix_web::main]
@@ -20,19 +54,96 @@ async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
     // env_logger::init();
     let url = env::var("URL").expect("URL must be set (e.g. http://123.32.1.2)");
+    let trusted_proxies = match utils::trusted_proxies_from_env() {
+        Ok(proxies) => proxies,
+        Err(e) => {
+            eprintln!("Failed to read TRUSTED_PROXIES: {}", e);
+            std::process::exit(1);
+        }
+    };
     let app_config = web::Data::new(AppConfig {
-        url
+        url,
+        password: PasswordConfig::from_env(),
+        totp: TotpConfig::from_env(),
+        cookies: CookieConfig::from_env(),
+        projects: ProjectConfig::from_env(),
+        trusted_proxies,
     });
 
-    let db_url = env::var("DB_URL").expect("DB URL must be set (e.g: https://couchdb-app-service.azurewebsites.net)");
-    let db_username = env::var("DB_USERNAME").expect("DB Username must be set");
-    let db_password = env::var("DB_PASSWORD").expect("DB Password must be set");
-
     let smtp_email = env::var("SMTP_EMAIL").expect("SMTP_EMAIL must be set");
     let smtp_password = env::var("SMTP_PASSWORD").expect("SMTP_PASSWORD must be set");
 
-    let couchdb = Arc::new(CouchDB::new(db_url, db_username, db_password));
-    let user_manager = Arc::new(Mutex::new(UserManager::new()));
+    let Storage { users, documents, config, sessions: session_records, codes: code_records, api_keys: api_key_records, audit: audit_records } = match env::var("STORAGE").as_deref() {
+        Ok("memory") => {
+            let memory = Arc::new(MemoryStore::new());
+            if let Ok(path) = env::var("MEMORY_CONFIG_FILE") {
+                match std::fs::read_to_string(&path).map_err(|e| e.to_string()).and_then(|text| serde_json::from_str(&text).map_err(|e| e.to_string())) {
+                    Ok(data) => memory.set_config(data),
+                    Err(e) => {
+                        eprintln!("Failed to load {}: {}", path, e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            Storage::new(memory)
+        },
+        _ => {
+            let db_url = env::var("DB_URL").expect("DB URL must be set (e.g: https://couchdb-app-service.azurewebsites.net)");
+            let db_username = env::var("DB_USERNAME").expect("DB Username must be set");
+            let db_password = env::var("DB_PASSWORD").expect("DB Password must be set");
+            let couchdb = Arc::new(CouchDB::new(db_url, db_username, db_password));
+            db::spawn_changes_feed(couchdb.clone());
+            Storage::new(couchdb)
+        },
+    };
+    if let Err(e) = documents.init().await {
+        eprintln!("Failed to set up the projects database: {:?}", e);
+        std::process::exit(1);
+    }
+    store::spawn_purge_task(documents.clone(), app_config.projects.clone());
+    let session_config = SessionConfig::from_env();
+    let jwt_keys = match session_config.mode {
+        SessionMode::Opaque => None,
+        SessionMode::Jwt => match JwtKeys::from_env() {
+            Ok(keys) => Some(keys),
+            Err(e) => {
+                eprintln!("Failed to load the JWT keys: {}", e);
+                std::process::exit(1);
+            }
+        },
+    };
+    let sessions = Arc::new(SessionStore::new(session_records, users.clone(), session_config, jwt_keys));
+    if let Err(e) = sessions.init().await {
+        eprintln!("Failed to set up the sessions database: {:?}", e);
+        std::process::exit(1);
+    }
+    session::spawn_purge_task(sessions.clone());
+    session::spawn_denylist_task(sessions.clone());
+    let codes = Arc::new(CodeStore::new(code_records, CodeConfig::from_env()));
+    if let Err(e) = codes.init().await {
+        eprintln!("Failed to set up the codes database: {:?}", e);
+        std::process::exit(1);
+    }
+    codes::spawn_purge_task(codes.clone());
+    let api_keys = Arc::new(ApiKeyStore::new(api_key_records, users.clone()));
+    if let Err(e) = api_keys.init().await {
+        eprintln!("Failed to set up the api_keys database: {:?}", e);
+        std::process::exit(1);
+    }
+    let audit = Arc::new(AuditLog::new(audit_records));
+    if let Err(e) = audit.init().await {
+        eprintln!("Failed to set up the audit database: {:?}", e);
+        std::process::exit(1);
+    }
+    let oidc = match OidcClient::from_env() {
+        Ok(client) => Arc::new(client),
+        Err(e) => {
+            eprintln!("Failed to configure OpenID Connect: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let limiter = Arc::new(RateLimiter::new(RateLimitConfig::from_env()));
+    rate_limit::spawn_prune_task(limiter.clone());
     let email_manager = match EmailManager::new(&smtp_email, &smtp_password) {
         Ok(manager) => Arc::new(manager),
         Err(e) => {
@@ -43,26 +154,78 @@ async fn main() -> std::io::Result<()> {
 
     HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(couchdb.clone()))
-            .app_data(web::Data::new(user_manager.clone()))
+            .app_data(web::Data::from(users.clone()))
+            .app_data(web::Data::from(documents.clone()))
+            .app_data(web::Data::from(config.clone()))
+            .app_data(web::Data::new(sessions.clone()))
+            .app_data(web::Data::new(codes.clone()))
+            .app_data(web::Data::new(api_keys.clone()))
+            .app_data(web::Data::new(oidc.clone()))
+            .app_data(web::Data::new(audit.clone()))
+            .app_data(web::Data::new(limiter.clone()))
             .app_data(web::Data::new(email_manager.clone()))
             .app_data(app_config.clone())
-            .route("/config", web::get().to(handlers::get_config))
-            .route("/{id}", web::get().to(handlers::get_document))
-            .route("/{id}", web::put().to(handlers::put_document))
-            .route("/login", web::post().to(handlers::login))
-            .route("/logout", web::post().to(handlers::logout))
-            .route("/register", web::post().to(handlers::register))
-            .route("/pre-register", web::post().to(handlers::pre_register))
-            .route("/uuids/{id}", web::get().to(handlers::get_uuids))
-            .route("/uuids/{id}", web::post().to(handlers::post_uuid))
-            .route("/uuids/{id}/{uuid}", web::delete().to(handlers::delete_uuid))
-            .route("/pre-reset", web::post().to(handlers::send_reset_email))
-            .route("/reset", web::post().to(handlers::reset_password))
-            .route("/user/{id}", web::delete().to(handlers::delete_user))
-            .route("/user/last-uuid", web::get().to(handlers::get_last_uuid))
+            .configure(routes)
     })
     .bind(("0.0.0.0", 3000))?
     .run()
     .await
 }
+
+/// Every endpoint, for `main` and the handler tests.
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/config", web::get().to(handlers::get_config))
+        .route("/token/refresh", web::post().to(handlers::refresh_token))
+        .route("/sessions", web::get().to(handlers::list_sessions))
+        .route("/sessions/revoke-others", web::post().to(handlers::revoke_other_sessions))
+        .route("/sessions/{id}", web::delete().to(handlers::revoke_session))
+        .route("/api-keys", web::get().to(handlers::list_api_keys))
+        .route("/api-keys", web::post().to(handlers::create_api_key))
+        .route("/api-keys/{id}", web::delete().to(handlers::revoke_api_key))
+        .route("/auth/oidc/{provider}/start", web::get().to(handlers::oidc_start))
+        .route("/auth/oidc/{provider}/callback", web::get().to(handlers::oidc_callback))
+        .route("/login/verify", web::post().to(handlers::verify_login))
+        .route("/login/magic", web::post().to(handlers::send_magic_link))
+        .route("/login/magic/verify", web::post().to(handlers::verify_magic_link))
+        .route("/2fa/enroll", web::post().to(handlers::enroll_totp))
+        .route("/2fa/confirm", web::post().to(handlers::confirm_totp))
+        .route("/2fa/recovery-codes", web::post().to(handlers::regenerate_recovery_codes))
+        .route("/2fa/disable", web::post().to(handlers::disable_totp))
+        .route("/me/password", web::post().to(handlers::change_password))
+        .route("/me/email", web::post().to(handlers::change_email))
+        .route("/me/email/confirm", web::post().to(handlers::confirm_email_change))
+        .route("/me/audit", web::get().to(handlers::list_audit))
+        .service(
+            web::scope("/admin")
+                .route("/users", web::get().to(admin::list_users))
+                .route("/users/{email}", web::get().to(admin::get_user))
+                .route("/users/{email}", web::delete().to(admin::delete_user))
+                .route("/users/{email}/roles", web::put().to(admin::set_roles))
+                .route("/users/{email}/sessions", web::delete().to(admin::revoke_sessions))
+                .route("/users/{email}/suspend", web::post().to(admin::suspend_user))
+                .route("/users/{email}/reactivate", web::post().to(admin::reactivate_user))
+                .route("/projects/{id}", web::get().to(admin::get_project))
+                .route("/audit", web::get().to(admin::list_audit))
+        )
+        .route("/{id}", web::get().to(handlers::get_document))
+        .route("/{id}", web::put().to(handlers::put_document))
+        .route("/{id}", web::patch().to(handlers::patch_document))
+        .route("/{id}", web::delete().to(handlers::delete_document))
+        .route("/login", web::post().to(handlers::login))
+        .route("/logout", web::post().to(handlers::logout))
+        .route("/register", web::post().to(handlers::register))
+        .route("/pre-register", web::post().to(handlers::pre_register))
+        .route("/uuids/{id}", web::get().to(handlers::get_uuids))
+        .route("/uuids/{id}", web::post().to(handlers::post_uuid))
+        .route("/uuids/{id}/{uuid}", web::delete().to(handlers::delete_uuid))
+        .route("/pre-reset", web::post().to(handlers::send_reset_email))
+        .route("/reset", web::post().to(handlers::reset_password))
+        .route("/user/{id}", web::delete().to(handlers::delete_user))
+        .route("/user/last-uuid", web::get().to(handlers::get_last_uuid))
+        // After the fixed paths, which these would shadow for a project of the same name
+        .route("/{id}/undelete", web::post().to(handlers::undelete_document))
+        .route("/{id}/history", web::get().to(handlers::list_history))
+        .route("/{id}/history/{rev}", web::get().to(handlers::get_history_revision))
+        .route("/{id}/restore/{rev}", web::post().to(handlers::restore_revision))
+        .route("/{id}/events", web::get().to(handlers::project_events));
+}
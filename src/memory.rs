@@ -0,0 +1,421 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use async_trait::async_trait;
+use tokio::sync::broadcast;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use uuid::Uuid;
+use crate::api_keys::ApiKey;
+use crate::audit::{AuditEntry, AuditQuery};
+use crate::auth::User;
+use crate::codes::OneTimeCode;
+use crate::db::{Deletion, Document, ProjectChange, Revision, RevisionInfo};
+use crate::session::SessionToken;
+use crate::store::{self, ApiKeyRecords, AuditRecords, CodeRecords, ConfigStore, DocumentPatch, DocumentStore, SessionRecords, StoreError, UserStore};
+
+/// Everything the server stores, held in memory, for tests and local
+/// development. Nothing survives a restart.
+pub struct MemoryStore {
+    users: Mutex<BTreeMap<String, User>>,
+    documents: Mutex<HashMap<String, Document>>,
+    /// Oldest first.
+    revisions: Mutex<Vec<Revision>>,
+    config: Mutex<Option<Value>>,
+    changes: broadcast::Sender<ProjectChange>,
+    sessions: Mutex<HashMap<Uuid, SessionToken>>,
+    codes: Mutex<HashMap<String, OneTimeCode>>,
+    api_keys: Mutex<HashMap<Uuid, ApiKey>>,
+    /// Oldest first.
+    audit: Mutex<Vec<AuditEntry>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore {
+            users: Mutex::new(BTreeMap::new()),
+            documents: Mutex::new(HashMap::new()),
+            revisions: Mutex::new(Vec::new()),
+            config: Mutex::new(None),
+            changes: broadcast::channel(CHANGES_BUFFER).0,
+            sessions: Mutex::new(HashMap::new()),
+            codes: Mutex::new(HashMap::new()),
+            api_keys: Mutex::new(HashMap::new()),
+            audit: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Tells `subscribe`rs about a write to `doc`.
+    fn changed(&self, doc: &Document, deleted: bool) {
+        let (Some(id), Some(rev)) = (&doc.id, &doc.rev) else { return };
+        // Nobody listening is not an error
+        let _ = self.changes.send(ProjectChange { id: id.clone(), rev: rev.clone(), deleted });
+    }
+
+    /// Sets what `get_config_data` returns. Until then it fails with `NotFound`, like an empty CouchDB.
+    pub fn set_config(&self, config: Value) {
+        *lock(&self.config) = Some(config);
+    }
+}
+
+const CHANGES_BUFFER: usize = 1024;
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        MemoryStore::new()
+    }
+}
+
+/// The maps are only changed in single statements, so a panic elsewhere
+/// can't leave them half updated and a poisoned lock is safe to keep using.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A CouchDB style revision, `<generation>-<random>`, following `previous`.
+fn next_rev(previous: Option<&str>) -> String {
+    let generation = previous
+        .and_then(|rev| rev.split_once('-'))
+        .and_then(|(generation, _)| generation.parse::<u64>().ok())
+        .unwrap_or(0);
+    format!("{}-{}", generation + 1, Uuid::new_v4().simple())
+}
+
+/// Fails like CouchDB when a write of `rev` would not follow `stored`, the
+/// revision currently held, or `None` for a new record.
+fn check_rev(stored: Option<&Option<String>>, rev: &Option<String>) -> Result<(), StoreError> {
+    match stored {
+        Some(stored) if stored != rev => Err(StoreError::Conflict),
+        None if rev.is_some() => Err(StoreError::Conflict),
+        _ => Ok(()),
+    }
+}
+
+#[async_trait]
+impl DocumentStore for MemoryStore {
+    async fn get_document(&self, id: &str) -> Result<Document, StoreError> {
+        lock(&self.documents).get(id).cloned().ok_or(StoreError::NotFound)
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<ProjectChange> {
+        self.changes.subscribe()
+    }
+
+    async fn put_document(&self, id: &str, data: Value, if_match: Option<&str>) -> Result<Document, StoreError> {
+        let mut documents = lock(&self.documents);
+        let doc = match documents.get_mut(id) {
+            Some(doc) => doc,
+            None if if_match.is_some() => return Err(StoreError::NotFound),
+            None => documents.entry(id.to_string()).or_insert(Document {
+                id: Some(id.to_string()),
+                rev: None,
+                owner: None,
+                deleted: None,
+                data: Value::Null,
+            }),
+        };
+        if if_match.is_some_and(|rev| doc.rev.as_deref() != Some(rev)) {
+            return Err(StoreError::Conflict);
+        }
+        doc.data = store::combine_json_values(doc.data.take(), data);
+        doc.rev = Some(next_rev(doc.rev.as_deref()));
+        self.changed(doc, false);
+        Ok(doc.clone())
+    }
+
+    async fn patch_document(&self, id: &str, patch: &DocumentPatch, if_match: Option<&str>) -> Result<Document, StoreError> {
+        let mut documents = lock(&self.documents);
+        let doc = documents.get_mut(id).ok_or(StoreError::NotFound)?;
+        if if_match.is_some_and(|rev| doc.rev.as_deref() != Some(rev)) {
+            return Err(StoreError::Conflict);
+        }
+        patch.apply(&mut doc.data)?;
+        doc.rev = Some(next_rev(doc.rev.as_deref()));
+        self.changed(doc, false);
+        Ok(doc.clone())
+    }
+
+    async fn create_document(&self, id: &str, owner: &str, data: Value) -> Result<Document, StoreError> {
+        let mut documents = lock(&self.documents);
+        if documents.contains_key(id) {
+            return Err(StoreError::Conflict);
+        }
+        let doc = Document {
+            id: Some(id.to_string()),
+            rev: Some(next_rev(None)),
+            owner: Some(owner.to_string()),
+            deleted: None,
+            data,
+        };
+        documents.insert(id.to_string(), doc.clone());
+        self.changed(&doc, false);
+        Ok(doc)
+    }
+
+    async fn delete_document(&self, id: &str) -> Result<(), StoreError> {
+        let doc = lock(&self.documents).remove(id).ok_or(StoreError::NotFound)?;
+        self.changed(&doc, true);
+        lock(&self.revisions).retain(|revision| revision.project_id != id);
+        Ok(())
+    }
+
+    async fn set_deleted(&self, id: &str, deleted: Option<Deletion>) -> Result<Document, StoreError> {
+        let mut documents = lock(&self.documents);
+        let doc = documents.get_mut(id).ok_or(StoreError::NotFound)?;
+        doc.deleted = deleted;
+        doc.rev = Some(next_rev(doc.rev.as_deref()));
+        self.changed(doc, false);
+        Ok(doc.clone())
+    }
+
+    async fn purge_deleted(&self, before: DateTime<Utc>) -> Result<usize, StoreError> {
+        let mut documents = lock(&self.documents);
+        let count = documents.len();
+        documents.retain(|_, doc| {
+            let keep = doc.deleted.as_ref().is_none_or(|deleted| deleted.at >= before);
+            if !keep {
+                self.changed(doc, true);
+            }
+            keep
+        });
+        lock(&self.revisions).retain(|revision| documents.contains_key(&revision.project_id));
+        Ok(count - documents.len())
+    }
+
+    async fn record_revision(&self, doc: &Document, author: &str) -> Result<(), StoreError> {
+        let (Some(id), Some(rev)) = (&doc.id, &doc.rev) else { return Ok(()) };
+        lock(&self.revisions).push(Revision {
+            id: Uuid::new_v4(),
+            project_id: id.clone(),
+            info: RevisionInfo { rev: rev.clone(), author: author.to_string(), at: Utc::now() },
+            data: doc.data.clone(),
+        });
+        Ok(())
+    }
+
+    async fn list_revisions(&self, id: &str, skip: usize, limit: usize) -> Result<Vec<RevisionInfo>, StoreError> {
+        Ok(lock(&self.revisions).iter().rev()
+            .filter(|revision| revision.project_id == id)
+            .skip(skip)
+            .take(limit)
+            .map(|revision| revision.info.clone())
+            .collect())
+    }
+
+    async fn get_revision(&self, id: &str, rev: &str) -> Result<Revision, StoreError> {
+        lock(&self.revisions).iter()
+            .find(|revision| revision.project_id == id && revision.info.rev == rev)
+            .cloned()
+            .ok_or(StoreError::NotFound)
+    }
+
+    async fn purge_revisions(&self, before: DateTime<Utc>) -> Result<usize, StoreError> {
+        let mut revisions = lock(&self.revisions);
+        let count = revisions.len();
+        revisions.retain(|revision| revision.info.at >= before);
+        Ok(count - revisions.len())
+    }
+
+    async fn transfer_document(&self, id: &str, from: &str, to: &str) -> Result<bool, StoreError> {
+        let mut documents = lock(&self.documents);
+        let doc = documents.get_mut(id).ok_or(StoreError::NotFound)?;
+        if doc.owner.as_deref() != Some(from) {
+            return Ok(false);
+        }
+        doc.owner = Some(to.to_string());
+        doc.rev = Some(next_rev(doc.rev.as_deref()));
+        self.changed(doc, false);
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl UserStore for MemoryStore {
+    async fn get_user(&self, email: &str) -> Result<User, StoreError> {
+        lock(&self.users).get(email).cloned().ok_or(StoreError::NotFound)
+    }
+
+    async fn list_users(&self, skip: usize, limit: usize) -> Result<Vec<User>, StoreError> {
+        Ok(lock(&self.users).values().skip(skip).take(limit).cloned().collect())
+    }
+
+    async fn create_user(&self, user: User) -> Result<User, StoreError> {
+        let mut users = lock(&self.users);
+        if users.contains_key(&user.email) {
+            return Err(StoreError::Conflict);
+        }
+        users.insert(user.email.clone(), user.clone());
+        Ok(user)
+    }
+
+    async fn modify_user(&self, email: &str, change: &mut (dyn for<'u> FnMut(&'u mut User) -> bool + Send)) -> Result<Option<User>, StoreError> {
+        let mut users = lock(&self.users);
+        let stored = users.get_mut(email).ok_or(StoreError::NotFound)?;
+        // Changed on a copy, so a declined change leaves nothing behind
+        let mut user = stored.clone();
+        if !change(&mut user) {
+            return Ok(None);
+        }
+        *stored = user.clone();
+        Ok(Some(user))
+    }
+
+    async fn delete_user(&self, email: &str) -> Result<(), StoreError> {
+        lock(&self.users).remove(email).map(|_| ()).ok_or(StoreError::NotFound)
+    }
+}
+
+#[async_trait]
+impl ConfigStore for MemoryStore {
+    async fn get_config_data(&self) -> Result<Value, StoreError> {
+        lock(&self.config).clone().ok_or(StoreError::NotFound)
+    }
+}
+
+#[async_trait]
+impl SessionRecords for MemoryStore {
+    async fn get_session(&self, token: &str) -> Result<SessionToken, StoreError> {
+        let token = Uuid::parse_str(token).map_err(|_| StoreError::NotFound)?;
+        lock(&self.sessions).get(&token).cloned().ok_or(StoreError::NotFound)
+    }
+
+    async fn put_session(&self, session: &SessionToken) -> Result<String, StoreError> {
+        let mut sessions = lock(&self.sessions);
+        check_rev(sessions.get(&session.token).map(|stored| &stored.rev), &session.rev)?;
+        let rev = next_rev(session.rev.as_deref());
+        let mut stored = session.clone();
+        stored.rev = Some(rev.clone());
+        sessions.insert(session.token, stored);
+        Ok(rev)
+    }
+
+    async fn delete_session(&self, session: &SessionToken) -> Result<(), StoreError> {
+        let mut sessions = lock(&self.sessions);
+        let stored = sessions.get(&session.token).ok_or(StoreError::NotFound)?;
+        check_rev(Some(&stored.rev), &session.rev)?;
+        sessions.remove(&session.token);
+        Ok(())
+    }
+
+    async fn list_active_sessions(&self, user_id: &str, now: DateTime<Utc>) -> Result<Vec<SessionToken>, StoreError> {
+        Ok(lock(&self.sessions).values()
+            .filter(|session| session.user_id == user_id && !session.is_revoked && !session.rotated)
+            .filter(|session| session.absolute_expires_at.is_some_and(|at| at > now))
+            .cloned()
+            .collect())
+    }
+
+    async fn list_family(&self, user_id: &str, family_id: Uuid) -> Result<Vec<SessionToken>, StoreError> {
+        Ok(lock(&self.sessions).values()
+            .filter(|session| session.user_id == user_id && session.family_id == family_id && !session.is_revoked)
+            .cloned()
+            .collect())
+    }
+
+    async fn list_revoked(&self, now: DateTime<Utc>) -> Result<Vec<(Uuid, DateTime<Utc>)>, StoreError> {
+        Ok(lock(&self.sessions).values()
+            .filter(|session| session.is_revoked && session.expires_at > now)
+            .map(|session| (session.token, session.expires_at))
+            .collect())
+    }
+
+    async fn purge_sessions(&self, now: DateTime<Utc>) -> Result<usize, StoreError> {
+        let mut sessions = lock(&self.sessions);
+        let count = sessions.len();
+        sessions.retain(|_, session| session.ends_at() >= now);
+        Ok(count - sessions.len())
+    }
+}
+
+#[async_trait]
+impl CodeRecords for MemoryStore {
+    async fn put_code(&self, code: &OneTimeCode) -> Result<(), StoreError> {
+        let mut codes = lock(&self.codes);
+        check_rev(codes.get(&code.id).map(|stored| &stored.rev), &code.rev)?;
+        let rev = next_rev(code.rev.as_deref());
+        let mut stored = code.clone();
+        stored.rev = Some(rev);
+        codes.insert(code.id.clone(), stored);
+        Ok(())
+    }
+
+    async fn get_code(&self, id: &str) -> Result<OneTimeCode, StoreError> {
+        lock(&self.codes).get(id).cloned().ok_or(StoreError::NotFound)
+    }
+
+    async fn delete_code(&self, code: &OneTimeCode) -> Result<(), StoreError> {
+        let mut codes = lock(&self.codes);
+        let stored = codes.get(&code.id).ok_or(StoreError::NotFound)?;
+        check_rev(Some(&stored.rev), &code.rev)?;
+        codes.remove(&code.id);
+        Ok(())
+    }
+
+    async fn purge_codes(&self, now: DateTime<Utc>) -> Result<usize, StoreError> {
+        let mut codes = lock(&self.codes);
+        let count = codes.len();
+        codes.retain(|_, code| code.expires_at >= now);
+        Ok(count - codes.len())
+    }
+}
+
+#[async_trait]
+impl ApiKeyRecords for MemoryStore {
+    async fn get_key(&self, id: Uuid) -> Result<ApiKey, StoreError> {
+        lock(&self.api_keys).get(&id).cloned().ok_or(StoreError::NotFound)
+    }
+
+    async fn put_key(&self, key: &ApiKey) -> Result<String, StoreError> {
+        let mut api_keys = lock(&self.api_keys);
+        check_rev(api_keys.get(&key.id).map(|stored| &stored.rev), &key.rev)?;
+        let rev = next_rev(key.rev.as_deref());
+        let mut stored = key.clone();
+        stored.rev = Some(rev.clone());
+        api_keys.insert(key.id, stored);
+        Ok(rev)
+    }
+
+    async fn delete_key(&self, key: &ApiKey) -> Result<(), StoreError> {
+        let mut api_keys = lock(&self.api_keys);
+        let stored = api_keys.get(&key.id).ok_or(StoreError::NotFound)?;
+        check_rev(Some(&stored.rev), &key.rev)?;
+        api_keys.remove(&key.id);
+        Ok(())
+    }
+
+    async fn list_keys(&self, user_id: &str, limit: usize) -> Result<Vec<ApiKey>, StoreError> {
+        Ok(lock(&self.api_keys).values()
+            .filter(|key| key.user_id == user_id)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    async fn delete_keys(&self, user_id: &str) -> Result<usize, StoreError> {
+        let mut api_keys = lock(&self.api_keys);
+        let count = api_keys.len();
+        api_keys.retain(|_, key| key.user_id != user_id);
+        Ok(count - api_keys.len())
+    }
+}
+
+#[async_trait]
+impl AuditRecords for MemoryStore {
+    async fn append(&self, entry: &AuditEntry) -> Result<(), StoreError> {
+        lock(&self.audit).push(entry.clone());
+        Ok(())
+    }
+
+    async fn find_entries(&self, filter: &AuditQuery, skip: usize, limit: usize) -> Result<Vec<AuditEntry>, StoreError> {
+        // Appended in order, so newest first is the list reversed
+        Ok(lock(&self.audit).iter().rev()
+            .filter(|entry| filter.account.as_ref().is_none_or(|account| entry.account == *account))
+            .filter(|entry| filter.actor.is_none() || entry.actor == filter.actor)
+            .filter(|entry| filter.action.is_none_or(|action| entry.action == action))
+            .filter(|entry| filter.since.is_none_or(|since| entry.at >= since))
+            .filter(|entry| filter.until.is_none_or(|until| entry.at <= until))
+            .skip(skip)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::RwLock;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use crate::utils::{constant_time_eq, env_name};
+
+const DEFAULT_SCOPES: &str = "openid email profile";
+
+/// One identity provider, configured through `OIDC_<NAME>_*` env vars.
+#[derive(Debug, Clone)]
+pub struct ProviderConfig {
+    pub issuer: String,
+    pub client_id: String,
+    /// Unset for public clients, which rely on PKCE alone.
+    pub client_secret: Option<String>,
+    /// Where the provider sends the browser back to with `code` and `state`.
+    pub redirect_url: String,
+    pub scopes: String,
+}
+
+/// The parts of the provider's discovery document we use.
+#[derive(Debug, Clone, Deserialize)]
+struct Metadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+struct Provider {
+    config: ProviderConfig,
+    metadata: RwLock<Option<Metadata>>,
+    jwks: RwLock<Option<JwkSet>>,
+}
+
+/// What has to be remembered between sending the browser to the provider and
+/// its return. Stored with the `state` code, never given to the browser.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingLogin {
+    pub provider: String,
+    verifier: String,
+    nonce: String,
+}
+
+/// The user an ID token vouches for.
+#[derive(Debug, Clone)]
+pub struct VerifiedIdentity {
+    pub subject: String,
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IdClaims {
+    sub: String,
+    email: Option<String>,
+    /// Some providers send `"true"` rather than `true`.
+    email_verified: Option<Value>,
+    nonce: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum OidcError {
+    #[error("{0} must be set")]
+    MissingConfig(String),
+    #[error("Request to the identity provider failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Discovery document names issuer {0}")]
+    IssuerMismatch(String),
+    #[error("Invalid authorization endpoint {0}")]
+    InvalidEndpoint(String),
+    #[error("The identity provider returned no ID token")]
+    MissingIdToken,
+    #[error("Invalid ID token: {0}")]
+    InvalidToken(#[from] jsonwebtoken::errors::Error),
+    #[error("ID token signed with an unknown key")]
+    UnknownKey,
+    #[error("ID token signed with unsupported algorithm {0:?}")]
+    UnsupportedAlgorithm(Algorithm),
+    #[error("ID token nonce does not match")]
+    NonceMismatch,
+    #[error("The identity provider did not vouch for an email address")]
+    UnverifiedEmail,
+}
+
+/// OpenID Connect authorization code flow with PKCE against the providers
+/// listed in `OIDC_PROVIDERS`. Discovery documents and signing keys are
+/// fetched on first use and cached.
+pub struct OidcClient {
+    http: Client,
+    providers: HashMap<String, Provider>,
+}
+
+impl OidcClient {
+    /// Reads `OIDC_PROVIDERS`, a comma separated list of provider names, and
+    /// for each `OIDC_<NAME>_ISSUER`, `_CLIENT_ID`, `_REDIRECT_URL` and the
+    /// optional `_CLIENT_SECRET` and `_SCOPES`.
+    pub fn from_env() -> Result<Self, OidcError> {
+        let mut providers = HashMap::new();
+        let names = env::var("OIDC_PROVIDERS").unwrap_or_default();
+        for name in names.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            let prefix = format!("{}_", env_name("OIDC_", name));
+            let setting = |key: &str| env::var(format!("{}{}", prefix, key)).ok().filter(|value| !value.is_empty());
+            let required = |key: &str| setting(key).ok_or_else(|| OidcError::MissingConfig(format!("{}{}", prefix, key)));
+            let config = ProviderConfig {
+                issuer: required("ISSUER")?,
+                client_id: required("CLIENT_ID")?,
+                client_secret: setting("CLIENT_SECRET"),
+                redirect_url: required("REDIRECT_URL")?,
+                scopes: setting("SCOPES").unwrap_or_else(|| DEFAULT_SCOPES.to_string()),
+            };
+            providers.insert(name.to_string(), Provider {
+                config,
+                metadata: RwLock::new(None),
+                jwks: RwLock::new(None),
+            });
+        }
+        Ok(OidcClient { http: Client::new(), providers })
+    }
+
+    pub fn has_provider(&self, name: &str) -> bool {
+        self.providers.contains_key(name)
+    }
+
+    /// Where to send the browser to sign in at the provider. `state` is
+    /// handed back on return and identifies `pending`.
+    pub async fn authorization_url(&self, pending: &PendingLogin, state: &str) -> Result<Option<String>, OidcError> {
+        let Some(provider) = self.providers.get(&pending.provider) else { return Ok(None) };
+        let metadata = self.metadata(provider).await?;
+        let mut url = Url::parse(&metadata.authorization_endpoint)
+            .map_err(|_| OidcError::InvalidEndpoint(metadata.authorization_endpoint.clone()))?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &provider.config.client_id)
+            .append_pair("redirect_uri", &provider.config.redirect_url)
+            .append_pair("scope", &provider.config.scopes)
+            .append_pair("state", state)
+            .append_pair("nonce", &pending.nonce)
+            .append_pair("code_challenge", &pkce_challenge(&pending.verifier))
+            .append_pair("code_challenge_method", "S256");
+        Ok(Some(url.to_string()))
+    }
+
+    /// Redeems the authorization `code` and verifies the returned ID token.
+    pub async fn finish(&self, pending: &PendingLogin, code: &str) -> Result<Option<VerifiedIdentity>, OidcError> {
+        let Some(provider) = self.providers.get(&pending.provider) else { return Ok(None) };
+        let metadata = self.metadata(provider).await?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", provider.config.redirect_url.as_str()),
+            ("client_id", provider.config.client_id.as_str()),
+            ("code_verifier", pending.verifier.as_str()),
+        ];
+        if let Some(secret) = &provider.config.client_secret {
+            form.push(("client_secret", secret.as_str()));
+        }
+        let tokens: TokenResponse = self.http
+            .post(&metadata.token_endpoint)
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let id_token = tokens.id_token.ok_or(OidcError::MissingIdToken)?;
+
+        let header = jsonwebtoken::decode_header(&id_token)?;
+        // Symmetric algorithms would let anyone who knows the client secret forge tokens
+        if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+            return Err(OidcError::UnsupportedAlgorithm(header.alg));
+        }
+        let key = self.signing_key(provider, &metadata, header.kid.as_deref()).await?;
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_audience(&[&provider.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        let claims = jsonwebtoken::decode::<IdClaims>(&id_token, &key, &validation)?.claims;
+
+        let nonce_matches = claims.nonce.as_deref()
+            .is_some_and(|nonce| constant_time_eq(nonce.as_bytes(), pending.nonce.as_bytes()));
+        if !nonce_matches {
+            return Err(OidcError::NonceMismatch);
+        }
+        let verified = match claims.email_verified {
+            Some(Value::Bool(verified)) => verified,
+            Some(Value::String(verified)) => verified == "true",
+            _ => false,
+        };
+        match claims.email {
+            Some(email) if verified && !email.is_empty() => Ok(Some(VerifiedIdentity {
+                subject: claims.sub,
+                email: email.to_lowercase(),
+            })),
+            _ => Err(OidcError::UnverifiedEmail),
+        }
+    }
+
+    async fn metadata(&self, provider: &Provider) -> Result<Metadata, OidcError> {
+        if let Some(metadata) = provider.metadata.read().ok().and_then(|cached| cached.clone()) {
+            return Ok(metadata);
+        }
+        let issuer = provider.config.issuer.trim_end_matches('/');
+        let url = format!("{}/.well-known/openid-configuration", issuer);
+        let metadata: Metadata = self.http.get(&url).send().await?.error_for_status()?.json().await?;
+        // Tokens are checked against this issuer, it has to be the configured one
+        if metadata.issuer.trim_end_matches('/') != issuer {
+            return Err(OidcError::IssuerMismatch(metadata.issuer));
+        }
+        if let Ok(mut cached) = provider.metadata.write() {
+            *cached = Some(metadata.clone());
+        }
+        Ok(metadata)
+    }
+
+    /// The provider's key `kid`. The key set is fetched again once when the
+    /// key is unknown, as providers rotate their keys.
+    async fn signing_key(&self, provider: &Provider, metadata: &Metadata, kid: Option<&str>) -> Result<DecodingKey, OidcError> {
+        for refetch in [false, true] {
+            let cached = if refetch { None } else { provider.jwks.read().ok().and_then(|jwks| jwks.clone()) };
+            let jwks = match cached {
+                Some(jwks) => jwks,
+                None => {
+                    let jwks: JwkSet = self.http.get(&metadata.jwks_uri).send().await?.error_for_status()?.json().await?;
+                    if let Ok(mut cached) = provider.jwks.write() {
+                        *cached = Some(jwks.clone());
+                    }
+                    jwks
+                }
+            };
+            let jwk = match kid {
+                Some(kid) => jwks.find(kid),
+                // Without a kid only an unambiguous key set will do
+                None if jwks.keys.len() == 1 => jwks.keys.first(),
+                None => None,
+            };
+            if let Some(jwk) = jwk {
+                return Ok(DecodingKey::from_jwk(jwk)?);
+            }
+        }
+        Err(OidcError::UnknownKey)
+    }
+}
+
+impl PendingLogin {
+    /// A fresh PKCE verifier and nonce for a login at `provider`.
+    pub fn new(provider: String) -> Self {
+        PendingLogin {
+            provider,
+            verifier: random_token(32),
+            nonce: random_token(16),
+        }
+    }
+}
+
+fn random_token(bytes: usize) -> String {
+    let mut buffer = vec![0u8; bytes];
+    OsRng.fill_bytes(&mut buffer);
+    URL_SAFE_NO_PAD.encode(buffer)
+}
+
+fn pkce_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
@@ -0,0 +1,217 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use std::collections::HashSet;
+use std::sync::OnceLock;
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use crate::utils::{constant_time_eq, env_or};
+
+/// Bundled list of common and breached passwords that `check_policy` rejects.
+const COMMON_PASSWORDS: &str = include_str!("common_passwords.txt");
+/// Longer passwords are rejected, hashing them would only cost time.
+const MAX_LENGTH: usize = 1024;
+
+/// Argon2id cost parameters and the password policy. Argon2 defaults follow
+/// the OWASP recommendation (19 MiB, 2 iterations, 1 lane).
+#[derive(Debug, Clone)]
+pub struct PasswordConfig {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    /// Minimum length of new passwords, in characters.
+    pub min_length: usize,
+}
+
+#[derive(Error, Debug)]
+pub enum PasswordError {
+    #[error("Invalid Argon2 parameters: {0}")]
+    Params(#[from] argon2::Error),
+    #[error("Password hashing failed: {0}")]
+    Hash(#[from] argon2::password_hash::Error),
+    #[error("Password hashing task was aborted")]
+    Aborted,
+}
+
+/// Why a new password was refused.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum PolicyViolation {
+    #[error("Password must be at least {0} characters long")]
+    TooShort(usize),
+    #[error("Password must be at most {MAX_LENGTH} characters long")]
+    TooLong,
+    #[error("Password is too common")]
+    Common,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verification {
+    Invalid,
+    Valid,
+    /// The password matched, but the stored hash is a legacy SHA-256 hash or
+    /// uses outdated Argon2 parameters and should be replaced.
+    NeedsRehash,
+}
+
+impl PasswordConfig {
+    pub fn from_env() -> Self {
+        PasswordConfig {
+            memory_kib: env_or("ARGON2_MEMORY_KIB", Params::DEFAULT_M_COST),
+            iterations: env_or("ARGON2_ITERATIONS", Params::DEFAULT_T_COST),
+            parallelism: env_or("ARGON2_PARALLELISM", Params::DEFAULT_P_COST),
+            min_length: env_or("PASSWORD_MIN_LENGTH", 8),
+        }
+    }
+
+    fn params(&self) -> Result<Params, PasswordError> {
+        Ok(Params::new(self.memory_kib, self.iterations, self.parallelism, None)?)
+    }
+
+    fn argon2(&self) -> Result<Argon2<'static>, PasswordError> {
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params()?))
+    }
+}
+
+/// Checks a password a user is about to set. Existing passwords are not
+/// checked at login, so a stricter policy only applies from the next change.
+pub fn check_policy(config: &PasswordConfig, password: &str) -> Result<(), PolicyViolation> {
+    let length = password.chars().count();
+    if length < config.min_length {
+        return Err(PolicyViolation::TooShort(config.min_length));
+    }
+    if length > MAX_LENGTH {
+        return Err(PolicyViolation::TooLong);
+    }
+    if common_passwords().contains(password.to_lowercase().as_str()) {
+        return Err(PolicyViolation::Common);
+    }
+    Ok(())
+}
+
+fn common_passwords() -> &'static HashSet<String> {
+    static COMMON: OnceLock<HashSet<String>> = OnceLock::new();
+    COMMON.get_or_init(|| {
+        COMMON_PASSWORDS
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_lowercase)
+            .collect()
+    })
+}
+
+/// Hashes `password` with Argon2id and returns the PHC string to store in `User.hashed`.
+pub fn hash_password(config: &PasswordConfig, password: &str) -> Result<String, PasswordError> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = config.argon2()?.hash_password(password.as_bytes(), &salt)?;
+    Ok(hash.to_string())
+}
+
+/// Checks `password` against a stored hash. `salt` is only used for legacy
+/// SHA-256 hashes, Argon2 PHC strings carry their own salt.
+pub fn verify_password(config: &PasswordConfig, password: &str, hashed: &str, salt: &str) -> Result<Verification, PasswordError> {
+    if !is_phc_string(hashed) {
+        let legacy = legacy_hash(password, salt);
+        return Ok(if constant_time_eq(legacy.as_bytes(), hashed.as_bytes()) {
+            Verification::NeedsRehash
+        } else {
+            Verification::Invalid
+        });
+    }
+
+    let parsed = PasswordHash::new(hashed)?;
+    match config.argon2()?.verify_password(password.as_bytes(), &parsed) {
+        Ok(()) => {},
+        Err(argon2::password_hash::Error::Password) => return Ok(Verification::Invalid),
+        Err(e) => return Err(e.into()),
+    }
+
+    let current = config.params()?;
+    let outdated = parsed.algorithm != Algorithm::Argon2id.ident()
+        || Params::try_from(&parsed).map_or(true, |stored| {
+            stored.m_cost() != current.m_cost()
+                || stored.t_cost() != current.t_cost()
+                || stored.p_cost() != current.p_cost()
+        });
+    Ok(if outdated { Verification::NeedsRehash } else { Verification::Valid })
+}
+
+/// Verifies `password` against a throwaway hash with the current parameters,
+/// so a login for an unknown account takes as long as one with a wrong password.
+pub fn verify_dummy(config: &PasswordConfig, password: &str) -> Result<(), PasswordError> {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    let hashed = match DUMMY.get() {
+        Some(hashed) => hashed,
+        None => {
+            let hashed = hash_password(config, "dummy password")?;
+            DUMMY.get_or_init(|| hashed)
+        }
+    };
+    verify_password(config, password, hashed, "")?;
+    Ok(())
+}
+
+fn is_phc_string(hashed: &str) -> bool {
+    hashed.starts_with('$')
+}
+
+fn legacy_hash(password: &str, salt: &str) -> String {
+    let salted = format!("{}{}", password, salt);
+    let mut hasher = Sha256::new();
+    hasher.update(salted.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap parameters, the tests hash a lot.
+    fn config() -> PasswordConfig {
+        PasswordConfig { memory_kib: 256, iterations: 1, parallelism: 1, min_length: 8 }
+    }
+
+    #[test]
+    fn verifies_argon2_hashes() {
+        let config = config();
+        let hashed = hash_password(&config, "correct horse").unwrap();
+        assert!(hashed.starts_with("$argon2id$"));
+        assert_eq!(verify_password(&config, "correct horse", &hashed, "").unwrap(), Verification::Valid);
+        assert_eq!(verify_password(&config, "wrong horse", &hashed, "").unwrap(), Verification::Invalid);
+    }
+
+    #[test]
+    fn asks_to_rehash_legacy_hashes() {
+        let config = config();
+        let hashed = legacy_hash("correct horse", "pepper");
+        assert_eq!(verify_password(&config, "correct horse", &hashed, "pepper").unwrap(), Verification::NeedsRehash);
+        assert_eq!(verify_password(&config, "wrong horse", &hashed, "pepper").unwrap(), Verification::Invalid);
+        assert_eq!(verify_password(&config, "correct horse", &hashed, "salt").unwrap(), Verification::Invalid);
+    }
+
+    #[test]
+    fn asks_to_rehash_outdated_parameters() {
+        let old = config();
+        let hashed = hash_password(&old, "correct horse").unwrap();
+        let current = PasswordConfig { iterations: 2, ..old };
+        assert_eq!(verify_password(&current, "correct horse", &hashed, "").unwrap(), Verification::NeedsRehash);
+        assert_eq!(verify_password(&current, "wrong horse", &hashed, "").unwrap(), Verification::Invalid);
+    }
+
+    #[test]
+    fn dummy_verification_succeeds_for_any_password() {
+        assert!(verify_dummy(&config(), "anything").is_ok());
+        assert!(verify_dummy(&config(), "").is_ok());
+    }
+
+    #[test]
+    fn enforces_the_policy() {
+        let config = config();
+        assert_eq!(check_policy(&config, "short"), Err(PolicyViolation::TooShort(8)));
+        assert_eq!(check_policy(&config, &"x".repeat(MAX_LENGTH + 1)), Err(PolicyViolation::TooLong));
+        assert_eq!(check_policy(&config, "PASSWORD"), Err(PolicyViolation::Common));
+        assert_eq!(check_policy(&config, "correct horse battery"), Ok(()));
+        // Length counts characters, not bytes
+        assert_eq!(check_policy(&config, "äöüäöü"), Err(PolicyViolation::TooShort(8)));
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::utils::env_or;
+
+/// A token bucket: up to `capacity` requests at once, refilled evenly so that
+/// `capacity` requests are available again after `period`.
+/// Parsed from `"<capacity>/<period in seconds>"`, e.g. `"5/60"`.
+#[derive(Debug, Clone, Copy)]
+pub struct BucketPolicy {
+    pub capacity: u32,
+    pub period: Duration,
+}
+
+/// The rate limited operations. Each has its own buckets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    Login,
+    /// Anything that sends an email: `/pre-register` and `/pre-reset`.
+    SendEmail,
+    /// Redeeming an emailed code: `/register` and `/reset`.
+    RedeemCode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Scope {
+    Ip,
+    Account,
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    pub login_ip: BucketPolicy,
+    pub login_account: BucketPolicy,
+    pub email_ip: BucketPolicy,
+    pub email_account: BucketPolicy,
+    pub redeem_ip: BucketPolicy,
+    /// Failed logins that are tolerated before backoff starts.
+    pub backoff_after: u32,
+    /// Failed logins after which the account is locked.
+    pub lockout_after: u32,
+    pub lockout: Duration,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Failures {
+    count: u32,
+    blocked_until: Instant,
+    last_failure: Instant,
+}
+
+/// In-memory rate limits and failed login tracking. Limits apply per instance.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<HashMap<(Action, Scope, String), Bucket>>,
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+#[derive(Debug)]
+pub struct InvalidPolicy;
+
+impl FromStr for BucketPolicy {
+    type Err = InvalidPolicy;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (capacity, period) = value.split_once('/').ok_or(InvalidPolicy)?;
+        let capacity: u32 = capacity.trim().parse().map_err(|_| InvalidPolicy)?;
+        let period: u64 = period.trim().parse().map_err(|_| InvalidPolicy)?;
+        if capacity == 0 || period == 0 {
+            return Err(InvalidPolicy);
+        }
+        Ok(BucketPolicy { capacity, period: Duration::from_secs(period) })
+    }
+}
+
+impl BucketPolicy {
+    const fn new(capacity: u32, period_secs: u64) -> Self {
+        BucketPolicy { capacity, period: Duration::from_secs(period_secs) }
+    }
+
+    fn refill_per_sec(&self) -> f64 {
+        self.capacity as f64 / self.period.as_secs_f64()
+    }
+}
+
+impl RateLimitConfig {
+    pub fn from_env() -> Self {
+        RateLimitConfig {
+            login_ip: env_or("RATE_LIMIT_LOGIN_IP", BucketPolicy::new(20, 60)),
+            login_account: env_or("RATE_LIMIT_LOGIN_ACCOUNT", BucketPolicy::new(10, 60)),
+            email_ip: env_or("RATE_LIMIT_EMAIL_IP", BucketPolicy::new(10, 3600)),
+            email_account: env_or("RATE_LIMIT_EMAIL_ACCOUNT", BucketPolicy::new(3, 3600)),
+            redeem_ip: env_or("RATE_LIMIT_CODE_IP", BucketPolicy::new(10, 60)),
+            backoff_after: env_or("LOGIN_BACKOFF_AFTER", 3),
+            lockout_after: env_or("LOGIN_LOCKOUT_AFTER", 10),
+            lockout: Duration::from_secs(60 * env_or("LOGIN_LOCKOUT_MINS", 15)),
+        }
+    }
+
+    fn policy(&self, action: Action, scope: Scope) -> Option<BucketPolicy> {
+        match (action, scope) {
+            (Action::Login, Scope::Ip) => Some(self.login_ip),
+            (Action::Login, Scope::Account) => Some(self.login_account),
+            (Action::SendEmail, Scope::Ip) => Some(self.email_ip),
+            (Action::SendEmail, Scope::Account) => Some(self.email_account),
+            (Action::RedeemCode, Scope::Ip) => Some(self.redeem_ip),
+            (Action::RedeemCode, Scope::Account) => None,
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for `action` from the caller's IP bucket and, if given,
+    /// the account's bucket. On rejection returns how long to wait.
+    pub fn check(&self, action: Action, ip: &str, account: Option<&str>) -> Result<(), Duration> {
+        let mut buckets = match self.buckets.lock() {
+            Ok(buckets) => buckets,
+            // Failing open is preferable to locking everyone out
+            Err(_) => return Ok(()),
+        };
+        let now = Instant::now();
+        let mut keys = vec![(Scope::Ip, ip)];
+        if let Some(account) = account {
+            keys.push((Scope::Account, account));
+        }
+
+        let mut wait = Duration::ZERO;
+        for (scope, key) in &keys {
+            let Some(policy) = self.config.policy(action, *scope) else { continue };
+            let bucket = buckets.entry((action, *scope, key.to_lowercase())).or_insert(Bucket {
+                tokens: policy.capacity as f64,
+                updated: now,
+            });
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * policy.refill_per_sec()).min(policy.capacity as f64);
+            bucket.updated = now;
+            if bucket.tokens < 1.0 {
+                wait = wait.max(Duration::from_secs_f64((1.0 - bucket.tokens) / policy.refill_per_sec()));
+            }
+        }
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        // Only take tokens once every bucket agreed
+        for (scope, key) in &keys {
+            if let Some(bucket) = buckets.get_mut(&(action, *scope, key.to_lowercase())) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+
+    /// How long logins to `account` are still blocked after failed attempts.
+    pub fn login_blocked(&self, account: &str) -> Option<Duration> {
+        let failures = self.failures.lock().ok()?;
+        let blocked_until = failures.get(&account.to_lowercase())?.blocked_until;
+        blocked_until.checked_duration_since(Instant::now()).filter(|wait| !wait.is_zero())
+    }
+
+    /// Records a failed login. Past `backoff_after` failures every further one
+    /// doubles the wait before the next attempt, at `lockout_after` the
+    /// account is locked for `lockout`.
+    pub fn login_failed(&self, account: &str) {
+        let Ok(mut failures) = self.failures.lock() else { return };
+        let now = Instant::now();
+        let entry = failures.entry(account.to_lowercase()).or_insert(Failures {
+            count: 0,
+            blocked_until: now,
+            last_failure: now,
+        });
+        // A lockout's worth of quiet wipes the slate
+        if now.duration_since(entry.last_failure) > self.config.lockout {
+            entry.count = 0;
+        }
+        entry.count += 1;
+        entry.last_failure = now;
+        if entry.count >= self.config.lockout_after {
+            entry.blocked_until = now + self.config.lockout;
+        } else if entry.count > self.config.backoff_after {
+            let exponent = (entry.count - self.config.backoff_after - 1).min(16);
+            let backoff = Duration::from_secs(1 << exponent).min(self.config.lockout);
+            entry.blocked_until = now + backoff;
+        }
+    }
+
+    pub fn login_succeeded(&self, account: &str) {
+        if let Ok(mut failures) = self.failures.lock() {
+            failures.remove(&account.to_lowercase());
+        }
+    }
+
+    /// Forgets full buckets and stale failure records so memory stays bounded.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        if let Ok(mut buckets) = self.buckets.lock() {
+            buckets.retain(|(action, scope, _), bucket| {
+                self.config.policy(*action, *scope).is_some_and(|policy| {
+                    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+                    bucket.tokens + elapsed * policy.refill_per_sec() < policy.capacity as f64
+                })
+            });
+        }
+        if let Ok(mut failures) = self.failures.lock() {
+            failures.retain(|_, entry| now.duration_since(entry.last_failure) <= self.config.lockout);
+        }
+    }
+}
+
+/// Periodically prunes the limiter for as long as the server runs.
+pub fn spawn_prune_task(limiter: Arc<RateLimiter>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            limiter.prune();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> RateLimitConfig {
+        RateLimitConfig {
+            login_ip: BucketPolicy::new(5, 60),
+            login_account: BucketPolicy::new(2, 60),
+            email_ip: BucketPolicy::new(3, 3600),
+            email_account: BucketPolicy::new(1, 3600),
+            redeem_ip: BucketPolicy::new(2, 60),
+            backoff_after: 2,
+            lockout_after: 5,
+            lockout: Duration::from_secs(900),
+        }
+    }
+
+    #[test]
+    fn parses_policies() {
+        let policy: BucketPolicy = "5/60".parse().unwrap();
+        assert_eq!(policy.capacity, 5);
+        assert_eq!(policy.period, Duration::from_secs(60));
+        assert!(" 5 / 60 ".parse::<BucketPolicy>().is_ok());
+        for invalid in ["", "5", "0/60", "5/0", "a/60", "5/-1"] {
+            assert!(invalid.parse::<BucketPolicy>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn empties_a_bucket_and_says_how_long_to_wait() {
+        let limiter = RateLimiter::new(config());
+        assert!(limiter.check(Action::RedeemCode, "1.2.3.4", None).is_ok());
+        assert!(limiter.check(Action::RedeemCode, "1.2.3.4", None).is_ok());
+        let wait = limiter.check(Action::RedeemCode, "1.2.3.4", None).unwrap_err();
+        // 2 per minute refill one token every 30 seconds
+        assert!(wait > Duration::from_secs(29) && wait <= Duration::from_secs(30), "{:?}", wait);
+        // Other addresses have their own bucket
+        assert!(limiter.check(Action::RedeemCode, "5.6.7.8", None).is_ok());
+    }
+
+    #[test]
+    fn limits_accounts_across_addresses_ignoring_case() {
+        let limiter = RateLimiter::new(config());
+        assert!(limiter.check(Action::SendEmail, "1.1.1.1", Some("a@example.com")).is_ok());
+        assert!(limiter.check(Action::SendEmail, "2.2.2.2", Some("A@Example.com")).is_err());
+        assert!(limiter.check(Action::SendEmail, "2.2.2.2", Some("b@example.com")).is_ok());
+    }
+
+    #[test]
+    fn rejected_requests_take_no_tokens() {
+        let limiter = RateLimiter::new(config());
+        assert!(limiter.check(Action::SendEmail, "1.1.1.1", Some("a@example.com")).is_ok());
+        // The account bucket refuses, so the IP bucket keeps its tokens
+        for _ in 0..5 {
+            assert!(limiter.check(Action::SendEmail, "1.1.1.1", Some("a@example.com")).is_err());
+        }
+        assert!(limiter.check(Action::SendEmail, "1.1.1.1", Some("b@example.com")).is_ok());
+        assert!(limiter.check(Action::SendEmail, "1.1.1.1", Some("c@example.com")).is_ok());
+    }
+
+    #[test]
+    fn backs_off_and_locks_out_after_failed_logins() {
+        let limiter = RateLimiter::new(config());
+        limiter.login_failed("a@example.com");
+        limiter.login_failed("a@example.com");
+        assert!(limiter.login_blocked("a@example.com").is_none());
+
+        limiter.login_failed("a@example.com");
+        let backoff = limiter.login_blocked("A@example.com").unwrap();
+        assert!(backoff <= Duration::from_secs(1), "{:?}", backoff);
+        limiter.login_failed("a@example.com");
+        let backoff = limiter.login_blocked("a@example.com").unwrap();
+        assert!(backoff > Duration::from_secs(1) && backoff <= Duration::from_secs(2), "{:?}", backoff);
+
+        limiter.login_failed("a@example.com");
+        let lockout = limiter.login_blocked("a@example.com").unwrap();
+        assert!(lockout > Duration::from_secs(890), "{:?}", lockout);
+        assert!(limiter.login_blocked("b@example.com").is_none());
+    }
+
+    #[test]
+    fn a_successful_login_clears_failures() {
+        let limiter = RateLimiter::new(config());
+        for _ in 0..5 {
+            limiter.login_failed("a@example.com");
+        }
+        assert!(limiter.login_blocked("a@example.com").is_some());
+        limiter.login_succeeded("a@example.com");
+        assert!(limiter.login_blocked("a@example.com").is_none());
+    }
+}
@@ -0,0 +1,628 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use uuid::Uuid;
+use crate::db::{CouchDB, DocRef};
+use crate::jwt::{AccessClaims, Denylist, JwtKeys, SessionMode};
+use crate::store::{SessionRecords, StoreError, UserStore};
+use crate::utils::{constant_time_eq, env_or};
+
+const SESSIONS_DB: &str = "sessions";
+const PURGE_BATCH_SIZE: usize = 500;
+const MAX_SESSIONS_LISTED: usize = 1000;
+const MAX_DENYLIST_SYNCED: usize = 10000;
+/// How often marking a refresh token as used is retried when another write got in between.
+const ROTATE_ATTEMPTS: u32 = 5;
+/// `last_used` is only written back when it is older than this, so an active
+/// session doesn't cause a CouchDB write on every request.
+const LAST_USED_RESOLUTION: chrono::Duration = chrono::Duration::minutes(1);
+
+/// One access token. Refreshing replaces it with a new token in the same
+/// family, a family being everything issued from a single login.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionToken {
+    #[serde(rename = "_id")]
+    pub token: Uuid,
+    #[serde(rename = "_rev", default, skip_serializing_if = "Option::is_none")]
+    pub rev: Option<String>,
+    pub user_id: String,
+    #[serde(default)]
+    pub family_id: Uuid,
+    /// When the family was created, i.e. when the user logged in.
+    pub created_at: DateTime<Utc>,
+    /// When this access token stops being accepted.
+    pub expires_at: DateTime<Utc>,
+    /// When the family ends, no matter how often it is refreshed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub absolute_expires_at: Option<DateTime<Utc>>,
+    pub last_used: DateTime<Utc>,
+    pub device_info: String,
+    #[serde(default)]
+    pub ip: String,
+    /// SHA-256 of the secret half of this token's refresh token.
+    #[serde(default)]
+    refresh_hash: String,
+    /// Set once the refresh token has been exchanged. Presenting it again means it leaked.
+    #[serde(default)]
+    pub rotated: bool,
+    pub is_revoked: bool,
+}
+
+/// A freshly issued access token together with its refresh token. The refresh
+/// token is only ever known here, the store keeps a hash of it.
+pub struct IssuedSession {
+    pub session: SessionToken,
+    /// The session id, or a signed JWT in `SessionMode::Jwt`.
+    pub access_token: String,
+    pub refresh_token: String,
+}
+
+/// Why `get_valid` turned a token down.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// Unknown, expired, rotated or revoked.
+    Invalid,
+    /// Revoked because the account was suspended.
+    Disabled,
+}
+
+#[derive(Error, Debug)]
+pub enum SessionError {
+    #[error("Storage error: {0}")]
+    Store(#[from] StoreError),
+    #[error("Access token could not be signed: {0}")]
+    Signing(#[from] jsonwebtoken::errors::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum RefreshError {
+    #[error("Unknown or malformed refresh token")]
+    Invalid,
+    #[error("Session expired")]
+    Expired,
+    #[error("Refresh token was already used, session family revoked")]
+    Reused,
+    #[error("Account disabled")]
+    Disabled,
+    #[error("Storage error: {0}")]
+    Store(#[from] StoreError),
+    #[error("Access token could not be signed: {0}")]
+    Signing(#[from] jsonwebtoken::errors::Error),
+}
+
+impl From<SessionError> for RefreshError {
+    fn from(e: SessionError) -> Self {
+        match e {
+            SessionError::Store(e) => RefreshError::Store(e),
+            SessionError::Signing(e) => RefreshError::Signing(e),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    pub mode: SessionMode,
+    /// How long an access token is accepted before it has to be refreshed.
+    pub access_ttl: chrono::Duration,
+    /// A session that has not been used for this long can no longer be refreshed.
+    pub idle_timeout: chrono::Duration,
+    /// How long a login can be kept alive by refreshing.
+    pub lifetime: chrono::Duration,
+    /// How long a stored session is trusted before it is read again.
+    /// Bounds how long a logout on another instance can go unnoticed.
+    pub cache_ttl: Duration,
+    /// How often expired sessions are deleted.
+    pub purge_interval: Duration,
+    /// How often the JWT denylist picks up revocations made on other instances.
+    pub denylist_sync_interval: Duration,
+}
+
+struct CachedSession {
+    session: SessionToken,
+    fetched_at: Instant,
+}
+
+/// Sessions are stored in `SessionRecords`, in production the CouchDB
+/// `sessions` database, so they survive restarts and are shared between
+/// instances. Reads go through a short-lived in-memory cache. In
+/// `SessionMode::Jwt` access tokens are checked against the signing keys and
+/// the denylist only; the records are still used for refresh tokens and the
+/// list of sessions.
+pub struct SessionStore {
+    records: Arc<dyn SessionRecords>,
+    /// Where the account behind a session is looked up.
+    users: Arc<dyn UserStore>,
+    config: SessionConfig,
+    cache: RwLock<HashMap<String, CachedSession>>,
+    jwt: Option<JwtKeys>,
+    denylist: Denylist,
+}
+
+impl SessionConfig {
+    pub fn from_env() -> Self {
+        SessionConfig {
+            mode: env_or("SESSION_MODE", SessionMode::Opaque),
+            access_ttl: chrono::Duration::minutes(env_or("ACCESS_TOKEN_TTL_MINS", 15)),
+            idle_timeout: chrono::Duration::minutes(env_or("SESSION_IDLE_TIMEOUT_MINS", 120)),
+            lifetime: chrono::Duration::hours(env_or("SESSION_LIFETIME_HOURS", 24)),
+            cache_ttl: Duration::from_secs(env_or("SESSION_CACHE_TTL_SECS", 30)),
+            purge_interval: Duration::from_secs(env_or("SESSION_PURGE_INTERVAL_SECS", 3600)),
+            denylist_sync_interval: Duration::from_secs(env_or("JWT_DENYLIST_SYNC_SECS", 30)),
+        }
+    }
+}
+
+impl SessionStore {
+    /// `jwt` holds the signing keys and is required in `SessionMode::Jwt`.
+    pub fn new(records: Arc<dyn SessionRecords>, users: Arc<dyn UserStore>, config: SessionConfig, jwt: Option<JwtKeys>) -> Self {
+        SessionStore {
+            records,
+            users,
+            config,
+            cache: RwLock::new(HashMap::new()),
+            jwt,
+            denylist: Denylist::new(),
+        }
+    }
+
+    pub async fn init(&self) -> Result<(), StoreError> {
+        self.records.init().await
+    }
+
+    /// Starts a new session family for a user who just logged in.
+    pub async fn create(&self, user_id: String, device_info: String, ip: String) -> Result<IssuedSession, SessionError> {
+        let now = Utc::now();
+        let token = Uuid::new_v4();
+        let mut session = SessionToken {
+            token,
+            rev: None,
+            user_id,
+            family_id: token,
+            created_at: now,
+            expires_at: now,
+            absolute_expires_at: Some(now + self.config.lifetime),
+            last_used: now,
+            device_info,
+            ip,
+            refresh_hash: String::new(),
+            rotated: false,
+            is_revoked: false,
+        };
+        let refresh_token = self.arm(&mut session, now);
+        self.save(&mut session).await?;
+        let access_token = self.access_token(&session).await?;
+        Ok(IssuedSession { session, access_token, refresh_token })
+    }
+
+    /// Exchanges a refresh token for a new access and refresh token. Each
+    /// refresh token works once, presenting it a second time revokes the family.
+    pub async fn refresh(&self, refresh_token: &str, ip: String) -> Result<IssuedSession, RefreshError> {
+        let (id, secret) = refresh_token.split_once('.').ok_or(RefreshError::Invalid)?;
+        let id = Uuid::parse_str(id).map_err(|_| RefreshError::Invalid)?;
+        let mut previous = match self.records.get_session(&id.to_string()).await {
+            Ok(session) => session,
+            Err(StoreError::NotFound) => return Err(RefreshError::Invalid),
+            Err(e) => return Err(e.into()),
+        };
+        if previous.refresh_hash.is_empty() || !constant_time_eq(hash_secret(secret).as_bytes(), previous.refresh_hash.as_bytes()) {
+            return Err(RefreshError::Invalid);
+        }
+        if previous.rotated {
+            let revoked = self.revoke_family(&previous.user_id, previous.family_id).await?;
+            println!("sessions: refresh token reused, revoked {} sessions of {}", revoked, previous.user_id);
+            return Err(RefreshError::Reused);
+        }
+        // Suspending revokes the sessions, this tells the client why
+        match self.users.get_user(&previous.user_id).await {
+            Ok(user) if user.disabled => return Err(RefreshError::Disabled),
+            Ok(_) => {},
+            Err(StoreError::NotFound) => return Err(RefreshError::Invalid),
+            Err(e) => return Err(e.into()),
+        }
+        if previous.is_revoked {
+            return Err(RefreshError::Invalid);
+        }
+        let now = Utc::now();
+        let ended = previous.absolute_expires_at.is_none_or(|end| end <= now);
+        if ended || now - previous.last_used > self.config.idle_timeout {
+            return Err(RefreshError::Expired);
+        }
+
+        let mut attempt = 1;
+        loop {
+            previous.rotated = true;
+            match self.save(&mut previous).await {
+                Ok(()) => break,
+                Err(StoreError::Conflict) if attempt < ROTATE_ATTEMPTS => {
+                    // Usually `touch` recording a request made with the access token.
+                    // Only another exchange of the same refresh token is reuse.
+                    previous = self.records.get_session(&id.to_string()).await?;
+                    if previous.rotated {
+                        self.revoke_family(&previous.user_id, previous.family_id).await?;
+                        return Err(RefreshError::Reused);
+                    }
+                    if previous.is_revoked {
+                        return Err(RefreshError::Invalid);
+                    }
+                    attempt += 1;
+                },
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let mut session = SessionToken {
+            token: Uuid::new_v4(),
+            rev: None,
+            last_used: now,
+            ip,
+            rotated: false,
+            ..previous
+        };
+        let refresh_token = self.arm(&mut session, now);
+        self.save(&mut session).await?;
+        let access_token = self.access_token(&session).await?;
+        Ok(IssuedSession { session, access_token, refresh_token })
+    }
+
+    /// The token a client authenticates `session` with. Signed tokens carry
+    /// the user's roles as they are right now.
+    async fn access_token(&self, session: &SessionToken) -> Result<String, SessionError> {
+        let Some(keys) = &self.jwt else { return Ok(session.token.to_string()) };
+        let user = self.users.get_user(&session.user_id).await?;
+        let claims = AccessClaims {
+            sub: session.user_id.clone(),
+            jti: session.token,
+            sid: session.family_id,
+            roles: user.roles,
+            iat: Utc::now().timestamp(),
+            exp: session.expires_at.timestamp(),
+        };
+        Ok(keys.sign(&claims)?)
+    }
+
+    /// Gives `session` a fresh access token lifetime and refresh secret, returns the refresh token.
+    fn arm(&self, session: &mut SessionToken, now: DateTime<Utc>) -> String {
+        let access_end = now + self.config.access_ttl;
+        session.expires_at = session.absolute_expires_at.map_or(access_end, |end| end.min(access_end));
+        let secret = Uuid::new_v4().simple().to_string();
+        session.refresh_hash = hash_secret(&secret);
+        format!("{}.{}", session.token, secret)
+    }
+
+    /// Stores `session` and refreshes the cached copy.
+    async fn save(&self, session: &mut SessionToken) -> Result<(), StoreError> {
+        let rev = self.records.put_session(session).await?;
+        session.rev = Some(rev);
+        if session.is_revoked {
+            self.denylist.insert(session.token, session.expires_at);
+        }
+        self.cache_insert(session.clone());
+        Ok(())
+    }
+
+    /// Records that `session` was just used.
+    /// Not written in `SessionMode::Jwt`, where a session counts as used when it is refreshed.
+    pub async fn touch(&self, mut session: SessionToken) -> SessionToken {
+        let now = Utc::now();
+        if self.jwt.is_some() || now - session.last_used < LAST_USED_RESOLUTION {
+            return session;
+        }
+        session.last_used = now;
+        if let Err(e) = self.save(&mut session).await {
+            // Most likely a conflicting write from another request, which is just as recent
+            println!("sessions: last_used not updated: {:?}", e);
+        }
+        session
+    }
+
+    /// The current session of every login of `user_id` that has not ended or been revoked.
+    pub async fn list_for_user(&self, user_id: &str) -> Result<Vec<SessionToken>, StoreError> {
+        self.records.list_active_sessions(user_id, Utc::now()).await
+    }
+
+    /// Marks a session as revoked. The document is kept until it expires so it
+    /// still shows where the account was used.
+    pub async fn revoke(&self, token: &str) -> Result<(), StoreError> {
+        // Read past the cache, the write needs the current revision
+        let mut session = self.records.get_session(token).await?;
+        session.is_revoked = true;
+        self.save(&mut session).await
+    }
+
+    /// Revokes every login of `user_id` except the family `keep`, returns how
+    /// many logins were revoked.
+    pub async fn revoke_all_for_user(&self, user_id: &str, keep: Option<Uuid>) -> Result<usize, StoreError> {
+        let mut revoked = 0;
+        for session in self.list_for_user(user_id).await? {
+            if Some(session.family_id) == keep {
+                continue;
+            }
+            self.revoke_family(user_id, session.family_id).await?;
+            revoked += 1;
+        }
+        Ok(revoked)
+    }
+
+    /// Moves every active session of `from` to `to`, after the user changed
+    /// their email. Returns how many were moved.
+    pub async fn reassign_user(&self, from: &str, to: &str) -> Result<usize, StoreError> {
+        let mut moved = 0;
+        for mut session in self.list_for_user(from).await? {
+            session.user_id = to.to_string();
+            self.save(&mut session).await?;
+            moved += 1;
+        }
+        Ok(moved)
+    }
+
+    /// Revokes every token ever issued from the login of `user_id` that started
+    /// `family_id`, so a refresh racing with the revocation can't keep it alive.
+    /// Returns how many tokens were revoked.
+    pub async fn revoke_family(&self, user_id: &str, family_id: Uuid) -> Result<usize, StoreError> {
+        let family = self.records.list_family(user_id, family_id).await?;
+        let mut revoked = 0;
+        for mut session in family {
+            session.is_revoked = true;
+            self.save(&mut session).await?;
+            revoked += 1;
+        }
+        Ok(revoked)
+    }
+
+    /// Looks up a session in the cache, falling back to the records.
+    pub async fn get(&self, token: &str) -> Option<SessionToken> {
+        // Anything that isn't a UUID can't be a token, and must not end up in a CouchDB URL
+        let token = Uuid::parse_str(token).ok()?.to_string();
+        if let Some(session) = self.cached(&token) {
+            return Some(session);
+        }
+        match self.records.get_session(&token).await {
+            Ok(session) => {
+                self.cache_insert(session.clone());
+                Some(session)
+            },
+            Err(_) => {
+                self.cache_remove(&token);
+                None
+            }
+        }
+    }
+
+    /// Returns the session only if it exists, has not expired and was not revoked.
+    /// In `SessionMode::Jwt` session ids issued before the switch are still looked up.
+    pub async fn get_valid(&self, token: &str) -> Result<SessionToken, Rejection> {
+        if let Some(keys) = &self.jwt {
+            if Uuid::parse_str(token).is_err() {
+                let claims = keys.verify(token).ok_or(Rejection::Invalid)?;
+                if self.denylist.contains(&claims.jti) {
+                    return Err(self.rejection(&claims.sub).await);
+                }
+                return Ok(SessionToken::from_claims(claims));
+            }
+        }
+        let session = self.get(token).await.ok_or(Rejection::Invalid)?;
+        if session.is_valid() {
+            Ok(session)
+        } else if session.is_revoked {
+            Err(self.rejection(&session.user_id).await)
+        } else {
+            Err(Rejection::Invalid)
+        }
+    }
+
+    /// Why a revoked token of `user_id` is refused. Only revoked tokens get
+    /// here, so valid requests don't pay for the lookup.
+    async fn rejection(&self, user_id: &str) -> Rejection {
+        match self.users.get_user(user_id).await {
+            Ok(user) if user.disabled => Rejection::Disabled,
+            _ => Rejection::Invalid,
+        }
+    }
+
+    /// Ends a session on logout. In `SessionMode::Jwt` the session is revoked
+    /// instead, so the other instances learn to reject its access token.
+    pub async fn remove(&self, token: &str) -> Result<(), StoreError> {
+        if self.jwt.is_some() {
+            return self.revoke(token).await;
+        }
+        self.cache_remove(token);
+        let stored = self.records.get_session(token).await?;
+        self.records.delete_session(&stored).await
+    }
+
+    /// Deletes sessions whose family has ended from the records and the
+    /// cache, returns how many were removed. Rotated tokens are kept until
+    /// then so refresh token reuse can still be detected.
+    pub async fn purge_expired(&self) -> Result<usize, StoreError> {
+        let now = Utc::now();
+        if let Ok(mut cache) = self.cache.write() {
+            cache.retain(|_, cached| cached.session.ends_at() > now);
+        }
+        self.records.purge_sessions(now).await
+    }
+
+    /// Loads revocations made by other instances into the denylist.
+    pub async fn sync_denylist(&self) -> Result<(), StoreError> {
+        let now = Utc::now();
+        for (token, expires_at) in self.records.list_revoked(now).await? {
+            self.denylist.insert(token, expires_at);
+        }
+        self.denylist.prune(now);
+        Ok(())
+    }
+
+    fn cached(&self, token: &str) -> Option<SessionToken> {
+        let cache = self.cache.read().ok()?;
+        cache.get(token)
+            .filter(|cached| cached.fetched_at.elapsed() < self.config.cache_ttl)
+            .map(|cached| cached.session.clone())
+    }
+
+    fn cache_insert(&self, session: SessionToken) {
+        if let Ok(mut cache) = self.cache.write() {
+            cache.insert(session.token.to_string(), CachedSession { session, fetched_at: Instant::now() });
+        }
+    }
+
+    fn cache_remove(&self, token: &str) {
+        if let Ok(mut cache) = self.cache.write() {
+            cache.remove(token);
+        }
+    }
+}
+
+/// Periodically deletes expired sessions for as long as the server runs.
+pub fn spawn_purge_task(store: Arc<SessionStore>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(store.config.purge_interval);
+        loop {
+            interval.tick().await;
+            match store.purge_expired().await {
+                Ok(0) => {},
+                Ok(purged) => println!("sessions: purged {} expired sessions", purged),
+                Err(e) => println!("sessions: purge failed: {:?}", e),
+            }
+        }
+    });
+}
+
+/// Keeps the JWT denylist in sync for as long as the server runs. Does nothing
+/// unless access tokens are JWTs.
+pub fn spawn_denylist_task(store: Arc<SessionStore>) {
+    if store.jwt.is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(store.config.denylist_sync_interval);
+        loop {
+            interval.tick().await;
+            if let Err(e) = store.sync_denylist().await {
+                println!("sessions: denylist sync failed: {:?}", e);
+            }
+        }
+    });
+}
+
+fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+#[derive(Deserialize)]
+struct RevokedToken {
+    #[serde(rename = "_id")]
+    token: Uuid,
+    expires_at: DateTime<Utc>,
+}
+
+#[async_trait]
+impl SessionRecords for CouchDB {
+    /// Creates the `sessions` database and its indexes if they are missing.
+    async fn init(&self) -> Result<(), StoreError> {
+        self.ensure_database(SESSIONS_DB).await?;
+        self.ensure_index(SESSIONS_DB, "absolute-expires-at", &["absolute_expires_at"]).await?;
+        self.ensure_index(SESSIONS_DB, "user-id", &["user_id"]).await?;
+        self.ensure_index(SESSIONS_DB, "family-id", &["family_id"]).await?;
+        Ok(self.ensure_index(SESSIONS_DB, "revoked-expires-at", &["is_revoked", "expires_at"]).await?)
+    }
+
+    async fn get_session(&self, token: &str) -> Result<SessionToken, StoreError> {
+        Ok(self.get_doc(SESSIONS_DB, token).await?)
+    }
+
+    async fn put_session(&self, session: &SessionToken) -> Result<String, StoreError> {
+        Ok(self.put_doc(SESSIONS_DB, &session.token.to_string(), session).await?)
+    }
+
+    async fn delete_session(&self, session: &SessionToken) -> Result<(), StoreError> {
+        Ok(self.delete_doc(SESSIONS_DB, &session.token.to_string(), session.rev.as_deref().unwrap_or_default()).await?)
+    }
+
+    async fn list_active_sessions(&self, user_id: &str, now: DateTime<Utc>) -> Result<Vec<SessionToken>, StoreError> {
+        Ok(self.find_docs(SESSIONS_DB, json!({
+            "selector": {
+                "user_id": user_id,
+                "is_revoked": false,
+                "rotated": false,
+                "absolute_expires_at": { "$gt": now },
+            },
+            "limit": MAX_SESSIONS_LISTED,
+        })).await?)
+    }
+
+    async fn list_family(&self, user_id: &str, family_id: Uuid) -> Result<Vec<SessionToken>, StoreError> {
+        Ok(self.find_docs(SESSIONS_DB, json!({
+            "selector": { "family_id": family_id, "user_id": user_id, "is_revoked": false },
+            "limit": MAX_SESSIONS_LISTED,
+        })).await?)
+    }
+
+    async fn list_revoked(&self, now: DateTime<Utc>) -> Result<Vec<(Uuid, DateTime<Utc>)>, StoreError> {
+        let revoked: Vec<RevokedToken> = self.find_docs(SESSIONS_DB, json!({
+            "selector": { "is_revoked": true, "expires_at": { "$gt": now } },
+            "fields": ["_id", "expires_at"],
+            "limit": MAX_DENYLIST_SYNCED,
+        })).await?;
+        Ok(revoked.into_iter().map(|token| (token.token, token.expires_at)).collect())
+    }
+
+    async fn purge_sessions(&self, now: DateTime<Utc>) -> Result<usize, StoreError> {
+        let mut purged = 0;
+        loop {
+            let expired: Vec<DocRef> = self.find_docs(SESSIONS_DB, json!({
+                "selector": { "$or": [
+                    { "absolute_expires_at": { "$lt": now } },
+                    // Sessions created before refresh tokens existed
+                    { "absolute_expires_at": { "$exists": false }, "expires_at": { "$lt": now } },
+                ] },
+                "fields": ["_id", "_rev"],
+                "limit": PURGE_BATCH_SIZE,
+            })).await?;
+            if expired.is_empty() {
+                return Ok(purged);
+            }
+            self.bulk_delete(SESSIONS_DB, &expired).await?;
+            purged += expired.len();
+            if expired.len() < PURGE_BATCH_SIZE {
+                return Ok(purged);
+            }
+        }
+    }
+}
+
+impl SessionToken {
+    /// The session as far as a signed access token describes it. Fields the
+    /// token doesn't carry are left empty.
+    fn from_claims(claims: AccessClaims) -> Self {
+        let issued_at = DateTime::from_timestamp(claims.iat, 0).unwrap_or_default();
+        SessionToken {
+            token: claims.jti,
+            rev: None,
+            user_id: claims.sub,
+            family_id: claims.sid,
+            created_at: issued_at,
+            expires_at: DateTime::from_timestamp(claims.exp, 0).unwrap_or_default(),
+            absolute_expires_at: None,
+            last_used: issued_at,
+            device_info: String::new(),
+            ip: String::new(),
+            refresh_hash: String::new(),
+            rotated: false,
+            is_revoked: false,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        !self.is_revoked && !self.rotated && self.expires_at > Utc::now()
+    }
+
+    /// When the login ends, after which the token is purged.
+    pub fn ends_at(&self) -> DateTime<Utc> {
+        self.absolute_expires_at.unwrap_or(self.expires_at)
+    }
+}
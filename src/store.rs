@@ -0,0 +1,333 @@
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use reqwest::StatusCode;
+use serde_json::Value;
+use thiserror::Error;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+use crate::api_keys::ApiKey;
+use crate::audit::{AuditEntry, AuditQuery};
+use crate::auth::User;
+use crate::codes::OneTimeCode;
+use crate::db::{Deletion, Document, ProjectChange, Revision, RevisionInfo};
+use crate::session::SessionToken;
+use crate::utils::env_or;
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error("Not found")]
+    NotFound,
+    /// The document was created or changed by someone else in the meantime.
+    #[error("Conflict")]
+    Conflict,
+    /// A `DocumentPatch` couldn't be applied to the current data.
+    #[error("Patch failed: {0}")]
+    Patch(#[from] json_patch::PatchError),
+    #[error("CouchDB error: {0}")]
+    Db(reqwest::Error),
+}
+
+/// A change to a project's data, applied with the revision it was read at.
+pub enum DocumentPatch {
+    /// RFC 7396: objects are merged recursively, `null` removes a key.
+    Merge(Value),
+    /// RFC 6902: add, remove, replace, move, copy and test operations, applied all or nothing.
+    Json(json_patch::Patch),
+    /// The whole data, e.g. an earlier revision being restored.
+    Replace(Value),
+}
+
+impl DocumentPatch {
+    pub fn apply(&self, data: &mut Value) -> Result<(), json_patch::PatchError> {
+        match self {
+            DocumentPatch::Merge(patch) => {
+                json_patch::merge(data, patch);
+                Ok(())
+            },
+            DocumentPatch::Json(patch) => json_patch::patch(data, patch),
+            DocumentPatch::Replace(replacement) => {
+                *data = replacement.clone();
+                Ok(())
+            },
+        }
+    }
+}
+
+impl From<reqwest::Error> for StoreError {
+    fn from(e: reqwest::Error) -> Self {
+        match e.status() {
+            Some(StatusCode::NOT_FOUND) => StoreError::NotFound,
+            Some(StatusCode::CONFLICT) => StoreError::Conflict,
+            _ => StoreError::Db(e),
+        }
+    }
+}
+
+/// Project documents, keyed by project id.
+#[async_trait]
+pub trait DocumentStore: Send + Sync {
+    /// Creates whatever indexes the store needs.
+    async fn init(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    async fn get_document(&self, id: &str) -> Result<Document, StoreError>;
+
+    /// Every write to any project from now on, including the trash and
+    /// deletions, as long as the receiver is kept.
+    fn subscribe(&self) -> broadcast::Receiver<ProjectChange>;
+
+    /// Merges `data` into project `id`, or creates it without an owner, and
+    /// returns the stored document. With `if_match` it is only written while
+    /// that is still the current revision, otherwise it fails with `Conflict`.
+    async fn put_document(&self, id: &str, data: Value, if_match: Option<&str>) -> Result<Document, StoreError>;
+
+    /// Applies `patch` to the current data of project `id` and writes the
+    /// result with the revision it was applied to, so a concurrent write is
+    /// never lost. `if_match` works as in `put_document`.
+    async fn patch_document(&self, id: &str, patch: &DocumentPatch, if_match: Option<&str>) -> Result<Document, StoreError>;
+
+    /// Creates project `id` owned by `owner`. Fails with `Conflict` if it already exists.
+    async fn create_document(&self, id: &str, owner: &str, data: Value) -> Result<Document, StoreError>;
+
+    /// Deletes project `id` for good, with its history.
+    async fn delete_document(&self, id: &str) -> Result<(), StoreError>;
+
+    /// Moves project `id` to the trash, or with `None` takes it back out.
+    async fn set_deleted(&self, id: &str, deleted: Option<Deletion>) -> Result<Document, StoreError>;
+
+    /// Deletes projects that were moved to the trash before `before` for good,
+    /// with their history, and returns how many.
+    async fn purge_deleted(&self, before: DateTime<Utc>) -> Result<usize, StoreError>;
+
+    /// Keeps a copy of `doc`, just written by `author`, in the project's history.
+    async fn record_revision(&self, doc: &Document, author: &str) -> Result<(), StoreError>;
+
+    /// A page of the recorded revisions of project `id`, newest first.
+    async fn list_revisions(&self, id: &str, skip: usize, limit: usize) -> Result<Vec<RevisionInfo>, StoreError>;
+
+    /// Revision `rev` of project `id`. `NotFound` if it wasn't recorded or has been purged.
+    async fn get_revision(&self, id: &str, rev: &str) -> Result<Revision, StoreError>;
+
+    /// Deletes revisions recorded before `before`, returns how many.
+    async fn purge_revisions(&self, before: DateTime<Utc>) -> Result<usize, StoreError>;
+
+    /// Hands project `id` from owner `from` to `to`. Returns false, without
+    /// writing, if `from` doesn't own it or it records no owner.
+    async fn transfer_document(&self, id: &str, from: &str, to: &str) -> Result<bool, StoreError>;
+}
+
+#[derive(Debug, Clone)]
+pub struct ProjectConfig {
+    /// How long a deleted project stays in the trash and can be brought back.
+    pub retention: chrono::Duration,
+    /// How long revisions are kept for `GET /{id}/history`.
+    pub history_retention: chrono::Duration,
+    /// How often projects past `retention` and revisions past `history_retention` are deleted.
+    pub purge_interval: Duration,
+}
+
+impl ProjectConfig {
+    pub fn from_env() -> Self {
+        ProjectConfig {
+            retention: chrono::Duration::days(env_or("PROJECT_RETENTION_DAYS", 30)),
+            history_retention: chrono::Duration::days(env_or("PROJECT_HISTORY_DAYS", 90)),
+            purge_interval: Duration::from_secs(env_or("PROJECT_PURGE_INTERVAL_SECS", 3600)),
+        }
+    }
+}
+
+/// Empties the trash of projects older than `config.retention` and drops
+/// revisions older than `config.history_retention`, every `config.purge_interval`.
+pub fn spawn_purge_task(documents: Arc<dyn DocumentStore>, config: ProjectConfig) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(config.purge_interval);
+        loop {
+            interval.tick().await;
+            match documents.purge_deleted(Utc::now() - config.retention).await {
+                Ok(0) => {},
+                Ok(purged) => println!("projects: purged {} deleted projects", purged),
+                Err(e) => println!("projects: purge failed: {:?}", e),
+            }
+            match documents.purge_revisions(Utc::now() - config.history_retention).await {
+                Ok(0) => {},
+                Ok(purged) => println!("projects: purged {} old revisions", purged),
+                Err(e) => println!("projects: history purge failed: {:?}", e),
+            }
+        }
+    });
+}
+
+/// User accounts, keyed by email.
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    async fn get_user(&self, email: &str) -> Result<User, StoreError>;
+
+    /// A page of users, ordered by email.
+    async fn list_users(&self, skip: usize, limit: usize) -> Result<Vec<User>, StoreError>;
+
+    /// Stores `user` as a new account. Fails with `Conflict` if the email is taken.
+    async fn create_user(&self, user: User) -> Result<User, StoreError>;
+
+    /// Applies `change` to the stored user and writes it back, unless `change`
+    /// returns false. Use `update_user` and `update_user_if` instead.
+    async fn modify_user(&self, email: &str, change: &mut (dyn for<'u> FnMut(&'u mut User) -> bool + Send)) -> Result<Option<User>, StoreError>;
+
+    async fn delete_user(&self, email: &str) -> Result<(), StoreError>;
+}
+
+impl<'a> dyn UserStore + 'a {
+    /// Applies `change` to the stored user and writes it back, starting over
+    /// when a concurrent write got in between.
+    pub async fn update_user<F: FnMut(&mut User) + Send>(&self, email: &str, mut change: F) -> Result<User, StoreError> {
+        let updated = self.modify_user(email, &mut |user| {
+            change(user);
+            true
+        }).await?;
+        Ok(updated.expect("unconditional update always writes"))
+    }
+
+    /// Like `update_user`, but `change` may decline by returning false, in
+    /// which case nothing is written and `None` is returned. `change` sees the
+    /// latest stored user on every attempt.
+    pub async fn update_user_if<F: FnMut(&mut User) -> bool + Send>(&self, email: &str, mut change: F) -> Result<Option<User>, StoreError> {
+        self.modify_user(email, &mut change).await
+    }
+}
+
+/// The frontend configuration served by `GET /config`.
+#[async_trait]
+pub trait ConfigStore: Send + Sync {
+    async fn get_config_data(&self) -> Result<Value, StoreError>;
+}
+
+/// Session tokens, keyed by token. Refresh rotation, caching and JWTs are
+/// left to `SessionStore`.
+#[async_trait]
+pub trait SessionRecords: Send + Sync {
+    /// Creates whatever indexes the store needs.
+    async fn init(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    async fn get_session(&self, token: &str) -> Result<SessionToken, StoreError>;
+
+    /// Stores `session` and returns its new revision. Fails with `Conflict`
+    /// if it was written since `session.rev` was read, or already exists
+    /// while `session.rev` is unset.
+    async fn put_session(&self, session: &SessionToken) -> Result<String, StoreError>;
+
+    /// Deletes `session` if it is still at `session.rev`.
+    async fn delete_session(&self, session: &SessionToken) -> Result<(), StoreError>;
+
+    /// The tokens of `user_id` that are neither revoked nor rotated, of logins that haven't ended by `now`.
+    async fn list_active_sessions(&self, user_id: &str, now: DateTime<Utc>) -> Result<Vec<SessionToken>, StoreError>;
+
+    /// Every unrevoked token of `user_id` issued from the login that started `family_id`.
+    async fn list_family(&self, user_id: &str, family_id: Uuid) -> Result<Vec<SessionToken>, StoreError>;
+
+    /// Id and expiry of every revoked token still unexpired at `now`, for the JWT denylist.
+    async fn list_revoked(&self, now: DateTime<Utc>) -> Result<Vec<(Uuid, DateTime<Utc>)>, StoreError>;
+
+    /// Deletes the tokens of logins that ended before `now`, returns how many.
+    async fn purge_sessions(&self, now: DateTime<Utc>) -> Result<usize, StoreError>;
+}
+
+/// One-time codes, keyed by the hash of the code.
+#[async_trait]
+pub trait CodeRecords: Send + Sync {
+    /// Creates whatever indexes the store needs.
+    async fn init(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    async fn put_code(&self, code: &OneTimeCode) -> Result<(), StoreError>;
+
+    async fn get_code(&self, id: &str) -> Result<OneTimeCode, StoreError>;
+
+    /// Deletes `code` at the revision it was read. Fails with `Conflict` or
+    /// `NotFound` if it was redeemed in the meantime.
+    async fn delete_code(&self, code: &OneTimeCode) -> Result<(), StoreError>;
+
+    /// Deletes codes that expired before `now`, returns how many.
+    async fn purge_codes(&self, now: DateTime<Utc>) -> Result<usize, StoreError>;
+}
+
+/// API keys, keyed by key id.
+#[async_trait]
+pub trait ApiKeyRecords: Send + Sync {
+    /// Creates whatever indexes the store needs.
+    async fn init(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    async fn get_key(&self, id: Uuid) -> Result<ApiKey, StoreError>;
+
+    /// Stores `key` and returns its new revision, failing like `SessionRecords::put_session`.
+    async fn put_key(&self, key: &ApiKey) -> Result<String, StoreError>;
+
+    async fn delete_key(&self, key: &ApiKey) -> Result<(), StoreError>;
+
+    /// The keys of `user_id`, at most `limit` of them.
+    async fn list_keys(&self, user_id: &str, limit: usize) -> Result<Vec<ApiKey>, StoreError>;
+
+    /// Deletes every key of `user_id`, returns how many there were.
+    async fn delete_keys(&self, user_id: &str) -> Result<usize, StoreError>;
+}
+
+/// The audit trail. Entries are only ever added.
+#[async_trait]
+pub trait AuditRecords: Send + Sync {
+    /// Creates whatever indexes the store needs.
+    async fn init(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    async fn append(&self, entry: &AuditEntry) -> Result<(), StoreError>;
+
+    /// Entries matching `filter`, newest first, from the `skip`th on and at most `limit` of them.
+    async fn find_entries(&self, filter: &AuditQuery, skip: usize, limit: usize) -> Result<Vec<AuditEntry>, StoreError>;
+}
+
+/// Every store the server needs, all backed by the same implementation.
+pub struct Storage {
+    pub users: Arc<dyn UserStore>,
+    pub documents: Arc<dyn DocumentStore>,
+    pub config: Arc<dyn ConfigStore>,
+    pub sessions: Arc<dyn SessionRecords>,
+    pub codes: Arc<dyn CodeRecords>,
+    pub api_keys: Arc<dyn ApiKeyRecords>,
+    pub audit: Arc<dyn AuditRecords>,
+}
+
+impl Storage {
+    pub fn new<S>(store: Arc<S>) -> Self
+    where
+        S: UserStore + DocumentStore + ConfigStore + SessionRecords + CodeRecords + ApiKeyRecords + AuditRecords + 'static,
+    {
+        Storage {
+            users: store.clone(),
+            documents: store.clone(),
+            config: store.clone(),
+            sessions: store.clone(),
+            codes: store.clone(),
+            api_keys: store.clone(),
+            audit: store,
+        }
+    }
+}
+
+/// Shallow merge: the top-level keys of `new_content` replace those of `old_document`.
+pub fn combine_json_values(old_document: Value, new_content: Value) -> Value {
+    match old_document {
+        Value::Object(mut map) => {
+            if let Value::Object(new_map) = new_content {
+                map.extend(new_map);
+            }
+            Value::Object(map)
+        },
+        _ => new_content,
+    }
+}
@@ -0,0 +1,348 @@
+//! Handler tests: the real routes on a `MemoryStore`, called through `actix_web::test`.
+
+use std::sync::Arc;
+use std::time::Duration;
+use actix_web::http::{header, StatusCode};
+use actix_web::test::TestRequest;
+use actix_web::{test, web, App};
+use actix_web::cookie::SameSite;
+use chrono::Utc;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use crate::api_keys::ApiKeyStore;
+use crate::audit::AuditLog;
+use crate::auth::{Role, User};
+use crate::codes::{CodeConfig, CodeStore};
+use crate::cookies::CookieConfig;
+use crate::jwt::SessionMode;
+use crate::memory::MemoryStore;
+use crate::password::{self, PasswordConfig};
+use crate::rate_limit::{RateLimitConfig, RateLimiter};
+use crate::session::{SessionConfig, SessionStore};
+use crate::store::{ProjectConfig, Storage};
+use crate::totp::{TotpConfig, TotpState};
+use crate::AppConfig;
+
+/// Everything `main` hands to the handlers, with cheap password hashing and
+/// rate limits that stay out of the way.
+struct Backend {
+    storage: Storage,
+    sessions: Arc<SessionStore>,
+    codes: Arc<CodeStore>,
+    api_keys: Arc<ApiKeyStore>,
+    audit: Arc<AuditLog>,
+    limiter: Arc<RateLimiter>,
+    app_config: web::Data<AppConfig>,
+}
+
+impl Backend {
+    fn new() -> Self {
+        let storage = Storage::new(Arc::new(MemoryStore::new()));
+        let sessions = Arc::new(SessionStore::new(storage.sessions.clone(), storage.users.clone(), SessionConfig {
+            mode: SessionMode::Opaque,
+            access_ttl: chrono::Duration::minutes(15),
+            idle_timeout: chrono::Duration::days(1),
+            lifetime: chrono::Duration::days(30),
+            cache_ttl: Duration::from_secs(5),
+            purge_interval: Duration::from_secs(3600),
+            denylist_sync_interval: Duration::from_secs(60),
+        }, None));
+        let codes = Arc::new(CodeStore::new(storage.codes.clone(), CodeConfig {
+            activation_ttl: chrono::Duration::hours(1),
+            reset_ttl: chrono::Duration::hours(1),
+            challenge_ttl: chrono::Duration::minutes(5),
+            oidc_ttl: chrono::Duration::minutes(10),
+            email_change_ttl: chrono::Duration::hours(1),
+            magic_link_ttl: chrono::Duration::minutes(15),
+            purge_interval: Duration::from_secs(3600),
+        }));
+        let api_keys = Arc::new(ApiKeyStore::new(storage.api_keys.clone(), storage.users.clone()));
+        let audit = Arc::new(AuditLog::new(storage.audit.clone()));
+        let generous = || "1000/60".parse().unwrap();
+        let limiter = Arc::new(RateLimiter::new(RateLimitConfig {
+            login_ip: generous(),
+            login_account: generous(),
+            email_ip: generous(),
+            email_account: generous(),
+            redeem_ip: generous(),
+            backoff_after: 100,
+            lockout_after: 200,
+            lockout: Duration::from_secs(900),
+        }));
+        let app_config = web::Data::new(AppConfig {
+            url: "http://localhost:3000".to_string(),
+            password: PasswordConfig { memory_kib: 256, iterations: 1, parallelism: 1, min_length: 8 },
+            totp: TotpConfig { issuer: "Couchtec".to_string() },
+            cookies: CookieConfig { secure: false, same_site: SameSite::Strict, domain: None },
+            projects: ProjectConfig {
+                retention: chrono::Duration::days(30),
+                history_retention: chrono::Duration::days(30),
+                purge_interval: Duration::from_secs(3600),
+            },
+            trusted_proxies: Vec::new(),
+        });
+        Backend { storage, sessions, codes, api_keys, audit, limiter, app_config }
+    }
+
+    /// Registers the shared state the way `main` does, and all routes.
+    fn configure(&self, cfg: &mut web::ServiceConfig) {
+        cfg.app_data(web::Data::from(self.storage.users.clone()))
+            .app_data(web::Data::from(self.storage.documents.clone()))
+            .app_data(web::Data::from(self.storage.config.clone()))
+            .app_data(web::Data::new(self.sessions.clone()))
+            .app_data(web::Data::new(self.codes.clone()))
+            .app_data(web::Data::new(self.api_keys.clone()))
+            .app_data(web::Data::new(self.audit.clone()))
+            .app_data(web::Data::new(self.limiter.clone()))
+            .app_data(self.app_config.clone());
+        crate::routes(cfg);
+    }
+
+    async fn add_user(&self, email: &str, password: &str) -> User {
+        let hashed = password::hash_password(&self.app_config.password, password).unwrap();
+        self.storage.users.create_user(User::new(email.to_string(), hashed, false)).await.unwrap()
+    }
+}
+
+/// Hashes the way accounts did before the Argon2 migration.
+fn legacy_hash(password: &str, salt: &str) -> String {
+    hex::encode(Sha256::digest(format!("{}{}", password, salt).as_bytes()))
+}
+
+fn bearer(token: &str) -> (header::HeaderName, String) {
+    (header::AUTHORIZATION, format!("Bearer {}", token))
+}
+
+fn login(email: &str, password: &str) -> TestRequest {
+    TestRequest::post().uri("/login").set_json(json!({ "email": email, "password": password }))
+}
+
+fn refresh(refresh_token: &Value) -> TestRequest {
+    TestRequest::post().uri("/token/refresh").set_json(json!({ "refresh_token": refresh_token }))
+}
+
+#[actix_web::test]
+async fn login_rehashes_legacy_passwords() {
+    let backend = Backend::new();
+    let mut user = User::new("alice@example.com".to_string(), legacy_hash("correct horse", "pepper"), false);
+    user.salt = "pepper".to_string();
+    backend.storage.users.create_user(user).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| backend.configure(cfg))).await;
+
+    let tokens: Value = test::call_and_read_body_json(&app, login("alice@example.com", "correct horse").to_request()).await;
+    assert!(tokens["access_token"].is_string());
+    let user = backend.storage.users.get_user("alice@example.com").await.unwrap();
+    assert!(user.hashed.starts_with("$argon2id$"), "{}", user.hashed);
+    assert!(user.salt.is_empty());
+
+    // The new hash works like the old one
+    let resp = test::call_service(&app, login("alice@example.com", "correct horse").to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = test::call_service(&app, login("alice@example.com", "wrong horse").to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn login_answers_the_same_for_unknown_accounts_and_wrong_passwords() {
+    let backend = Backend::new();
+    backend.add_user("alice@example.com", "correct horse").await;
+    let app = test::init_service(App::new().configure(|cfg| backend.configure(cfg))).await;
+
+    for (email, password) in [("alice@example.com", "wrong horse"), ("nobody@example.com", "correct horse")] {
+        let resp = test::call_service(&app, login(email, password).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "{}", email);
+        assert_eq!(test::read_body(resp).await, "Unauthorized");
+    }
+}
+
+#[actix_web::test]
+async fn refresh_rotates_and_reuse_revokes_the_family() {
+    let backend = Backend::new();
+    backend.add_user("alice@example.com", "correct horse").await;
+    let app = test::init_service(App::new().configure(|cfg| backend.configure(cfg))).await;
+
+    let first: Value = test::call_and_read_body_json(&app, login("alice@example.com", "correct horse").to_request()).await;
+    let resp = test::call_service(&app, refresh(&first["refresh_token"]).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let second: Value = test::read_body_json(resp).await;
+    assert_ne!(second["refresh_token"], first["refresh_token"]);
+    let access = second["access_token"].as_str().unwrap();
+    let req = TestRequest::get().uri("/sessions").insert_header(bearer(access)).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    // The first refresh token leaked: using it again ends the whole login
+    let resp = test::call_service(&app, refresh(&first["refresh_token"]).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let req = TestRequest::get().uri("/sessions").insert_header(bearer(access)).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+    let resp = test::call_service(&app, refresh(&second["refresh_token"]).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn totp_accounts_get_a_challenge_instead_of_tokens() {
+    let backend = Backend::new();
+    backend.add_user("alice@example.com", "correct horse").await;
+    let totp = TotpState { confirmed: true, ..TotpState::new() };
+    backend.storage.users.update_user("alice@example.com", |user| user.totp = Some(totp.clone())).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| backend.configure(cfg))).await;
+
+    let body: Value = test::call_and_read_body_json(&app, login("alice@example.com", "correct horse").to_request()).await;
+    assert_eq!(body["mfa_required"], true);
+    assert!(body.get("access_token").is_none());
+    let challenge = body["challenge"].clone();
+
+    let req = TestRequest::post().uri("/login/verify").set_json(json!({ "challenge": challenge, "code": "000000x" })).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+    let code = totp.code_at(Utc::now());
+    let req = TestRequest::post().uri("/login/verify").set_json(json!({ "challenge": challenge, "code": code })).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let tokens: Value = test::read_body_json(resp).await;
+    assert!(tokens["access_token"].is_string());
+
+    // Neither the challenge nor the code can be used twice
+    let req = TestRequest::post().uri("/login/verify").set_json(json!({ "challenge": challenge, "code": code })).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+    let body: Value = test::call_and_read_body_json(&app, login("alice@example.com", "correct horse").to_request()).await;
+    let req = TestRequest::post().uri("/login/verify").set_json(json!({ "challenge": body["challenge"], "code": code })).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn projects_belong_to_their_owner() {
+    let backend = Backend::new();
+    backend.add_user("alice@example.com", "correct horse").await;
+    backend.add_user("bob@example.com", "battery staple").await;
+    let app = test::init_service(App::new().configure(|cfg| backend.configure(cfg))).await;
+    let alice: Value = test::call_and_read_body_json(&app, login("alice@example.com", "correct horse").to_request()).await;
+    let alice = alice["access_token"].as_str().unwrap();
+    let bob: Value = test::call_and_read_body_json(&app, login("bob@example.com", "battery staple").to_request()).await;
+    let bob = bob["access_token"].as_str().unwrap();
+
+    let req = TestRequest::put().uri("/project-a").insert_header(bearer(alice)).set_json(json!({ "name": "A" })).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let req = TestRequest::get().uri("/project-a").insert_header(bearer(alice)).to_request();
+    assert_eq!(test::call_and_read_body_json::<_, _, Value>(&app, req).await, json!({ "name": "A" }));
+
+    let req = TestRequest::get().uri("/project-a").insert_header(bearer(bob)).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+    let req = TestRequest::put().uri("/project-a").insert_header(bearer(bob)).set_json(json!({ "name": "B" })).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+    let req = TestRequest::delete().uri("/project-a").insert_header(bearer(bob)).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+
+    // Listing someone else's project doesn't grant access to it
+    let req = TestRequest::post().uri("/uuids/me").insert_header(bearer(bob)).set_json(json!({ "uuid": "project-a" })).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+    let req = TestRequest::post().uri("/uuids/alice@example.com").insert_header(bearer(bob)).set_json(json!({ "uuid": "project-b" })).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+    let req = TestRequest::get().uri("/project-a").insert_header(bearer(bob)).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+
+    // An id nobody created yet can be claimed
+    let req = TestRequest::post().uri("/uuids/me").insert_header(bearer(bob)).set_json(json!({ "uuid": "project-b" })).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let uuids = backend.storage.users.get_user("bob@example.com").await.unwrap().uuids;
+    assert_eq!(uuids, vec!["project-b".to_string()]);
+}
+
+#[actix_web::test]
+async fn writes_with_a_stale_if_match_conflict() {
+    let backend = Backend::new();
+    backend.add_user("alice@example.com", "correct horse").await;
+    let app = test::init_service(App::new().configure(|cfg| backend.configure(cfg))).await;
+    let alice: Value = test::call_and_read_body_json(&app, login("alice@example.com", "correct horse").to_request()).await;
+    let alice = alice["access_token"].as_str().unwrap();
+
+    let req = TestRequest::put().uri("/project-a").insert_header(bearer(alice)).insert_header((header::IF_MATCH, "\"1-abc\"")).set_json(json!({ "v": 1 })).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+
+    let req = TestRequest::put().uri("/project-a").insert_header(bearer(alice)).set_json(json!({ "v": 1 })).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let first = resp.headers().get(header::ETAG).unwrap().clone();
+
+    let req = TestRequest::put().uri("/project-a").insert_header(bearer(alice)).insert_header((header::IF_MATCH, first.clone())).set_json(json!({ "v": 2 })).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let second = resp.headers().get(header::ETAG).unwrap().clone();
+    assert_ne!(first, second);
+
+    // Another edit got in first: the current copy comes back to merge with
+    let req = TestRequest::put().uri("/project-a").insert_header(bearer(alice)).insert_header((header::IF_MATCH, first.clone())).set_json(json!({ "v": 3 })).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    assert_eq!(resp.headers().get(header::ETAG), Some(&second));
+    assert_eq!(test::read_body_json::<Value, _>(resp).await, json!({ "v": 2 }));
+
+    let req = TestRequest::patch().uri("/project-a").insert_header(bearer(alice)).insert_header((header::IF_MATCH, first))
+        .insert_header((header::CONTENT_TYPE, "application/merge-patch+json")).set_payload(r#"{"v":3}"#).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CONFLICT);
+    let req = TestRequest::get().uri("/project-a").insert_header(bearer(alice)).to_request();
+    assert_eq!(test::call_and_read_body_json::<_, _, Value>(&app, req).await, json!({ "v": 2 }));
+}
+
+#[actix_web::test]
+async fn trashed_projects_can_be_restored_until_purged() {
+    let backend = Backend::new();
+    backend.add_user("alice@example.com", "correct horse").await;
+    let app = test::init_service(App::new().configure(|cfg| backend.configure(cfg))).await;
+    let alice: Value = test::call_and_read_body_json(&app, login("alice@example.com", "correct horse").to_request()).await;
+    let alice = alice["access_token"].as_str().unwrap();
+
+    let req = TestRequest::put().uri("/project-a").insert_header(bearer(alice)).set_json(json!({ "v": 1 })).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let req = TestRequest::delete().uri("/project-a").insert_header(bearer(alice)).to_request();
+    let deleted: Value = test::call_and_read_body_json(&app, req).await;
+    assert!(deleted["restorable_until"].is_string());
+    let req = TestRequest::get().uri("/project-a").insert_header(bearer(alice)).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+    assert!(backend.storage.users.get_user("alice@example.com").await.unwrap().uuids.is_empty());
+
+    let req = TestRequest::post().uri("/project-a/undelete").insert_header(bearer(alice)).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let req = TestRequest::get().uri("/project-a").insert_header(bearer(alice)).to_request();
+    assert_eq!(test::call_and_read_body_json::<_, _, Value>(&app, req).await, json!({ "v": 1 }));
+
+    let req = TestRequest::delete().uri("/project-a").insert_header(bearer(alice)).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let purged = backend.storage.documents.purge_deleted(Utc::now() + chrono::Duration::seconds(1)).await.unwrap();
+    assert_eq!(purged, 1);
+    let req = TestRequest::post().uri("/project-a/undelete").insert_header(bearer(alice)).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+
+    // Permanently deleted projects skip the trash
+    let req = TestRequest::put().uri("/project-b").insert_header(bearer(alice)).set_json(json!({ "v": 1 })).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let req = TestRequest::delete().uri("/project-b?permanent=true").insert_header(bearer(alice)).to_request();
+    let deleted: Value = test::call_and_read_body_json(&app, req).await;
+    assert!(deleted["restorable_until"].is_null());
+    let req = TestRequest::post().uri("/project-b/undelete").insert_header(bearer(alice)).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn suspended_accounts_are_told_why_their_tokens_stopped_working() {
+    let backend = Backend::new();
+    backend.add_user("admin@example.com", "correct horse").await;
+    backend.storage.users.update_user("admin@example.com", |user| user.roles = vec![Role::Admin]).await.unwrap();
+    backend.add_user("bob@example.com", "battery staple").await;
+    let app = test::init_service(App::new().configure(|cfg| backend.configure(cfg))).await;
+    let admin: Value = test::call_and_read_body_json(&app, login("admin@example.com", "correct horse").to_request()).await;
+    let admin = admin["access_token"].as_str().unwrap();
+    let bob: Value = test::call_and_read_body_json(&app, login("bob@example.com", "battery staple").to_request()).await;
+
+    let req = TestRequest::post().uri("/admin/users/bob@example.com/suspend").insert_header(bearer(admin)).set_json(json!({ "reason": "unpaid" })).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let req = TestRequest::get().uri("/sessions").insert_header(bearer(bob["access_token"].as_str().unwrap())).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(test::read_body(resp).await, "Account disabled");
+    let resp = test::call_service(&app, refresh(&bob["refresh_token"]).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(test::read_body(resp).await, "Account disabled");
+}
@@ -0,0 +1,226 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base32::Alphabet;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use crate::utils::{constant_time_eq, env_or};
+
+const SECRET_BYTES: usize = 20;
+const STEP_SECS: i64 = 30;
+const DIGITS: u32 = 6;
+/// Codes from one step before or after the current one are accepted, to
+/// tolerate clock drift between server and authenticator.
+const SKEW_STEPS: i64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+const SECRET_ALPHABET: Alphabet = Alphabet::Rfc4648 { padding: false };
+
+/// RFC 6238 second factor of a user, stored on the `User` document.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TotpState {
+    /// Base32 shared secret, without padding.
+    pub secret: String,
+    /// False until the user proved their authenticator works. Unconfirmed
+    /// secrets are not asked for on login.
+    pub confirmed: bool,
+    /// SHA-256 hashes of the unused recovery codes.
+    #[serde(default)]
+    pub recovery_codes: Vec<String>,
+    /// Time step of the last accepted code, so no code is accepted twice.
+    #[serde(default)]
+    pub last_step: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct TotpConfig {
+    /// Shown as the account's issuer in authenticator apps.
+    pub issuer: String,
+}
+
+impl TotpConfig {
+    pub fn from_env() -> Self {
+        TotpConfig {
+            issuer: env_or("TOTP_ISSUER", "Couchtec".to_string()),
+        }
+    }
+}
+
+impl TotpState {
+    /// A fresh, unconfirmed secret.
+    pub fn new() -> Self {
+        let mut secret = [0u8; SECRET_BYTES];
+        OsRng.fill_bytes(&mut secret);
+        TotpState {
+            secret: base32::encode(SECRET_ALPHABET, &secret),
+            confirmed: false,
+            recovery_codes: Vec::new(),
+            last_step: 0,
+        }
+    }
+
+    /// The `otpauth://` URI authenticator apps read from a QR code.
+    pub fn provisioning_uri(&self, issuer: &str, account: &str) -> String {
+        let mut uri = Url::parse("otpauth://totp/").expect("static URI is valid");
+        uri.set_path(&format!("{}:{}", issuer, account));
+        uri.query_pairs_mut()
+            .append_pair("secret", &self.secret)
+            .append_pair("issuer", issuer)
+            .append_pair("algorithm", "SHA1")
+            .append_pair("digits", &DIGITS.to_string())
+            .append_pair("period", &STEP_SECS.to_string());
+        uri.to_string()
+    }
+
+    /// Checks a code from the authenticator and remembers its time step.
+    pub fn accept_code(&mut self, code: &str, now: DateTime<Utc>) -> bool {
+        let code = code.trim();
+        let Some(secret) = base32::decode(SECRET_ALPHABET, &self.secret) else { return false };
+        let current = now.timestamp() / STEP_SECS;
+        for step in (current - SKEW_STEPS)..=(current + SKEW_STEPS) {
+            if step <= self.last_step {
+                continue;
+            }
+            let expected = format!("{:0width$}", hotp(&secret, step as u64), width = DIGITS as usize);
+            if constant_time_eq(expected.as_bytes(), code.as_bytes()) {
+                self.last_step = step;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// The code an authenticator shows at `now`.
+    #[cfg(test)]
+    pub fn code_at(&self, now: DateTime<Utc>) -> String {
+        let secret = base32::decode(SECRET_ALPHABET, &self.secret).expect("secret is base32");
+        format!("{:0width$}", hotp(&secret, (now.timestamp() / STEP_SECS) as u64), width = DIGITS as usize)
+    }
+
+    /// Checks a recovery code and uses it up.
+    pub fn accept_recovery_code(&mut self, code: &str) -> bool {
+        let hashed = hash_recovery_code(code);
+        match self.recovery_codes.iter().position(|stored| constant_time_eq(stored.as_bytes(), hashed.as_bytes())) {
+            Some(index) => {
+                self.recovery_codes.remove(index);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Replaces all recovery codes and returns the new ones. They are only
+    /// stored hashed, so this is the only time they can be shown.
+    pub fn reset_recovery_codes(&mut self) -> Vec<String> {
+        let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+            .map(|_| {
+                let mut bytes = [0u8; 5];
+                OsRng.fill_bytes(&mut bytes);
+                let code = hex::encode(bytes);
+                format!("{}-{}", &code[..5], &code[5..])
+            })
+            .collect();
+        self.recovery_codes = codes.iter().map(|code| hash_recovery_code(code)).collect();
+        codes
+    }
+}
+
+/// RFC 4226 HOTP value for `counter`.
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let truncated = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    truncated % 10u32.pow(DIGITS)
+}
+
+/// Recovery codes are compared without dashes, spaces or case.
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hex::encode(Sha256::digest(normalized.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The RFC 6238 test secret, "12345678901234567890".
+    fn rfc_state() -> TotpState {
+        TotpState {
+            secret: base32::encode(SECRET_ALPHABET, b"12345678901234567890"),
+            confirmed: true,
+            recovery_codes: Vec::new(),
+            last_step: 0,
+        }
+    }
+
+    fn at(timestamp: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(timestamp, 0).unwrap()
+    }
+
+    #[test]
+    fn accepts_rfc_6238_vectors() {
+        // The RFC lists 8 digits, we use the last 6
+        assert!(rfc_state().accept_code("287082", at(59)));
+        assert!(rfc_state().accept_code("081804", at(1111111109)));
+        assert!(rfc_state().accept_code(" 005924 ", at(1234567890)));
+    }
+
+    #[test]
+    fn rejects_wrong_codes() {
+        let mut state = rfc_state();
+        assert!(!state.accept_code("287083", at(59)));
+        assert!(!state.accept_code("", at(59)));
+        assert!(!state.accept_code("28708", at(59)));
+        assert_eq!(state.last_step, 0);
+    }
+
+    #[test]
+    fn accepts_a_code_only_once() {
+        let mut state = rfc_state();
+        assert!(state.accept_code("081804", at(1111111109)));
+        assert!(!state.accept_code("081804", at(1111111109)));
+        // Nor an older one still inside the skew window
+        let previous = state.code_at(at(1111111109 - STEP_SECS));
+        assert!(!state.accept_code(&previous, at(1111111109)));
+    }
+
+    #[test]
+    fn tolerates_one_step_of_drift() {
+        let now = at(1111111109);
+        let state = rfc_state();
+        assert!(state.clone().accept_code(&state.code_at(at(1111111109 - STEP_SECS)), now));
+        assert!(state.clone().accept_code(&state.code_at(at(1111111109 + STEP_SECS)), now));
+        assert!(!state.clone().accept_code(&state.code_at(at(1111111109 - 2 * STEP_SECS)), now));
+        assert!(!state.clone().accept_code(&state.code_at(at(1111111109 + 2 * STEP_SECS)), now));
+    }
+
+    #[test]
+    fn recovery_codes_work_once_in_any_spelling() {
+        let mut state = TotpState::new();
+        let codes = state.reset_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert!(state.accept_recovery_code(&codes[0].to_uppercase().replace('-', " ")));
+        assert!(!state.accept_recovery_code(&codes[0]));
+        assert!(state.accept_recovery_code(&codes[1]));
+        assert_eq!(state.recovery_codes.len(), RECOVERY_CODE_COUNT - 2);
+    }
+
+    #[test]
+    fn resetting_recovery_codes_invalidates_the_old_ones() {
+        let mut state = TotpState::new();
+        let old = state.reset_recovery_codes();
+        state.reset_recovery_codes();
+        assert!(!state.accept_recovery_code(&old[0]));
+    }
+}
@@ -1,21 +1,46 @@
-use actix_web::{HttpRequest, HttpResponse };
-use crate::auth::UserManager;
+use actix_web::{web, HttpRequest, HttpResponse };
+use chrono::{DateTime, Utc};
+use std::env;
+use std::net::{AddrParseError, IpAddr};
+use std::str::FromStr;
+use std::time::Duration;
+use crate::api_keys::{Access, ApiKeyStore, KEY_PREFIX};
+use crate::auth::Role;
+use crate::cookies::{self, SESSION_COOKIE};
+use crate::session::{Rejection, SessionStore, SessionToken};
+use crate::store::UserStore;
+use crate::AppConfig;
 
 pub enum ApiResponse {
     Ok,
+    /// Carries the reason, sent as the body.
+    BadRequest(String),
     NotFound,
     Conflict,
     Unauthorized,
+    Forbidden,
+    /// The account was suspended by an admin.
+    AccountDisabled,
+    UnsupportedMediaType,
+    /// Carries how long the client should wait, sent as `Retry-After`.
+    TooManyRequests(Duration),
     InternalServerError,
 }
 
 impl ApiResponse {
-    pub fn to_response(self) -> HttpResponse {
+    pub fn to_response(&self) -> HttpResponse {
         match self {
             ApiResponse::Ok => HttpResponse::Ok().body("Ok"),
+            ApiResponse::BadRequest(reason) => HttpResponse::BadRequest().body(reason.clone()),
             ApiResponse::NotFound => HttpResponse::NotFound().body("Not found"),
-            ApiResponse::Conflict => HttpResponse::NotFound().body("Conflict"),
+            ApiResponse::Conflict => HttpResponse::Conflict().body("Conflict"),
             ApiResponse::Unauthorized => HttpResponse::Unauthorized().body("Unauthorized"),
+            ApiResponse::Forbidden => HttpResponse::Forbidden().body("Forbidden"),
+            ApiResponse::AccountDisabled => HttpResponse::Forbidden().body("Account disabled"),
+            ApiResponse::UnsupportedMediaType => HttpResponse::UnsupportedMediaType().body("Unsupported media type"),
+            ApiResponse::TooManyRequests(wait) => HttpResponse::TooManyRequests()
+                .insert_header(("Retry-After", wait.as_secs_f64().ceil().max(1.0).to_string()))
+                .body("Too many requests"),
             ApiResponse::InternalServerError => HttpResponse::InternalServerError().body("Internal Server Error")
         }
     }
@@ -25,18 +50,227 @@ pub fn extract_session_token(req: &HttpRequest) -> Option<String> {
     req.headers().get("Authorization")
         .and_then(|header_value| header_value.to_str().ok())
         .and_then(|header_str| {
-            if header_str.starts_with("Bearer ") {
-                Some(header_str[7..].to_string())
-            } else {
-                None 
-            }
+            header_str.strip_prefix("Bearer ").map(|token| token.to_string())
         })
 }
 
-pub fn verfiy_session_token(req: &HttpRequest, user_manager: &UserManager) -> Result<String, ApiResponse> {
-    let token_id = extract_session_token(req).ok_or(ApiResponse::Unauthorized)?;
-    if !user_manager.session_token_valid(token_id.clone()) {
-        return Err(ApiResponse::Unauthorized);
+/// Accepts the bearer header or, failing that, the session cookie. Cookies
+/// are sent by the browser on its own, so those requests also need the CSRF token.
+pub async fn verfiy_session_token(req: &HttpRequest, sessions: &SessionStore) -> Result<SessionToken, ApiResponse> {
+    let token_id = match extract_session_token(req) {
+        Some(token) => token,
+        None => {
+            let cookie = req.cookie(SESSION_COOKIE).ok_or(ApiResponse::Unauthorized)?;
+            cookies::check_csrf(req)?;
+            cookie.value().to_string()
+        }
+    };
+    let session = sessions.get_valid(&token_id).await.map_err(|rejection| match rejection {
+        Rejection::Invalid => ApiResponse::Unauthorized,
+        // Tells the client not to bother refreshing
+        Rejection::Disabled => ApiResponse::AccountDisabled,
+    })?;
+    Ok(sessions.touch(session).await)
+}
+
+/// Accepts a session token or an API key whose scope allows `access`, and
+/// returns the caller's email. Endpoints that manage the account itself use
+/// `verfiy_session_token` instead, so API keys can't be used there.
+pub async fn authenticate(req: &HttpRequest, sessions: &SessionStore, api_keys: &ApiKeyStore, access: Access) -> Result<String, ApiResponse> {
+    let token = match extract_session_token(req) {
+        Some(token) if token.starts_with(KEY_PREFIX) => token,
+        _ => return verfiy_session_token(req, sessions).await.map(|session| session.user_id),
+    };
+    let key = match api_keys.verify(&token).await {
+        Ok(Some(key)) => key,
+        Ok(None) => return Err(ApiResponse::Unauthorized),
+        Err(e) => {
+            println!("Error: {:?}", e);
+            return Err(ApiResponse::InternalServerError);
+        }
+    };
+    if !key.scope.allows(access) {
+        return Err(ApiResponse::Forbidden);
+    }
+    match api_keys.owner_disabled(&key).await {
+        Ok(false) => {},
+        Ok(true) => return Err(ApiResponse::AccountDisabled),
+        Err(e) => {
+            println!("Error: {:?}", e);
+            return Err(ApiResponse::InternalServerError);
+        }
+    }
+    let user_id = key.user_id.clone();
+    api_keys.touch(key).await;
+    Ok(user_id)
+}
+
+/// The token a request was authenticated with, bearer header first, for
+/// checking it again with `recheck` while a long-lived response is open.
+pub fn presented_token(req: &HttpRequest) -> Option<String> {
+    extract_session_token(req).or_else(|| req.cookie(SESSION_COOKIE).map(|cookie| cookie.value().to_string()))
+}
+
+/// Checks that `token` from `presented_token` still authenticates `caller`:
+/// the session is neither expired nor revoked, or the API key still exists
+/// and its owner isn't suspended. Returns when a session token expires,
+/// `None` for API keys, which don't.
+pub async fn recheck(token: &str, caller: &str, sessions: &SessionStore, api_keys: &ApiKeyStore) -> Result<Option<DateTime<Utc>>, ApiResponse> {
+    if token.starts_with(KEY_PREFIX) {
+        let key = match api_keys.verify(token).await {
+            Ok(Some(key)) if key.user_id == caller => key,
+            Ok(_) => return Err(ApiResponse::Unauthorized),
+            Err(e) => {
+                println!("Error: {:?}", e);
+                return Err(ApiResponse::InternalServerError);
+            }
+        };
+        return match api_keys.owner_disabled(&key).await {
+            Ok(false) => Ok(None),
+            Ok(true) => Err(ApiResponse::AccountDisabled),
+            Err(e) => {
+                println!("Error: {:?}", e);
+                Err(ApiResponse::InternalServerError)
+            }
+        };
+    }
+    match sessions.get_valid(token).await {
+        Ok(session) if session.user_id == caller => Ok(Some(session.expires_at)),
+        Ok(_) | Err(Rejection::Invalid) => Err(ApiResponse::Unauthorized),
+        Err(Rejection::Disabled) => Err(ApiResponse::AccountDisabled),
+    }
+}
+
+/// Resolves the account named in a path to the email the caller may act on.
+/// `me` and the caller's own email name the caller, any other account
+/// requires the admin role.
+pub async fn authorize_account(caller: &str, users: &dyn UserStore, account: &str) -> Result<String, ApiResponse> {
+    if account == "me" || account == caller {
+        return Ok(caller.to_string());
+    }
+    let caller = users.get_user(caller).await.map_err(|_| ApiResponse::Unauthorized)?;
+    if caller.has_role(Role::Admin) {
+        Ok(account.to_string())
+    } else {
+        Err(ApiResponse::Forbidden)
+    }
+}
+
+/// The address the request came from. `X-Forwarded-For` is only believed
+/// when the connection comes from one of `TRUSTED_PROXIES`, and then only as
+/// far back as the proxies go: the last address that isn't a trusted proxy
+/// wins, since anything before it was written by the client.
+pub fn client_ip(req: &HttpRequest) -> String {
+    let Some(peer) = req.peer_addr().map(|addr| addr.ip()) else { return "unknown".to_string() };
+    let trusted = req.app_data::<web::Data<AppConfig>>()
+        .map(|config| config.trusted_proxies.as_slice())
+        .unwrap_or_default();
+    if !trusted.contains(&peer) {
+        return peer.to_string();
+    }
+    let forwarded = req.headers().get_all("X-Forwarded-For")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect::<Vec<_>>();
+    let mut client = peer;
+    for hop in forwarded.into_iter().rev() {
+        match hop.parse::<IpAddr>() {
+            Ok(ip) => {
+                client = ip;
+                if !trusted.contains(&ip) {
+                    break;
+                }
+            },
+            // Can't tell who wrote it, stop at the last proxy we trust
+            Err(_) => break,
+        }
     }
-    Ok(token_id)
+    client.to_string()
+}
+
+/// Reads `TRUSTED_PROXIES`, a comma separated list of proxy IP addresses.
+pub fn trusted_proxies_from_env() -> Result<Vec<IpAddr>, AddrParseError> {
+    env::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|proxy| !proxy.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+const MAX_EMAIL_LENGTH: usize = 254;
+
+/// Trims and lowercases an address given for a new account or email change,
+/// `None` if it doesn't look like an email address. Accounts are keyed by
+/// email, so two spellings of one mailbox would be two accounts.
+pub fn normalize_email(email: &str) -> Option<String> {
+    let email = email.trim().to_lowercase();
+    let (local, domain) = email.split_once('@')?;
+    let valid = !local.is_empty()
+        && email.len() <= MAX_EMAIL_LENGTH
+        && !domain.contains('@')
+        && domain.split('.').count() >= 2
+        && domain.split('.').all(|label| !label.is_empty())
+        && !email.chars().any(|c| c.is_whitespace() || c.is_control());
+    valid.then_some(email)
+}
+
+/// Turns a User-Agent header into a short description such as "Firefox on Windows".
+pub fn describe_user_agent(req: &HttpRequest) -> String {
+    let user_agent = match req.headers().get("User-Agent").and_then(|value| value.to_str().ok()) {
+        Some(user_agent) => user_agent,
+        None => return "Unknown device".to_string(),
+    };
+    // Order matters: Edge and Opera also announce Chrome, Chrome also announces Safari
+    let browser = [
+        ("Edg/", "Edge"),
+        ("OPR/", "Opera"),
+        ("Firefox/", "Firefox"),
+        ("Chrome/", "Chrome"),
+        ("Safari/", "Safari"),
+        ("curl/", "curl"),
+    ]
+        .iter()
+        .find(|(marker, _)| user_agent.contains(marker))
+        .map(|(_, name)| *name);
+    let os = [
+        ("iPhone", "iOS"),
+        ("iPad", "iPadOS"),
+        ("Android", "Android"),
+        ("Windows", "Windows"),
+        ("Mac OS X", "macOS"),
+        ("CrOS", "ChromeOS"),
+        ("Linux", "Linux"),
+    ]
+        .iter()
+        .find(|(marker, _)| user_agent.contains(marker))
+        .map(|(_, name)| *name);
+    match (browser, os) {
+        (Some(browser), Some(os)) => format!("{} on {}", browser, os),
+        (Some(name), None) | (None, Some(name)) => name.to_string(),
+        (None, None) => user_agent.chars().take(64).collect(),
+    }
+}
+
+/// Reads an optional setting from the environment, falling back to `default`
+/// when it is unset or cannot be parsed.
+pub fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    env::var(key).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
+
+/// Env var name for a configured id such as a key id or provider name:
+/// `prefix` followed by the id in upper case, with anything but letters and
+/// digits replaced by `_`.
+pub fn env_name(prefix: &str, id: &str) -> String {
+    let suffix: String = id.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+    format!("{}{}", prefix, suffix)
+}
+
+/// Compares two secrets without leaking through timing how much of them matched.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
 }
@@ -25,7 +25,7 @@ export SESSION_TOKEN=$(curl -X POST http://localhost/api/login \
 -d '{
   "email": "linus@couchtec.com",
   "password": "lol"
-}' | sed 's/"//g')
+}' | sed -E 's/.*"access_token":"([^"]+)".*/\1/')
 
 curl -X GET http://localhost/api/config \
 -H "Content-Type: application/json" \
@@ -128,8 +128,188 @@ curl -X POST http://localhost/api/uuids/linus@couchtec.com \
   "uuid": "eyy"
 }'
 
-curl -X DELETE https://couchdb-app-service.azurewebsites.net/users/Getthemlol@protonmail.com?rev=31-c3eb40d582f71055b36d2b51b7e0fc04 \
--u "admin:8RzuxhQ7"
+curl -X DELETE http://localhost/api/admin/users/Getthemlol@protonmail.com \
+-H "Authorization: Bearer $SESSION_TOKEN"
+
+curl -X GET http://localhost/api/admin/users/linus@couchtec.com \
+-H "Authorization: Bearer $SESSION_TOKEN"
+
+curl -X GET "http://localhost/api/admin/users?skip=0&limit=50" \
+-H "Authorization: Bearer $SESSION_TOKEN"
+
+curl -X PUT http://localhost/api/admin/users/linus@couchtec.com/roles \
+-H "Content-Type: application/json" \
+-H "Authorization: Bearer $SESSION_TOKEN" \
+-d '["customer", "consultant"]'
+
+curl -X GET http://localhost/api/sessions \
+-H "Content-Type: application/json" \
+-H "Authorization: Bearer $SESSION_TOKEN"
+
+curl -X POST http://localhost/api/sessions/revoke-others \
+-H "Content-Type: application/json" \
+-H "Authorization: Bearer $SESSION_TOKEN"
+
+curl -X POST http://localhost/api/token/refresh \
+-H "Content-Type: application/json" \
+-d '{
+  "refresh_token": "<refresh_token from /login>"
+}'
+
+curl -X GET http://localhost/api/uuids/me \
+-H "Content-Type: application/json" \
+-H "Authorization: Bearer $SESSION_TOKEN"
+
+curl -X DELETE http://localhost/api/user/me \
+-H "Content-Type: application/json" \
+-H "Authorization: Bearer $SESSION_TOKEN"
+
+curl -X POST http://localhost/api/2fa/enroll \
+-H "Content-Type: application/json" \
+-H "Authorization: Bearer $SESSION_TOKEN"
+
+curl -X POST http://localhost/api/2fa/confirm \
+-H "Content-Type: application/json" \
+-H "Authorization: Bearer $SESSION_TOKEN" \
+-d '{
+  "code": "<code from the authenticator app>"
+}'
+
+curl -X POST http://localhost/api/login/verify \
+-H "Content-Type: application/json" \
+-d '{
+  "challenge": "<challenge from /login>",
+  "code": "<code from the authenticator app>"
+}'
+
+curl -X POST http://localhost/api/api-keys \
+-H "Content-Type: application/json" \
+-H "Authorization: Bearer $SESSION_TOKEN" \
+-d '{
+  "name": "ci",
+  "scope": "read_write"
+}'
+
+curl -X PUT http://localhost/api/test.6aa5280a-e007-4ed5-87fb-3e2a78db1c52 \
+-H "Content-Type: application/json" \
+-H "Authorization: Bearer $API_KEY" \
+-d '{
+    "name": "Linus"
+}'
+
+curl -i -X GET http://localhost/api/auth/oidc/dev/start
+
+curl -X GET "http://localhost/api/auth/oidc/dev/callback?code=<code from the provider>&state=<state from the provider>"
+
+curl -X POST http://localhost/api/me/email \
+-H "Content-Type: application/json" \
+-H "Authorization: Bearer $SESSION_TOKEN" \
+-d '{
+  "new_email": "linus.new@couchtec.com",
+  "password": "lol"
+}'
+
+curl -X POST http://localhost/api/me/email/confirm \
+-H "Content-Type: application/json" \
+-d '{
+  "code": "<email-code from the confirmation link>"
+}'
+
+curl -X POST http://localhost/api/me/password \
+-H "Content-Type: application/json" \
+-H "Authorization: Bearer $SESSION_TOKEN" \
+-d '{
+  "current_password": "lol",
+  "new_password": "correct horse battery staple"
+}'
+
+curl -X GET "http://localhost/api/me/audit?action=login_failed&limit=20" \
+-H "Authorization: Bearer $SESSION_TOKEN"
+
+curl -X GET "http://localhost/api/admin/audit?account=linus@couchtec.com&since=2024-01-01T00:00:00Z" \
+-H "Authorization: Bearer $SESSION_TOKEN"
+
+curl -X POST http://localhost/api/login/magic \
+-H "Content-Type: application/json" \
+-d '{
+  "email": "linus@couchtec.com"
+}'
+
+curl -X POST http://localhost/api/login/magic/verify \
+-H "Content-Type: application/json" \
+-d '{
+  "code": "<magic code from the sign-in link>"
+}'
+
+curl -c cookies.txt -X POST http://localhost/api/login \
+-H "Content-Type: application/json" \
+-H "X-Session-Transport: cookie" \
+-d '{
+  "email": "linus@couchtec.com",
+  "password": "lol"
+}'
+
+curl -b cookies.txt -X GET http://localhost/api/sessions
+
+curl -b cookies.txt -c cookies.txt -X POST http://localhost/api/token/refresh \
+-H "X-CSRF-Token: $(awk '$6 == "ct_csrf" { print $7 }' cookies.txt)"
+
+curl -b cookies.txt -c cookies.txt -X POST http://localhost/api/logout \
+-H "X-CSRF-Token: $(awk '$6 == "ct_csrf" { print $7 }' cookies.txt)"
+
+curl -X POST http://localhost/api/admin/users/linus@couchtec.com/suspend \
+-H "Content-Type: application/json" \
+-H "Authorization: Bearer $SESSION_TOKEN" \
+-d '{
+  "reason": "Chargeback"
+}'
+
+curl -X POST http://localhost/api/admin/users/linus@couchtec.com/reactivate \
+-H "Authorization: Bearer $SESSION_TOKEN"
+
+curl -i -X GET http://localhost/api/test.6aa5280a-e007-4ed5-87fb-3e2a78db1c52 \
+-H "Authorization: Bearer $SESSION_TOKEN"
+
+curl -i -X PUT http://localhost/api/test.6aa5280a-e007-4ed5-87fb-3e2a78db1c52 \
+-H "Content-Type: application/json" \
+-H "Authorization: Bearer $SESSION_TOKEN" \
+-H 'If-Match: "<ETag from the GET>"' \
+-d '{
+    "name": "Linus"
+}'
+
+curl -X PATCH http://localhost/api/test.6aa5280a-e007-4ed5-87fb-3e2a78db1c52 \
+-H "Content-Type: application/merge-patch+json" \
+-H "Authorization: Bearer $SESSION_TOKEN" \
+-d '{
+    "options": { "color": "red", "legacy": null }
+}'
 
-curl -X GET https://couchdb-app-service.azurewebsites.net/users/linus@couchtec.com \
--u "admin:8RzuxhQ7"
+curl -X PATCH http://localhost/api/test.6aa5280a-e007-4ed5-87fb-3e2a78db1c52 \
+-H "Content-Type: application/json-patch+json" \
+-H "Authorization: Bearer $SESSION_TOKEN" \
+-d '[
+    { "op": "test", "path": "/name", "value": "Linus" },
+    { "op": "replace", "path": "/options/color", "value": "blue" }
+]'
+
+curl -X DELETE http://localhost/api/test.6aa5280a-e007-4ed5-87fb-3e2a78db1c52 \
+-H "Authorization: Bearer $SESSION_TOKEN"
+
+curl -X POST http://localhost/api/test.6aa5280a-e007-4ed5-87fb-3e2a78db1c52/undelete \
+-H "Authorization: Bearer $SESSION_TOKEN"
+
+curl -X DELETE "http://localhost/api/test.6aa5280a-e007-4ed5-87fb-3e2a78db1c52?permanent=true" \
+-H "Authorization: Bearer $SESSION_TOKEN"
+
+curl http://localhost/api/test.6aa5280a-e007-4ed5-87fb-3e2a78db1c52/history \
+-H "Authorization: Bearer $SESSION_TOKEN"
+
+curl http://localhost/api/test.6aa5280a-e007-4ed5-87fb-3e2a78db1c52/history/<rev> \
+-H "Authorization: Bearer $SESSION_TOKEN"
+
+curl -X POST http://localhost/api/test.6aa5280a-e007-4ed5-87fb-3e2a78db1c52/restore/<rev> \
+-H "Authorization: Bearer $SESSION_TOKEN"
+
+curl -N http://localhost/api/test.6aa5280a-e007-4ed5-87fb-3e2a78db1c52/events \
+-H "Authorization: Bearer $SESSION_TOKEN"